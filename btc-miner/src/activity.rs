@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::commands::handle_input_line;
+use crate::output::Output;
+use crate::session::Session;
+
+const COMMAND: &str = "command";
+const OUTPUT: &str = "output";
+const ERROR: &str = "error";
+const JSON: &str = "json";
+const NOTE: &str = "note";
+
+// --log-file: one "<unix millis>\t<kind>\t<text>" line per command typed and message printed
+pub struct ActivityLog {
+    file: RefCell<File>,
+}
+
+impl ActivityLog {
+    pub fn open(path: &Path) -> io::Result<ActivityLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ActivityLog { file: RefCell::new(file) })
+    }
+
+    fn record(&self, kind: &str, text: &str) {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+        // Logging must never interrupt the session, so write failures are dropped
+        let _ = writeln!(self.file.borrow_mut(), "{}\t{}\t{}", millis, kind, text.replace('\n', " "));
+    }
+
+    pub fn record_command(&self, line: &str) {
+        self.record(COMMAND, line);
+    }
+
+    pub fn record_note(&self, height: u64, text: &str) {
+        self.record(NOTE, &format!("{} {}", height, text));
+    }
+}
+
+// Mirrors everything written through the wrapped output into the activity log
+pub struct LoggingOutput {
+    inner: Rc<dyn Output>,
+    log: Rc<ActivityLog>,
+}
+
+impl LoggingOutput {
+    pub fn new(inner: Rc<dyn Output>, log: Rc<ActivityLog>) -> Self {
+        LoggingOutput { inner, log }
+    }
+}
+
+impl Output for LoggingOutput {
+    fn human(&self, message: &str) {
+        self.log.record(OUTPUT, message);
+        self.inner.human(message);
+    }
+
+    fn json(&self, value: &serde_json::Value) {
+        self.log.record(JSON, &value.to_string());
+        self.inner.json(value);
+    }
+
+    fn error(&self, message: &str) {
+        self.log.record(ERROR, message);
+        self.inner.error(message);
+    }
+
+    fn progress(&self, label: &str, done: u64, total: u64) {
+        self.inner.progress(label, done, total);
+    }
+}
+
+struct RecordedCommand {
+    millis: u64,
+    line: String,
+}
+
+fn read_commands(path: &Path) -> io::Result<Vec<RecordedCommand>> {
+    let contents = fs::read_to_string(path)?;
+    let commands = contents.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let millis = fields.next()?.parse().ok()?;
+            (fields.next()? == COMMAND).then(|| RecordedCommand { millis, line: fields.next().unwrap_or_default().to_string() })
+        })
+        .collect();
+
+    Ok(commands)
+}
+
+// Re-runs the commands of a --log-file in order, waiting the recorded gaps divided by speed
+pub fn replay(session: &mut Session, path: &Path, speed: f64) {
+    let commands = match read_commands(path) {
+        Ok(commands) => commands,
+        Err(e) => {
+            err!(session, "Failed to read replay log {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    out!(session, "Replaying {} command(s) from {}", commands.len(), path.display());
+    let mut failed = 0;
+    let mut previous_millis = commands.first().map_or(0, |command| command.millis);
+    for (index, command) in commands.iter().enumerate() {
+        let gap = command.millis.saturating_sub(previous_millis);
+        previous_millis = command.millis;
+        if gap > 0 {
+            thread::sleep(Duration::from_secs_f64(gap as f64 / 1000.0 / speed));
+        }
+
+        out!(session, "> {}", command.line);
+        if !handle_input_line(session, command.line.clone()) {
+            err!(session, "Replay: command {} failed: {}", index + 1, command.line);
+            failed += 1;
+        }
+        if session.quit_requested {
+            out!(session, "Replay reached quit, stopping");
+            break;
+        }
+    }
+
+    out!(session, "Replay finished, {} command(s) failed", failed);
+}
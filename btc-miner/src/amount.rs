@@ -0,0 +1,108 @@
+use std::str::FromStr;
+
+use bitcoin::{Address, Amount, Denomination};
+use bitcoin::amount::ParseAmountError;
+
+use crate::error::MinerError;
+
+// Parses a user supplied BTC amount, rejecting anything the node would never accept as a payment value
+pub fn parse_amount(input: &str) -> Result<Amount, MinerError> {
+    parse_amount_in(input, Denomination::Bitcoin)
+}
+
+// "0.5", "0.5 BTC" or "50000 sat", for prompts where the unit is spelled out
+pub fn parse_amount_with_unit(input: &str) -> Result<Amount, MinerError> {
+    match input.trim().split_once(char::is_whitespace) {
+        Some((value, unit)) => {
+            let denomination = Denomination::from_str(unit.trim()).map_err(|_| MinerError::InvalidAmount(input.to_string()))?;
+            parse_amount_in(value, denomination)
+        }
+        None => parse_amount(input.trim()),
+    }
+}
+
+fn parse_amount_in(input: &str, denomination: Denomination) -> Result<Amount, MinerError> {
+    let amount = match Amount::from_str_in(input, denomination) {
+        Ok(amount) => amount,
+        Err(ParseAmountError::OutOfRange(e)) if e.is_below_min() => return Err(MinerError::NegativeAmount),
+        Err(ParseAmountError::OutOfRange(_)) | Err(ParseAmountError::InputTooLarge(_)) => {
+            return Err(MinerError::AmountOverflow(input.to_string()))
+        }
+        Err(_) => return Err(MinerError::InvalidAmount(input.to_string())),
+    };
+
+    if amount == Amount::ZERO {
+        return Err(MinerError::ZeroAmount);
+    }
+    if amount > Amount::MAX_MONEY {
+        return Err(MinerError::AmountOverflow(input.to_string()));
+    }
+
+    Ok(amount)
+}
+
+// The dust threshold depends on the output script, so it can only be checked once the recipient is known
+pub fn check_dust(amount: Amount, address: &Address) -> Result<(), MinerError> {
+    let threshold = address.script_pubkey().minimal_non_dust();
+    if amount < threshold {
+        return Err(MinerError::DustAmount { amount, threshold });
+    }
+
+    Ok(())
+}
+
+// Formats a BTC number from a raw RPC result, e.g. 0.0001 -> "0.0001 BTC"
+pub fn format_btc_value(value: &serde_json::Value) -> String {
+    value.as_f64()
+        .and_then(|btc| Amount::from_btc(btc).ok())
+        .map_or("unavailable".to_string(), |amount| amount.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p2wpkh_address() -> Address {
+        Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap().assume_checked()
+    }
+
+    #[test]
+    fn parses_valid_amounts() {
+        assert_eq!(parse_amount("0.5").unwrap(), Amount::from_sat(50_000_000));
+        assert_eq!(parse_amount("21000000").unwrap(), Amount::MAX_MONEY);
+        assert_eq!(parse_amount_with_unit("50000 sat").unwrap(), Amount::from_sat(50_000));
+    }
+
+    #[test]
+    fn rejects_zero() {
+        assert!(matches!(parse_amount("0"), Err(MinerError::ZeroAmount)));
+        assert!(matches!(parse_amount("0.00000000"), Err(MinerError::ZeroAmount)));
+    }
+
+    #[test]
+    fn rejects_negative() {
+        assert!(matches!(parse_amount("-1"), Err(MinerError::NegativeAmount)));
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert!(matches!(parse_amount("21000000.00000001"), Err(MinerError::AmountOverflow(_))));
+        assert!(matches!(parse_amount("9999999999"), Err(MinerError::AmountOverflow(_))));
+        assert!(matches!(parse_amount("99999999999999999999"), Err(MinerError::AmountOverflow(_))));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(parse_amount("abc"), Err(MinerError::InvalidAmount(_))));
+        assert!(matches!(parse_amount(""), Err(MinerError::InvalidAmount(_))));
+        assert!(matches!(parse_amount_with_unit("1 furlong"), Err(MinerError::InvalidAmount(_))));
+    }
+
+    #[test]
+    fn dust_depends_on_the_output_script() {
+        let address = p2wpkh_address();
+        let threshold = address.script_pubkey().minimal_non_dust();
+        assert!(matches!(check_dust(threshold - Amount::ONE_SAT, &address), Err(MinerError::DustAmount { .. })));
+        assert!(check_dust(threshold, &address).is_ok());
+    }
+}
@@ -0,0 +1,1027 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::{Address, Amount, BlockHash};
+use bitcoincore_rpc::{Client, RpcApi};
+use bitcoincore_rpc::json::{GetBlockTemplateModes, GetBlockTemplateRules, GetBlockchainInfoResult};
+use serde_json::json;
+
+use crate::amount::format_btc_value;
+use crate::mining::mining_address;
+use crate::rpc::{is_timeout, rpc_error_code, rpc_error_message, RPC_METHOD_NOT_FOUND};
+use crate::session::Session;
+
+pub const DEFAULT_PROPAGATION_TIMEOUT_SECS: u64 = 30;
+const PROPAGATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_SYNC_WAIT_SECS: u64 = 600;
+const SYNC_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+// verificationprogress is an estimate and rarely reports exactly 1.0, even at the tip
+const SYNCED_PROGRESS: f64 = 0.9999;
+
+pub fn check_block_count(session: &Session) {
+    match session.rpc_client.get_block_count() {
+        Ok(block_count) => {
+            out!(session, "Current block count: {}", block_count);
+            session.out.json(&json!({ "block_count": block_count }));
+        }
+        Err(e) => err!(session, "Failed to get block count. Error {:?}", e)
+    }
+}
+
+// bitcoind reports "Block not available (pruned data)" for blocks outside the retained window
+fn is_pruned_block_error(e: &bitcoincore_rpc::Error) -> bool {
+    e.to_string().contains("pruned data")
+}
+
+pub fn warn_if_pruned(session: &Session) {
+    if let Ok(info) = session.rpc_client.get_blockchain_info() {
+        if info.pruned {
+            out!(
+                session,
+                "Warning: node is pruned, blocks below height {} are not available",
+                info.prune_height.unwrap_or(0)
+            );
+        }
+    }
+}
+
+// A height or block hash argument, resolved to a hash; reports the failure itself
+fn resolve_block_ref(session: &Session, block_ref: &str) -> Option<BlockHash> {
+    match block_ref.parse::<u64>() {
+        Ok(height) => match session.rpc_client.get_block_hash(height) {
+            Ok(hash) => Some(hash),
+            // RPC_INVALID_PARAMETER
+            Err(e) if rpc_error_code(&e) == Some(-8) => {
+                match session.rpc_client.get_block_count() {
+                    Ok(tip) => err!(session, "Height {} is out of range, the chain tip is at {}", height, tip),
+                    Err(_) => err!(session, "Height {} is out of range", height),
+                }
+                None
+            }
+            Err(e) => {
+                err!(session, "Failed to get block hash at height {}. Error {:?}", height, e);
+                None
+            }
+        },
+        Err(_) => match BlockHash::from_str(block_ref) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                err!(session, "Error parsing block hash {:?}", e);
+                None
+            }
+        },
+    }
+}
+
+fn is_synced(info: &GetBlockchainInfoResult) -> bool {
+    !info.initial_block_download && info.blocks == info.headers && info.verification_progress >= SYNCED_PROGRESS
+}
+
+fn describe_sync(info: &GetBlockchainInfoResult) -> String {
+    format!(
+        "{:.2}% verified, {} of {} headers{}",
+        info.verification_progress * 100.0,
+        info.blocks,
+        info.headers,
+        if info.initial_block_download { " (initial block download)" } else { "" }
+    )
+}
+
+pub fn show_uptime(session: &Session) {
+    match session.rpc_client.check_uptime() {
+        Ok(uptime) => {
+            out!(session, "Node uptime: {}s ({}d {:02}h {:02}m {:02}s)", uptime, uptime / 86400, uptime / 3600 % 24, uptime / 60 % 60, uptime % 60);
+            session.out.json(&json!({ "uptime_secs": uptime }));
+        }
+        Err(e) => err!(session, "Failed to get uptime. Error {:?}", e)
+    }
+}
+
+pub fn show_sync_status(session: &Session) {
+    match session.rpc_client.get_blockchain_info() {
+        Ok(info) => {
+            let synced = is_synced(&info);
+            out!(session, "Sync: {}", describe_sync(&info));
+            out!(session, "{}", if synced { "Node is synced" } else { "Node is still syncing" });
+            session.out.json(&json!({
+                "synced": synced,
+                "verification_progress": info.verification_progress,
+                "blocks": info.blocks,
+                "headers": info.headers,
+                "initial_block_download": info.initial_block_download,
+            }));
+        }
+        Err(e) => err!(session, "Failed to get blockchain info. Error {:?}", e)
+    }
+}
+
+// The independent reads behind status, sent as one batch
+fn status_calls() -> Vec<(&'static str, Vec<serde_json::Value>)> {
+    vec![
+        ("getblockchaininfo", vec![]),
+        ("getbalances", vec![]),
+        ("getmempoolinfo", vec![]),
+        ("getconnectioncount", vec![]),
+    ]
+}
+
+pub fn show_status(session: &Session) {
+    let started = Instant::now();
+    let results = session.rpc_client.batch(&status_calls());
+    let elapsed = started.elapsed();
+    let (chain, balances, mempool, peers) = match (&results[0], &results[1], &results[2], &results[3]) {
+        (Ok(chain), Ok(balances), Ok(mempool), Ok(peers)) => (chain, balances, mempool, peers),
+        (Err(e), ..) | (_, Err(e), ..) | (.., Err(e), _) | (.., Err(e)) => {
+            err!(session, "Failed to get node status. Error {:?}", e);
+            return;
+        }
+    };
+
+    out!(session, "Chain: {} at height {} ({} headers)", chain["chain"].as_str().unwrap_or_default(), chain["blocks"], chain["headers"]);
+    out!(session, "Tip: {}", chain["bestblockhash"].as_str().unwrap_or_default());
+    out!(session, "Balance: {} ({} unconfirmed, {} immature)",
+        format_btc_value(&balances["mine"]["trusted"]),
+        format_btc_value(&balances["mine"]["untrusted_pending"]),
+        format_btc_value(&balances["mine"]["immature"]));
+    out!(session, "Mempool: {} transaction(s), {} bytes", mempool["size"], mempool["bytes"]);
+    out!(session, "Peers: {}", peers);
+    out!(session, "Fetched in {:.1} ms", elapsed.as_secs_f64() * 1000.0);
+    session.out.json(&json!({
+        "chain": chain["chain"],
+        "blocks": chain["blocks"],
+        "headers": chain["headers"],
+        "best_block_hash": chain["bestblockhash"],
+        "balance": balances["mine"]["trusted"],
+        "unconfirmed_balance": balances["mine"]["untrusted_pending"],
+        "immature_balance": balances["mine"]["immature"],
+        "mempool_size": mempool["size"],
+        "mempool_bytes": mempool["bytes"],
+        "peers": peers,
+        "elapsed_ms": elapsed.as_secs_f64() * 1000.0,
+    }));
+}
+
+pub struct Deployment {
+    pub name: String,
+    pub kind: String,
+    pub status: String,
+    pub height: Option<u64>,
+    // (blocks signalling, blocks elapsed, threshold, period) in the current BIP9 period
+    pub signalling: Option<(u64, u64, u64, u64)>,
+}
+
+fn parse_signalling(statistics: &serde_json::Value) -> Option<(u64, u64, u64, u64)> {
+    Some((
+        statistics["count"].as_u64()?,
+        statistics["elapsed"].as_u64()?,
+        statistics["threshold"].as_u64()?,
+        statistics["period"].as_u64()?,
+    ))
+}
+
+// One "softforks"/"deployments" map entry, as getdeploymentinfo (v23+) and getblockchaininfo (v0.19-v22) report it
+fn parse_deployment(name: &str, entry: &serde_json::Value) -> Deployment {
+    let bip9 = &entry["bip9"];
+    let status = match bip9["status"].as_str() {
+        Some(status) => status.to_string(),
+        None if entry["active"].as_bool() == Some(true) => "active".to_string(),
+        None => "defined".to_string(),
+    };
+    Deployment {
+        name: name.to_string(),
+        kind: entry["type"].as_str().unwrap_or("unknown").to_string(),
+        status,
+        height: entry["height"].as_u64().or_else(|| bip9["since"].as_u64()),
+        signalling: parse_signalling(&bip9["statistics"]),
+    }
+}
+
+// The deployments in whichever schema the node uses: a name-keyed map, or before v0.19 a "softforks"
+// array of ISM forks alongside a separate "bip9_softforks" map
+pub fn parse_deployments(info: &serde_json::Value) -> Vec<Deployment> {
+    let mut deployments = Vec::new();
+    if let Some(map) = info["deployments"].as_object().or_else(|| info["softforks"].as_object()) {
+        deployments.extend(map.iter().map(|(name, entry)| parse_deployment(name, entry)));
+    }
+    if let Some(forks) = info["softforks"].as_array() {
+        deployments.extend(forks.iter().map(|fork| Deployment {
+            name: fork["id"].as_str().unwrap_or("unknown").to_string(),
+            kind: "buried".to_string(),
+            status: if fork["reject"]["status"].as_bool() == Some(true) { "active" } else { "defined" }.to_string(),
+            height: None,
+            signalling: None,
+        }));
+    }
+    if let Some(map) = info["bip9_softforks"].as_object() {
+        deployments.extend(map.iter().map(|(name, entry)| Deployment {
+            name: name.clone(),
+            kind: "bip9".to_string(),
+            status: entry["status"].as_str().unwrap_or("unknown").to_string(),
+            height: entry["since"].as_u64(),
+            signalling: parse_signalling(&entry["statistics"]),
+        }));
+    }
+    deployments
+}
+
+pub fn show_softforks(session: &Session) {
+    let rpc_client = &session.rpc_client;
+    // getdeploymentinfo replaced the softforks section of getblockchaininfo in v23
+    let info = match rpc_client.call::<serde_json::Value>("getdeploymentinfo", &[]) {
+        Err(e) if rpc_error_code(&e) == Some(RPC_METHOD_NOT_FOUND) => rpc_client.call("getblockchaininfo", &[]),
+        result => result,
+    };
+    let info = match info {
+        Ok(info) => info,
+        Err(e) => {
+            err!(session, "Failed to get deployment info. Error {:?}", e);
+            return;
+        }
+    };
+
+    let deployments = parse_deployments(&info);
+    if deployments.is_empty() {
+        out!(session, "The node reported no softfork deployments");
+        session.out.json(&json!({ "deployments": [] }));
+        return;
+    }
+    let name_width = deployments.iter().map(|deployment| deployment.name.len()).max().unwrap_or(0).max(4);
+    out!(session, "{:<width$}  {:<7}  {:<10}  {:>8}  SIGNALLING", "NAME", "TYPE", "STATUS", "SINCE", width = name_width);
+    for deployment in &deployments {
+        let height = deployment.height.map_or_else(|| "-".to_string(), |height| height.to_string());
+        let signalling = match deployment.signalling {
+            Some((count, elapsed, threshold, period)) => {
+                format!("{}/{} blocks, {} of {} needed", count, elapsed, threshold, period)
+            }
+            None => "-".to_string(),
+        };
+        out!(
+            session,
+            "{:<width$}  {:<7}  {:<10}  {:>8}  {}",
+            deployment.name, deployment.kind, deployment.status, height, signalling, width = name_width
+        );
+    }
+
+    let json_deployments: Vec<serde_json::Value> = deployments.iter()
+        .map(|deployment| json!({
+            "name": deployment.name,
+            "type": deployment.kind,
+            "status": deployment.status,
+            "height": deployment.height,
+            "signalling": deployment.signalling.map(|(count, elapsed, threshold, period)| json!({
+                "count": count, "elapsed": elapsed, "threshold": threshold, "period": period,
+            })),
+        }))
+        .collect();
+    session.out.json(&json!({ "deployments": json_deployments }));
+}
+
+pub fn wait_for_sync(session: &Session, timeout_secs: u64) {
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(timeout_secs);
+    let mut next_report = started;
+    out!(session, "Waiting up to {}s for the node to sync", timeout_secs);
+
+    loop {
+        let info = match session.rpc_client.get_blockchain_info() {
+            Ok(info) => info,
+            Err(e) => {
+                err!(session, "Failed to get blockchain info. Error {:?}", e);
+                return;
+            }
+        };
+
+        if is_synced(&info) {
+            let waited = started.elapsed().as_secs_f64();
+            out!(session, "Node synced at height {} after {:.1}s", info.blocks, waited);
+            session.out.json(&json!({ "synced": true, "blocks": info.blocks, "waited_secs": waited }));
+            return;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            out!(session, "Timed out after {}s waiting for sync: {}", timeout_secs, describe_sync(&info));
+            session.out.json(&json!({ "synced": false, "verification_progress": info.verification_progress, "waited_secs": timeout_secs }));
+            return;
+        }
+        if now >= next_report {
+            out!(session, "Sync: {}", describe_sync(&info));
+            session.out.progress("Synced blocks", info.blocks, info.headers);
+            next_report = now + SYNC_REPORT_INTERVAL;
+        }
+        thread::sleep(SYNC_POLL_INTERVAL);
+    }
+}
+
+pub fn show_block(session: &Session, block_ref: &str) {
+    let rpc_client = &session.rpc_client;
+    let Some(block_hash) = resolve_block_ref(session, block_ref) else { return };
+
+    match rpc_client.get_block_info(&block_hash) {
+        Ok(block) => {
+            out!(session, "Block {} at height {}", block.hash, block.height);
+            out!(session, "Time: {}, Transactions: {}, Size: {}", block.time, block.n_tx, block.size);
+            out!(session, "Confirmations: {}", block.confirmations);
+            session.out.json(&json!({
+                "hash": block.hash,
+                "height": block.height,
+                "time": block.time,
+                "n_tx": block.n_tx,
+                "size": block.size,
+                "confirmations": block.confirmations,
+            }));
+        }
+        Err(e) if is_pruned_block_error(&e) => {
+            out!(session, "Block {} is not available: the node is pruned and this block is outside its retained window", block_hash);
+        }
+        Err(e) => err!(session, "Failed to get block {}. Error {:?}", block_hash, e)
+    }
+}
+
+// Shown by blockstats when no stat names are given
+const DEFAULT_BLOCK_STATS: &[&str] = &["height", "txs", "totalfee", "avgfeerate", "medianfee", "total_size", "total_weight"];
+
+pub fn show_block_stats(session: &Session, block_ref: &str, stats: &[&str]) {
+    let Some(block_hash) = resolve_block_ref(session, block_ref) else { return };
+
+    let selected = if stats.is_empty() { DEFAULT_BLOCK_STATS } else { stats };
+    match session.rpc_client.call::<serde_json::Value>("getblockstats", &[json!(block_hash), json!(selected)]) {
+        Ok(block_stats) => {
+            out!(session, "Block {}", block_hash);
+            let width = selected.iter().map(|stat| stat.len()).max().unwrap_or(0);
+            for stat in selected {
+                out!(session, "{:<width$}  {}", stat, block_stats[stat], width = width);
+            }
+            session.out.json(&block_stats);
+        }
+        // The node names the offending stat in its message, e.g. "Invalid selected statistic 'fees'"
+        Err(e) if rpc_error_code(&e) == Some(-8) => err!(session, "{}", rpc_error_message(&e).unwrap_or_default()),
+        Err(e) if is_pruned_block_error(&e) => {
+            out!(session, "Block {} is not available: the node is pruned and this block is outside its retained window", block_hash);
+        }
+        Err(e) => err!(session, "Failed to get stats for block {}. Error {:?}", block_hash, e)
+    }
+}
+
+pub fn add_note(session: &mut Session, height: u64, text: &str) {
+    match session.rpc_client.get_block_count() {
+        Ok(tip) if height > tip => {
+            err!(session, "Height {} is above the chain tip at {}", height, tip);
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            err!(session, "Failed to get block count. Error {:?}", e);
+            return;
+        }
+    }
+
+    if let Some(log) = &session.activity_log {
+        log.record_note(height, text);
+    }
+    match session.notes.insert(height, text.to_string()) {
+        Some(previous) => out!(session, "Replaced note at height {} (was: {})", height, previous),
+        None => out!(session, "Noted height {}", height),
+    }
+}
+
+pub fn list_notes(session: &Session) {
+    if session.notes.is_empty() {
+        out!(session, "No notes, add one with note <height> <text>");
+        return;
+    }
+
+    let rpc_client = &session.rpc_client;
+    let mut notes = Vec::new();
+    for (height, text) in &session.notes {
+        // The block at a noted height can change after a reorg, so look it up now rather than when noted
+        let header = rpc_client.get_block_hash(*height).and_then(|hash| rpc_client.get_block_header_info(&hash));
+        match &header {
+            Ok(header) => out!(session, "{:>8}  {}  {}  {}", height, header.hash, header.time, text),
+            Err(_) => out!(session, "{:>8}  (block unavailable)  {}", height, text),
+        }
+        notes.push(json!({
+            "height": height,
+            "hash": header.as_ref().ok().map(|header| header.hash),
+            "time": header.as_ref().ok().map(|header| header.time),
+            "note": text,
+        }));
+    }
+    session.out.json(&json!({ "notes": notes }));
+}
+
+pub fn parse_template_rule(rule: &str) -> Option<GetBlockTemplateRules> {
+    match rule {
+        "segwit" => Some(GetBlockTemplateRules::SegWit),
+        "signet" => Some(GetBlockTemplateRules::Signet),
+        "csv" => Some(GetBlockTemplateRules::Csv),
+        "taproot" => Some(GetBlockTemplateRules::Taproot),
+        _ => None,
+    }
+}
+
+pub fn show_block_template(session: &Session, rules: &[GetBlockTemplateRules]) {
+    match session.rpc_client.get_block_template(GetBlockTemplateModes::Template, rules, &[]) {
+        Ok(template) => {
+            let total_fees: Amount = template.transactions.iter().map(|tx| tx.fee).sum();
+            out!(session, "Template height: {}", template.height);
+            out!(session, "Transactions: {}", template.transactions.len());
+            out!(session, "Total fees: {}", total_fees);
+            out!(session, "Coinbase value: {}", template.coinbase_value);
+        }
+        // RPC_CLIENT_NOT_CONNECTED and RPC_CLIENT_IN_INITIAL_DOWNLOAD
+        Err(e) if rpc_error_code(&e) == Some(-9) => {
+            out!(session, "Node has no peers, getblocktemplate requires at least one connection (or -test=getblocktemplate on newer nodes)")
+        }
+        Err(e) if rpc_error_code(&e) == Some(-10) => {
+            out!(session, "Node is still in initial block download, mine a block first to leave IBD")
+        }
+        Err(e) => err!(session, "Failed to get block template. Error {:?}", e)
+    }
+}
+
+// (height, tip, peer count) from one node's compare batch
+fn node_tip(
+    results: Result<Vec<Result<serde_json::Value, bitcoincore_rpc::Error>>, bitcoincore_rpc::Error>,
+) -> Result<(u64, BlockHash, u64), bitcoincore_rpc::Error> {
+    let [info, peers]: [_; 2] = results?.try_into().expect("one result per call");
+    let info: GetBlockchainInfoResult = serde_json::from_value(info?)?;
+    Ok((info.blocks, info.best_block_hash, serde_json::from_value(peers?)?))
+}
+
+pub fn compare_nodes(session: &Session) {
+    let urls = session.node_urls();
+    if urls.len() < 2 {
+        out!(session, "Only one node configured, add more with --node <URL>");
+        return;
+    }
+
+    // One round trip per node
+    let tips: Vec<_> = session.batch_nodes(&[("getblockchaininfo", vec![]), ("getconnectioncount", vec![])])
+        .into_iter()
+        .map(node_tip)
+        .collect();
+
+    let url_width = urls.iter().map(|url| url.len()).max().unwrap_or(0);
+    for (url, tip) in urls.iter().zip(&tips) {
+        match tip {
+            Ok((height, hash, peers)) => {
+                out!(session, "{:<width$}  {:>8}  {}  {} peer(s)", url, height, hash, peers, width = url_width)
+            }
+            Err(e) => out!(session, "{:<width$}  UNREACHABLE ({})", url, e, width = url_width),
+        }
+    }
+
+    let reachable: Vec<(u64, BlockHash)> = tips.iter()
+        .filter_map(|tip| tip.as_ref().ok().map(|(height, hash, _)| (*height, *hash)))
+        .collect();
+    let unreachable = tips.len() - reachable.len();
+    let in_sync = match reachable.first() {
+        None => {
+            out!(session, "No nodes reachable");
+            false
+        }
+        Some(first) if reachable.iter().all(|tip| tip == first) => {
+            out!(session, "IN SYNC ({} node(s), {} unreachable)", reachable.len(), unreachable);
+            true
+        }
+        Some(_) => {
+            out!(session, "DIVERGED ({} node(s), {} unreachable)", reachable.len(), unreachable);
+            false
+        }
+    };
+
+    let nodes: Vec<serde_json::Value> = urls.iter().zip(&tips)
+        .map(|(url, tip)| match tip {
+            Ok((height, hash, peers)) => json!({ "url": url, "height": height, "best_block_hash": hash, "peers": peers }),
+            Err(e) => json!({ "url": url, "error": e.to_string() }),
+        })
+        .collect();
+    session.out.json(&json!({ "in_sync": in_sync, "nodes": nodes }));
+}
+
+// Asks every node for its block hash at one height, None where that node's chain is shorter
+pub fn verify_block(session: &Session, height: u64) {
+    let urls = session.node_urls();
+    if urls.len() < 2 {
+        out!(session, "Only one node configured, add more with --node <URL>");
+        return;
+    }
+
+    let hashes = session.query_nodes(|client| match client.get_block_hash(height) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(e) if rpc_error_code(&e) == Some(-8) => Ok(None),
+        Err(e) => Err(e),
+    });
+
+    let url_width = urls.iter().map(|url| url.len()).max().unwrap_or(0);
+    for (url, hash) in urls.iter().zip(&hashes) {
+        match hash {
+            Ok(Some(hash)) => out!(session, "{:<width$}  {}", url, hash, width = url_width),
+            Ok(None) => out!(session, "{:<width$}  NO BLOCK (chain shorter than {})", url, height, width = url_width),
+            Err(e) => out!(session, "{:<width$}  UNREACHABLE ({})", url, e, width = url_width),
+        }
+    }
+
+    let found: Vec<&BlockHash> = hashes.iter().filter_map(|hash| hash.as_ref().ok()?.as_ref()).collect();
+    let missing = hashes.iter().filter(|hash| matches!(hash, Ok(None))).count();
+    let unreachable = hashes.iter().filter(|hash| hash.is_err()).count();
+    let agree = match found.first() {
+        None => {
+            out!(session, "No node has a block at height {}", height);
+            false
+        }
+        Some(first) if found.iter().all(|hash| hash == first) => {
+            out!(session, "MATCH at height {} ({} node(s), {} missing the block, {} unreachable)",
+                height, found.len(), missing, unreachable);
+            true
+        }
+        Some(_) => {
+            out!(session, "MISMATCH at height {} ({} node(s), {} missing the block, {} unreachable)",
+                height, found.len(), missing, unreachable);
+            false
+        }
+    };
+
+    let nodes: Vec<serde_json::Value> = urls.iter().zip(&hashes)
+        .map(|(url, hash)| match hash {
+            Ok(Some(hash)) => json!({ "url": url, "block_hash": hash }),
+            Ok(None) => json!({ "url": url, "block_hash": null }),
+            Err(e) => json!({ "url": url, "error": e.to_string() }),
+        })
+        .collect();
+    session.out.json(&json!({ "height": height, "match": agree, "nodes": nodes }));
+}
+
+fn has_block(client: &Client, height: u64, hash: &BlockHash) -> Result<bool, bitcoincore_rpc::Error> {
+    if client.get_block_count()? < height {
+        return Ok(false);
+    }
+    Ok(client.get_block_hash(height)? == *hash)
+}
+
+// Mines one block on the primary node and times how long the first --node takes to receive it
+pub fn measure_propagation(session: &Session, timeout_secs: u64) {
+    let Some(peer_url) = session.extra_nodes.first() else {
+        err!(session, "proptime needs a second node, add one with --node <URL>");
+        return;
+    };
+    let peer = match session.node_client(peer_url) {
+        Ok(peer) => peer,
+        Err(e) => {
+            err!(session, "Failed to create client for {}. Error {:?}", peer_url, e);
+            return;
+        }
+    };
+
+    let rpc_client = &session.rpc_client;
+    let mined = mining_address(session)
+        .and_then(|address| rpc_client.generate_to_address(1, &address))
+        .and_then(|hashes| Ok((rpc_client.get_block_count()?, hashes[0])));
+    let (height, hash) = match mined {
+        Ok(mined) => mined,
+        Err(e) => {
+            err!(session, "Failed to mine block. Error {:?}", e);
+            return;
+        }
+    };
+    session.record_blocks_mined(1);
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(timeout_secs);
+    out!(session, "Mined block {} at height {}, waiting for {}", hash, height, peer_url);
+
+    loop {
+        match has_block(&peer, height, &hash) {
+            Ok(true) => {
+                let elapsed_ms = started.elapsed().as_millis();
+                out!(session, "Block propagated to {} in {} ms", peer_url, elapsed_ms);
+                session.out.json(&json!({ "block": hash, "peer": peer_url, "propagated": true, "elapsed_ms": elapsed_ms as u64 }));
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                err!(session, "Failed to query {}. Error {:?}", peer_url, e);
+                return;
+            }
+        }
+
+        if Instant::now() >= deadline {
+            out!(session, "Propagation failed: {} did not receive block {} within {}s", peer_url, hash, timeout_secs);
+            session.out.json(&json!({ "block": hash, "peer": peer_url, "propagated": false, "timeout_secs": timeout_secs }));
+            return;
+        }
+        thread::sleep(PROPAGATION_POLL_INTERVAL);
+    }
+}
+
+pub fn show_tx_out_set_info(session: &Session) {
+    out!(session, "Computing UTXO set statistics, this can take a while on large chains...");
+
+    // Read the raw result since the hash field is hash_serialized_2 or _3 depending on the node version
+    let info = session.run_blocking("computing UTXO set statistics", |client| client.call::<serde_json::Value>("gettxoutsetinfo", &[]));
+    match info {
+        Ok(info) => {
+            let utxo_hash = ["hash_serialized_3", "hash_serialized_2", "muhash"].iter()
+                .find_map(|field| info[field].as_str())
+                .unwrap_or("unavailable");
+            let total_amount = info["total_amount"].as_f64().and_then(|btc| Amount::from_btc(btc).ok());
+
+            out!(session, "Height: {}", info["height"]);
+            out!(session, "UTXOs: {}", info["txouts"]);
+            match total_amount {
+                Some(total) => out!(session, "Total amount: {}", total),
+                None => out!(session, "Total amount: {}", info["total_amount"]),
+            }
+            out!(session, "UTXO set hash: {}", utxo_hash);
+            session.out.json(&json!({
+                "height": info["height"],
+                "best_block": info["bestblock"],
+                "txouts": info["txouts"],
+                "total_amount": info["total_amount"],
+                "hash": utxo_hash,
+            }));
+        }
+        Err(e) if is_timeout(&e) => {
+            out!(session, "gettxoutsetinfo did not finish within the RPC timeout of {}s, retry with a larger --rpc-timeout", session.rpc_timeout.as_secs())
+        }
+        Err(e) => err!(session, "Failed to get UTXO set info. Error {:?}", e)
+    }
+}
+
+pub const DEFAULT_COINBASE_COUNT: u64 = 10;
+// OP_RETURN, a 36 byte push, then the BIP141 commitment header
+const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+pub fn show_coinbases(session: &Session, count: u64) {
+    let rpc_client = &session.rpc_client;
+    let tip = match rpc_client.get_block_count() {
+        Ok(tip) => tip,
+        Err(e) => {
+            err!(session, "Failed to get block count. Error {:?}", e);
+            return;
+        }
+    };
+
+    // Address (or script asm when it has none) -> total paid over the scanned blocks
+    let mut totals: BTreeMap<String, Amount> = BTreeMap::new();
+    let mut blocks = Vec::new();
+    let first = (tip + 1).saturating_sub(count);
+    for height in (first..=tip).rev() {
+        let block = match rpc_client.get_block_hash(height).and_then(|hash| rpc_client.get_block(&hash)) {
+            Ok(block) => block,
+            Err(e) if is_pruned_block_error(&e) => {
+                out!(session, "{:>8}  (pruned, stopping here)", height);
+                break;
+            }
+            Err(e) => {
+                err!(session, "Failed to get block at height {}. Error {:?}", height, e);
+                return;
+            }
+        };
+        let Some(coinbase) = block.txdata.first() else { continue };
+
+        let mut payouts = Vec::new();
+        for output in &coinbase.output {
+            let script = &output.script_pubkey;
+            // Not a payout, and a zero-value OP_RETURN can't be one either
+            let is_commitment = script.as_bytes().starts_with(&WITNESS_COMMITMENT_PREFIX);
+            if is_commitment || (script.is_op_return() && output.value == Amount::ZERO) {
+                continue;
+            }
+            let recipient = Address::from_script(script, session.network)
+                .map_or_else(|_| format!("script {}", script.to_asm_string()), |address| address.to_string());
+            *totals.entry(recipient.clone()).or_default() += output.value;
+            payouts.push((recipient, output.value));
+        }
+
+        match payouts.as_slice() {
+            [] => out!(session, "{:>8}  (no payout)", height),
+            [(recipient, value)] => out!(session, "{:>8}  {}  {}", height, value, recipient),
+            _ => {
+                out!(session, "{:>8}  {} outputs", height, payouts.len());
+                for (recipient, value) in &payouts {
+                    out!(session, "          {}  {}", value, recipient);
+                }
+            }
+        }
+        let payouts: Vec<serde_json::Value> = payouts.iter()
+            .map(|(recipient, value)| json!({ "recipient": recipient, "amount": value.to_btc() }))
+            .collect();
+        blocks.push(json!({ "height": height, "hash": block.block_hash(), "payouts": payouts }));
+    }
+
+    out!(session, "{} recipient(s) over {} block(s)", totals.len(), blocks.len());
+    for (recipient, total) in &totals {
+        out!(session, "  {}  {}", total, recipient);
+    }
+    let totals: Vec<serde_json::Value> = totals.iter()
+        .map(|(recipient, total)| json!({ "recipient": recipient, "total": total.to_btc() }))
+        .collect();
+    session.out.json(&json!({ "blocks": blocks, "totals": totals }));
+}
+
+pub const DEFAULT_FEE_HISTORY_BLOCKS: u64 = 10;
+// A day of blocks, each one is a getblockstats call that reads the whole block
+pub const MAX_FEE_HISTORY_BLOCKS: u64 = 144;
+const FEE_HISTORY_STATS: &[&str] = &["height", "txs", "avgfeerate", "feerate_percentiles"];
+
+pub fn show_fee_history(session: &Session, count: u64) {
+    let rpc_client = &session.rpc_client;
+    let tip = match rpc_client.get_block_count() {
+        Ok(tip) => tip,
+        Err(e) => {
+            err!(session, "Failed to get block count. Error {:?}", e);
+            return;
+        }
+    };
+
+    let first = (tip + 1).saturating_sub(count);
+    let calls: Vec<(&str, Vec<serde_json::Value>)> = (first..=tip)
+        .map(|height| ("getblockstats", vec![json!(height), json!(FEE_HISTORY_STATS)]))
+        .collect();
+
+    // (height, txs, mean sat/vB, median sat/vB), oldest first
+    let mut rows = Vec::new();
+    for (height, result) in (first..=tip).zip(rpc_client.batch(&calls)) {
+        match result {
+            Ok(block_stats) => {
+                // A coinbase-only block reports 0 for both, which is what it paid
+                let mean = block_stats["avgfeerate"].as_f64().unwrap_or(0.0);
+                let median = block_stats["feerate_percentiles"][2].as_f64().unwrap_or(0.0);
+                rows.push((height, block_stats["txs"].as_u64().unwrap_or(0), mean, median));
+            }
+            Err(e) if is_pruned_block_error(&e) => out!(session, "{:>8}  (pruned)", height),
+            Err(e) => err!(session, "Failed to get stats for block {}. Error {:?}", height, e),
+        }
+    }
+    if rows.is_empty() {
+        return;
+    }
+
+    out!(session, "{:>8}  {:>6}  {:>10}  {:>10}", "Height", "Txs", "Mean", "Median");
+    for (height, txs, mean, median) in &rows {
+        out!(session, "{:>8}  {:>6}  {:>10.2}  {:>10.2}", height, txs, mean, median);
+    }
+
+    let overall = rows.iter().map(|row| row.2).sum::<f64>() / rows.len() as f64;
+    // Older half against newer half; with a single block there is nothing to compare
+    let (older, newer) = rows.split_at(rows.len() / 2);
+    let half_mean = |half: &[(u64, u64, f64, f64)]| half.iter().map(|row| row.2).sum::<f64>() / half.len() as f64;
+    let trend = if older.is_empty() {
+        "flat"
+    } else {
+        match half_mean(newer) - half_mean(older) {
+            change if change > 0.5 => "rising",
+            change if change < -0.5 => "falling",
+            _ => "flat",
+        }
+    };
+    out!(session, "Mean over {} block(s): {:.2} sat/vB, trend {}", rows.len(), overall, trend);
+
+    let blocks: Vec<serde_json::Value> = rows.iter()
+        .map(|(height, txs, mean, median)| json!({ "height": height, "txs": txs, "mean_feerate": mean, "median_feerate": median }))
+        .collect();
+    session.out.json(&json!({ "blocks": blocks, "mean_feerate": overall, "trend": trend }));
+}
+
+// Heights per pair of batched round trips (hashes, then headers)
+const DUMP_HEADERS_BATCH_SIZE: u64 = 500;
+
+// One "height hash time previous_hash" line per block, so dumps from two nodes can be diffed directly
+pub fn dump_headers(session: &Session, from_height: u64, to_height: u64, path: Option<&str>) {
+    let rpc_client = &session.rpc_client;
+    let tip = match rpc_client.get_block_count() {
+        Ok(tip) => tip,
+        Err(e) => {
+            err!(session, "Failed to get block count. Error {:?}", e);
+            return;
+        }
+    };
+    if from_height > tip {
+        err!(session, "From height {} is above the chain tip at {}", from_height, tip);
+        return;
+    }
+    let to_height = if to_height > tip {
+        out!(session, "Warning: to height {} is above the chain tip, stopping at {}", to_height, tip);
+        tip
+    } else {
+        to_height
+    };
+
+    let mut lines = Vec::new();
+    let mut batch_start = from_height;
+    while batch_start <= to_height {
+        let batch_end = (batch_start + DUMP_HEADERS_BATCH_SIZE - 1).min(to_height);
+        let hash_calls: Vec<_> = (batch_start..=batch_end).map(|height| ("getblockhash", vec![json!(height)])).collect();
+        let hashes = match rpc_client.batch(&hash_calls).into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                err!(session, "Failed to get block hashes from height {}. Error {:?}", batch_start, e);
+                return;
+            }
+        };
+        let header_calls: Vec<_> = hashes.iter().map(|hash| ("getblockheader", vec![hash.clone()])).collect();
+        let headers = match rpc_client.batch(&header_calls).into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(headers) => headers,
+            Err(e) => {
+                err!(session, "Failed to get block headers from height {}. Error {:?}", batch_start, e);
+                return;
+            }
+        };
+        for (height, header) in (batch_start..=batch_end).zip(&headers) {
+            // The genesis block has no previous hash
+            lines.push(format!(
+                "{} {} {} {}",
+                height,
+                header["hash"].as_str().unwrap_or_default(),
+                header["time"],
+                header["previousblockhash"].as_str().unwrap_or("-")
+            ));
+        }
+        session.out.progress("Exported headers", batch_end - from_height + 1, to_height - from_height + 1);
+        batch_start = batch_end + 1;
+    }
+
+    match path {
+        Some(path) => match fs::write(path, lines.join("\n") + "\n") {
+            Ok(()) => out!(session, "Wrote {} header(s), heights {} to {}, to {}", lines.len(), from_height, to_height, path),
+            Err(e) => {
+                err!(session, "Failed to write {}: {}", path, e);
+                return;
+            }
+        },
+        None => {
+            for line in &lines {
+                out!(session, "{}", line);
+            }
+        }
+    }
+    session.out.json(&json!({ "from": from_height, "to": to_height, "count": lines.len(), "path": path }));
+}
+
+pub const DEFAULT_BENCH_COUNT: u64 = 100;
+pub const MAX_BENCH_COUNT: u64 = 10_000;
+
+// Nearest-rank percentile of an ascending list
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+// getblockcount round trips through the session's client, so retries and the --rpc-timeout apply as for any command
+pub fn bench_rpc(session: &Session, count: u64) {
+    out!(session, "Timing {} getblockcount call(s) against {}", count, session.rpc_url);
+    let started = Instant::now();
+    let mut latencies = Vec::with_capacity(count as usize);
+    let progress_step = (count / 100).max(1);
+    for done in 0..count {
+        let call_started = Instant::now();
+        if let Err(e) = session.rpc_client.get_block_count() {
+            err!(session, "Call {} failed, stopping. Error {:?}", done + 1, e);
+            if latencies.is_empty() {
+                return;
+            }
+            break;
+        }
+        latencies.push(call_started.elapsed());
+        // Every 1% at most, so --json and --events don't get a line per call
+        if (done + 1) % progress_step == 0 || done + 1 == count {
+            session.out.progress("Timed calls", done + 1, count);
+        }
+    }
+    let total = started.elapsed();
+
+    latencies.sort();
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let (min, max) = (latencies[0], latencies[latencies.len() - 1]);
+    let (median, p95) = (percentile(&latencies, 50), percentile(&latencies, 95));
+    let throughput = latencies.len() as f64 / total.as_secs_f64();
+    out!(session, "{} call(s) in {:.2}s, {:.1} calls/s", latencies.len(), total.as_secs_f64(), throughput);
+    out!(
+        session,
+        "Latency ms: min {:.2}  median {:.2}  p95 {:.2}  max {:.2}",
+        millis(min), millis(median), millis(p95), millis(max)
+    );
+    session.out.json(&json!({
+        "calls": latencies.len(),
+        "total_secs": total.as_secs_f64(),
+        "calls_per_sec": throughput,
+        "min_ms": millis(min),
+        "median_ms": millis(median),
+        "p95_ms": millis(p95),
+        "max_ms": millis(max),
+    }));
+}
+
+// The status reads sent one call at a time and then as one batch, so the gap is the latency batching saves
+pub fn bench_batch(session: &Session, rounds: u64) {
+    let calls = status_calls();
+    out!(session, "Timing {} round(s) of the {} status reads, separately and batched, against {}", rounds, calls.len(), session.rpc_url);
+    let mut separate = Vec::with_capacity(rounds as usize);
+    let mut batched = Vec::with_capacity(rounds as usize);
+    let progress_step = (rounds / 100).max(1);
+    for done in 0..rounds {
+        let started = Instant::now();
+        let separate_results: Result<Vec<serde_json::Value>, _> = calls.iter()
+            .map(|(cmd, args)| session.rpc_client.call(cmd, args))
+            .collect();
+        let separate_time = started.elapsed();
+
+        let started = Instant::now();
+        let batched_results: Result<Vec<_>, _> = session.rpc_client.batch(&calls).into_iter().collect();
+        let batched_time = started.elapsed();
+
+        if let Err(e) = separate_results.and(batched_results) {
+            err!(session, "Round {} failed, stopping. Error {:?}", done + 1, e);
+            if separate.is_empty() {
+                return;
+            }
+            break;
+        }
+        separate.push(separate_time);
+        batched.push(batched_time);
+        if (done + 1) % progress_step == 0 || done + 1 == rounds {
+            session.out.progress("Timed rounds", done + 1, rounds);
+        }
+    }
+
+    separate.sort();
+    batched.sort();
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let (separate_median, batched_median) = (percentile(&separate, 50), percentile(&batched, 50));
+    let speedup = separate_median.as_secs_f64() / batched_median.as_secs_f64();
+    out!(session, "Separate calls: median {:.2} ms  p95 {:.2} ms", millis(separate_median), millis(percentile(&separate, 95)));
+    out!(session, "Batched:        median {:.2} ms  p95 {:.2} ms", millis(batched_median), millis(percentile(&batched, 95)));
+    out!(session, "Batching saves {:.2} ms per status ({:.1}x)", millis(separate_median) - millis(batched_median), speedup);
+    session.out.json(&json!({
+        "rounds": separate.len(),
+        "calls_per_round": calls.len(),
+        "separate_median_ms": millis(separate_median),
+        "separate_p95_ms": millis(percentile(&separate, 95)),
+        "batched_median_ms": millis(batched_median),
+        "batched_p95_ms": millis(percentile(&batched, 95)),
+        "speedup": speedup,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_session, ScriptedNode};
+
+    fn script_status(node: &ScriptedNode) {
+        node.respond("getblockchaininfo", json!({ "chain": "regtest", "blocks": 101, "headers": 101, "bestblockhash": "00ff" }))
+            .respond("getbalances", json!({ "mine": { "trusted": 50.0, "untrusted_pending": 0.5, "immature": 5000.0 } }))
+            .respond("getmempoolinfo", json!({ "size": 3, "bytes": 672 }))
+            .respond("getconnectioncount", json!(8));
+    }
+
+    #[test]
+    fn status_reads_everything_in_one_round_trip() {
+        let node = ScriptedNode::default();
+        node.accept_batches();
+        script_status(&node);
+        let (session, out) = test_session(&node);
+
+        show_status(&session);
+
+        assert_eq!(node.round_trips(), 1);
+        assert!(out.text().starts_with(
+            "Chain: regtest at height 101 (101 headers)\nTip: 00ff\nBalance: 50 BTC (0.50000000 BTC unconfirmed, 5000 BTC immature)\n\
+             Mempool: 3 transaction(s), 672 bytes\nPeers: 8\n"
+        ), "{}", out.text());
+        assert_eq!(out.json.borrow()[0]["peers"], 8);
+    }
+
+    #[test]
+    fn status_falls_back_to_one_call_per_read() {
+        let node = ScriptedNode::default();
+        script_status(&node);
+        let (session, out) = test_session(&node);
+
+        show_status(&session);
+
+        assert_eq!(node.calls(), ["getblockchaininfo", "getbalances", "getmempoolinfo", "getconnectioncount"]);
+        assert_eq!(node.round_trips(), 5);
+        assert!(out.text().contains("Peers: 8"));
+    }
+
+    #[test]
+    fn status_reports_the_first_failed_read() {
+        let node = ScriptedNode::default();
+        node.accept_batches();
+        node.fail("getbalances", -4, "Error: This wallet has no available keys");
+        script_status(&node);
+        let (session, out) = test_session(&node);
+
+        show_status(&session);
+
+        assert!(out.error_text().starts_with("Failed to get node status."), "{}", out.error_text());
+        assert!(out.json.borrow().is_empty());
+    }
+}
@@ -0,0 +1,909 @@
+use std::num::IntErrorKind;
+use std::str::FromStr;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::{Amount, OutPoint, PublicKey, Txid};
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::json::GetBlockTemplateRules;
+
+use crate::amount::parse_amount;
+use crate::chain::{
+    add_note, bench_batch, bench_rpc, check_block_count, compare_nodes, dump_headers, list_notes, measure_propagation,
+    parse_template_rule, show_block, show_block_stats, show_block_template, show_coinbases, show_fee_history,
+    show_softforks, show_status, show_sync_status, show_tx_out_set_info, show_uptime, verify_block, wait_for_sync,
+    warn_if_pruned, DEFAULT_BENCH_COUNT, DEFAULT_COINBASE_COUNT, DEFAULT_FEE_HISTORY_BLOCKS,
+    DEFAULT_PROPAGATION_TIMEOUT_SECS, DEFAULT_SYNC_WAIT_SECS, MAX_BENCH_COUNT, MAX_FEE_HISTORY_BLOCKS,
+};
+use crate::error::MinerError;
+use crate::events::Event;
+use crate::mempool::{
+    show_confirmation_eta, show_mempool_limits, start_mempool_watch, stop_mempool_watch, wait_for_mempool,
+    DEFAULT_MEMPOOL_WAIT_SECS,
+};
+use crate::message::{parse_message_source, sign_message, verify_message};
+use crate::mining::{
+    change_auto_mine, generate_blocks, generate_blocks_if_required, generate_tagged_blocks, mine_to_height, reset_chain,
+    show_auto_mine, simulate_blocks, AutoMineChange, DEFAULT_SIMULATE_INTERVAL_SECS,
+};
+use crate::peers::{disconnect_peer, parse_peer_ref, set_network_active, show_peer_heights};
+use crate::rawtx::{
+    broadcast_to_all, bump_fee, capture_transaction, double_spend, replace_transaction, replay_transaction, send_locked,
+    send_package, send_raw_transaction, send_spec, send_to_script, show_raw_transaction, test_mempool_accept,
+    DoubleSpendBroadcast,
+};
+use crate::send::{
+    advance_send_wizard, flood_mempool, is_send_option, parse_fee_rate, parse_send_options, parse_send_outputs,
+    request_flood, require_fee_rate_only, send_many, send_to_address, show_fee_estimate, start_send_wizard, sweep,
+    DEFAULT_ESTIMATE_TARGET,
+};
+use crate::session::{PendingAction, Session};
+use crate::snapshot::{list_snapshots, load_snapshot, request_snapshot_load, save_snapshot};
+use crate::wallet::{
+    abandon_transactions, abort_rescan, audit_balance, check_balance, check_reorg_consistency, dump_wallet,
+    import_multisig, import_watch_only, label_transaction, list_locked_utxos, list_unspent, parse_unspent_filters,
+    refill_keypool, request_abandon_all, set_tx_fee, set_utxo_locked, show_conflicts, show_descriptor_addresses,
+    show_labels, show_receive_address, show_received_by_address, show_received_by_label, show_rescan_status,
+    show_total_balance, show_wallet_info, show_wallet_summary, spawn_wallet, start_stable_wait, stop_stable_wait,
+    DEFAULT_DESCRIPTOR_ADDRESS_COUNT, DEFAULT_KEYPOOL_SIZE, DEFAULT_STABLE_QUIET_SECS, DEFAULT_STABLE_TIMEOUT_SECS,
+    MAX_MULTISIG_KEYS,
+};
+use crate::watch::{list_watches, unwatch_address, watch_address};
+
+pub struct CommandHelp {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+}
+
+// Every command the prompt accepts. Argument errors print the matching usage line
+pub const COMMANDS: &[CommandHelp] = &[
+    CommandHelp { name: "sendtoaddress", usage: "sendtoaddress <address|alias> <amount> [feerate=<sat/vB>] [id=<token>] [--estimate-size]", summary: "Send BTC to an address" },
+    CommandHelp { name: "send", usage: "send", summary: "Guided send: prompts for the destination, amount and fee rate" },
+    CommandHelp { name: "sendmany", usage: "sendmany <address|alias>=<amount>... [feerate=<sat/vB>]", summary: "Send to several addresses in one transaction" },
+    CommandHelp { name: "sweep", usage: "sweep <address|alias> [feerate=<sat/vB>]", summary: "Send the whole spendable balance, fee deducted" },
+    CommandHelp { name: "sendspec", usage: "sendspec <file>", summary: "Build, sign and broadcast a transaction from a JSON inputs/outputs spec" },
+    CommandHelp { name: "flood", usage: "flood <count> <amount> [--yes]", summary: "Send many small transactions to the wallet itself and report throughput" },
+    CommandHelp { name: "doublespend", usage: "doublespend <txid:vout> <addr1> <addr2> <amount> [--broadcast|--split]", summary: "Build two conflicting spends of one output and test or broadcast both" },
+    CommandHelp { name: "sendtoscript", usage: "sendtoscript <hex_script> <amount>", summary: "Send to a raw scriptPubKey, for outputs that have no address" },
+    CommandHelp { name: "sendlocked", usage: "sendlocked <address|alias> <amount> <locktime>", summary: "Send with an nLockTime (height below 500000000, else unix time), broadcast once final" },
+    CommandHelp { name: "mempoollimits", usage: "mempoollimits", summary: "Show the node's mempool size, relay fee and expiry policy" },
+    CommandHelp { name: "sendrawtx", usage: "sendrawtx <hex> [--allow-high-fee]", summary: "Broadcast a raw transaction" },
+    CommandHelp { name: "broadcastall", usage: "broadcastall <hex>", summary: "Submit a raw transaction to every --node endpoint and compare their verdicts" },
+    CommandHelp { name: "replaytx", usage: "replaytx <capture|replay> <txid>", summary: "Capture a transaction, then re-broadcast it after a chain reset and check it matches" },
+    CommandHelp { name: "getrawtx", usage: "getrawtx <txid> [--verbose]", summary: "Show any transaction as hex, or decoded with --verbose" },
+    CommandHelp { name: "testmempoolaccept", usage: "testmempoolaccept <hex>...", summary: "Check whether raw transactions would be accepted, without broadcasting" },
+    CommandHelp { name: "sendpackage", usage: "sendpackage <hex>...", summary: "Submit dependent raw transactions together for package relay" },
+    CommandHelp { name: "replace", usage: "replace <old_txid> <new_feerate>", summary: "Replace an unconfirmed BIP125 transaction with a higher fee" },
+    CommandHelp { name: "bumpfee", usage: "bumpfee <txid> [totalfee=<sat>]", summary: "Bump the fee of an unconfirmed wallet transaction" },
+    CommandHelp { name: "receive", usage: "receive", summary: "Print an address to receive funds (--receive-address or a fresh one)" },
+    CommandHelp { name: "receivedbyaddress", usage: "receivedbyaddress <address|alias> [minconf]", summary: "Total received by a wallet address" },
+    CommandHelp { name: "receivedbylabel", usage: "receivedbylabel <label> [minconf]", summary: "Total received by addresses with a label" },
+    CommandHelp { name: "labels", usage: "labels", summary: "List wallet labels with their addresses and amounts received" },
+    CommandHelp { name: "labeltx", usage: "labeltx <txid> <label...>", summary: "Label every address a wallet transaction touched" },
+    CommandHelp { name: "conflicts", usage: "conflicts", summary: "List conflicted wallet transactions" },
+    CommandHelp { name: "lockutxo", usage: "lockutxo <txid:vout>", summary: "Exclude an output from the wallet's coin selection" },
+    CommandHelp { name: "unlockutxo", usage: "unlockutxo <txid:vout>", summary: "Make a locked output spendable again" },
+    CommandHelp { name: "listunspent", usage: "listunspent [minconf] [maxconf=<n>] [minamount=<btc>] [address=<address|alias>]", summary: "List wallet UTXOs, optionally filtered" },
+    CommandHelp { name: "lockedutxos", usage: "lockedutxos", summary: "List locked outputs" },
+    CommandHelp { name: "reorgcheck", usage: "reorgcheck", summary: "Flag wallet transactions that don't match the active chain after a reorg" },
+    CommandHelp { name: "abandonall", usage: "abandonall [--yes]", summary: "Abandon every conflicted wallet transaction" },
+    CommandHelp { name: "getdescriptoraddress", usage: "getdescriptoraddress [count]", summary: "Derive the next receiving addresses from the active descriptor" },
+    CommandHelp { name: "signmessage", usage: "signmessage <address|alias> <message...> | signmessage <address|alias> --file <path>", summary: "Sign a message with an address's key" },
+    CommandHelp { name: "verifymessage", usage: "verifymessage <address|alias> <signature> <message...> | verifymessage <address|alias> <signature> --file <path>", summary: "Check a message signature" },
+    CommandHelp { name: "watch", usage: "watch <address|alias>", summary: "Report when an address receives funds" },
+    CommandHelp { name: "unwatch", usage: "unwatch <address|alias>", summary: "Stop watching an address" },
+    CommandHelp { name: "watches", usage: "watches", summary: "List watched addresses" },
+    CommandHelp { name: "alias", usage: "alias <name> <address>", summary: "Register a name for an address" },
+    CommandHelp { name: "aliases", usage: "aliases", summary: "List registered aliases" },
+    CommandHelp { name: "generate", usage: "generate <count>", summary: "Mine blocks to a new wallet address" },
+    CommandHelp { name: "automine", usage: "automine [on|off|interval <secs>]", summary: "Show or change the auto-mine mode and timer interval" },
+    CommandHelp { name: "simulate", usage: "simulate <duration_secs> [block_interval_secs]", summary: "Mine blocks at randomized, mainnet-like intervals for a while (regtest only)" },
+    CommandHelp { name: "snapshot", usage: "snapshot <save|load|list> [name] [backup_path]", summary: "Record the chain tip under a name, or return the chain to one (load is regtest only)" },
+    CommandHelp { name: "minetoheight", usage: "minetoheight <height>", summary: "Mine just enough blocks to reach a height (regtest only)" },
+    CommandHelp { name: "resetchain", usage: "resetchain <length> [address|alias]", summary: "Discard the chain and mine a fresh one to a fixed address (needs --allow-chain-reset, regtest only)" },
+    CommandHelp { name: "generatetagged", usage: "generatetagged <count> <message...>", summary: "Mine blocks whose coinbase carries a message (regtest)" },
+    CommandHelp { name: "mine", usage: "mine", summary: "Mine a block if the mempool has transactions" },
+    CommandHelp { name: "eta", usage: "eta <txid>", summary: "Estimate when a mempool transaction confirms under the current auto-mine settings" },
+    CommandHelp { name: "mempoolwatch", usage: "mempoolwatch [stop]", summary: "Print transactions entering and leaving the mempool until stopped" },
+    CommandHelp { name: "waitmempool", usage: "waitmempool <txid> [timeout_secs]", summary: "Wait for a transaction to enter the mempool" },
+    CommandHelp { name: "balance", usage: "balance", summary: "Show the wallet balance" },
+    CommandHelp { name: "audit", usage: "audit", summary: "Check the trusted balance against the sum of spendable UTXOs" },
+    CommandHelp { name: "totalbalance", usage: "totalbalance", summary: "Show the trusted balance of every loaded wallet" },
+    CommandHelp { name: "walletinfo", usage: "walletinfo", summary: "Show wallet balances, keypool, rescan and encryption status" },
+    CommandHelp { name: "importmultisig", usage: "importmultisig <n> <m> <pubkeys...>", summary: "Import an n-of-m sortedmulti descriptor as watch-only and show its balance" },
+    CommandHelp { name: "waitstable", usage: "waitstable [quiet_secs] [timeout_secs] | waitstable stop", summary: "Report when the balance, pending included, stops changing (default 10s quiet, 300s timeout)" },
+    CommandHelp { name: "summary", usage: "summary", summary: "Total received, sent, fees paid and generated over the wallet's whole history" },
+    CommandHelp { name: "rescanstatus", usage: "rescanstatus", summary: "Show whether a wallet rescan is running and how far it got" },
+    CommandHelp { name: "abortrescan", usage: "abortrescan", summary: "Cancel a running wallet rescan" },
+    CommandHelp { name: "watchaddress", usage: "watchaddress <address|alias> [label...]", summary: "Import an address as watch-only, rescan, and watch it for payments" },
+    CommandHelp { name: "spawnwallet", usage: "spawnwallet <name> <initial_btc>", summary: "Create a new wallet, fund it from this one and mine a confirming block" },
+    CommandHelp { name: "keypoolrefill", usage: "keypoolrefill [size]", summary: "Top up the wallet's keypool (default 100)" },
+    CommandHelp { name: "dumpwallet", usage: "dumpwallet <path>", summary: "Write every wallet key to a file (needs --allow-key-export, regtest only)" },
+    CommandHelp { name: "estimatefee", usage: "estimatefee [conf_target]", summary: "Estimate the fee rate for confirmation within a number of blocks (default 6)" },
+    CommandHelp { name: "settxfee", usage: "settxfee <BTC/kvB>", summary: "Set the wallet fee rate" },
+    CommandHelp { name: "blockcount", usage: "blockcount", summary: "Show the current block height" },
+    CommandHelp { name: "uptime", usage: "uptime", summary: "Show how long the node has been running" },
+    CommandHelp { name: "status", usage: "status", summary: "Show height, tip, balance, mempool and peers in one batched round trip" },
+    CommandHelp { name: "syncstatus", usage: "syncstatus", summary: "Show how far the node is through block verification" },
+    CommandHelp { name: "waitsync", usage: "waitsync [timeout_secs]", summary: "Wait until the node has caught up with its headers" },
+    CommandHelp { name: "getblock", usage: "getblock <height|hash>", summary: "Show a block" },
+    CommandHelp { name: "blockstats", usage: "blockstats <height|hash> [stats...]", summary: "Show fee and size statistics for a block" },
+    CommandHelp { name: "coinbases", usage: "coinbases [count]", summary: "Show where the coinbase of each recent block paid (default last 10)" },
+    CommandHelp { name: "feehistory", usage: "feehistory [count]", summary: "Mean and median fee rate of recent blocks, with the trend (default 10, max 144)" },
+    CommandHelp { name: "dumpheaders", usage: "dumpheaders <from_height> <to_height> [path]", summary: "List height, hash, time and previous hash per block, to diff chains between nodes" },
+    CommandHelp { name: "note", usage: "note <height> <text...>", summary: "Annotate a block height for this session" },
+    CommandHelp { name: "notes", usage: "notes", summary: "List annotated heights with their block hash and time" },
+    CommandHelp { name: "blocktemplate", usage: "blocktemplate [segwit|signet|csv|taproot ...]", summary: "Show the next candidate block" },
+    CommandHelp { name: "txoutsetinfo", usage: "txoutsetinfo", summary: "Summarise the UTXO set (count, total amount, hash)" },
+    CommandHelp { name: "compare", usage: "compare", summary: "Compare chain tips across --node endpoints" },
+    CommandHelp { name: "softforks", usage: "softforks", summary: "Show softfork deployment status and signalling" },
+    CommandHelp { name: "verifyblock", usage: "verifyblock <height>", summary: "Check every --node endpoint has the same block at a height" },
+    CommandHelp { name: "proptime", usage: "proptime [timeout_secs]", summary: "Mine a block and time its propagation to the first --node" },
+    CommandHelp { name: "bench", usage: "bench [count] | bench batch [rounds]", summary: "Time back-to-back getblockcount calls (default 100), or the status reads separately vs batched" },
+    CommandHelp { name: "setnetwork", usage: "setnetwork <on|off>", summary: "Enable or disable the node's P2P networking" },
+    CommandHelp { name: "peerheights", usage: "peerheights", summary: "Compare each peer's synced blocks and headers with our height" },
+    CommandHelp { name: "disconnect", usage: "disconnect <host:port|peer_id>", summary: "Disconnect a specific peer" },
+    CommandHelp { name: "help", usage: "help [command]", summary: "List commands or show one command's usage" },
+    CommandHelp { name: "quit", usage: "quit", summary: "End the session (also: exit)" },
+];
+
+fn find_command(name: &str) -> Option<&'static CommandHelp> {
+    let name = if name == "exit" { "quit" } else { name };
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+pub fn usage(name: &str) -> &'static str {
+    find_command(name).map_or("", |command| command.usage)
+}
+
+// Positional <args> before the first optional one, read off the usage line (first form only)
+fn required_arg_count(usage: &str) -> usize {
+    let first_form = usage.split(" | ").next().unwrap_or_default();
+    first_form.split(' ').skip(1).take_while(|token| token.starts_with('<')).count()
+}
+
+// Everything handle_input_line rejects before running, without running it: for checking a script up front
+pub fn check_command_line(line: &str) -> Result<(), String> {
+    let mut tokens = line.split(' ').filter(|token| !token.is_empty());
+    let Some(command) = tokens.next() else { return Ok(()) };
+    let Some(help) = find_command(command) else {
+        return Err(format!("unknown command '{}'", command));
+    };
+    if tokens.count() < required_arg_count(help.usage) {
+        return Err(format!("missing arguments, usage: {}", help.usage));
+    }
+    Ok(())
+}
+
+pub enum ArgError {
+    // Too few arguments, the usage line says it all
+    Missing,
+    Invalid(String),
+}
+
+impl From<MinerError> for ArgError {
+    fn from(e: MinerError) -> Self {
+        ArgError::Invalid(e.to_string())
+    }
+}
+
+impl From<String> for ArgError {
+    fn from(message: String) -> Self {
+        ArgError::Invalid(message)
+    }
+}
+
+pub fn require_args(args: &[&str], required: usize) -> Result<(), ArgError> {
+    if args.len() < required {
+        return Err(ArgError::Missing);
+    }
+    Ok(())
+}
+
+// Upper bound for counts, heights and timeouts typed at the prompt. Also keeps every value within u32
+const MAX_COUNT: u64 = 10_000_000;
+
+pub fn parse_count(input: &str, label: &str) -> Result<u64, MinerError> {
+    let invalid = || MinerError::InvalidCount { label: label.to_string(), input: input.to_string() };
+    let count = u64::from_str(input).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow => MinerError::CountTooLarge { label: label.to_string(), max: MAX_COUNT },
+        _ => invalid(),
+    })?;
+
+    if count > MAX_COUNT {
+        return Err(MinerError::CountTooLarge { label: label.to_string(), max: MAX_COUNT });
+    }
+    Ok(count)
+}
+
+fn parse_arg<T: FromStr>(arg: &str, what: &str) -> Result<T, ArgError>
+where
+    T::Err: std::fmt::Debug,
+{
+    T::from_str(arg).map_err(|e| ArgError::Invalid(format!("Error parsing {} {:?}", what, e)))
+}
+
+fn show_help(session: &Session, command: Option<&str>) {
+    match command {
+        Some(name) => match find_command(name) {
+            Some(command) => {
+                out!(session, "usage: {}", command.usage);
+                out!(session, "{}", command.summary);
+            }
+            None => err!(session, "Unknown command {}", name),
+        },
+        None => {
+            for command in COMMANDS {
+                out!(session, "{:<22} {}", command.name, command.summary);
+            }
+        }
+    }
+}
+
+fn run_pending_action(session: &mut Session, action: PendingAction, line: &str) {
+    if let PendingAction::SendWizard(wizard) = action {
+        advance_send_wizard(session, wizard, line);
+        return;
+    }
+    if !matches!(line.trim(), "yes" | "y") {
+        out!(session, "Cancelled");
+        return;
+    }
+
+    match action {
+        PendingAction::AbandonAll(txids) => abandon_transactions(session, txids),
+        PendingAction::Flood { count, amount } => flood_mempool(session, count, amount),
+        PendingAction::LoadSnapshot(name) => load_snapshot(session, &name),
+        PendingAction::SendWizard(_) => {}
+    }
+}
+
+// Commands that never talk to the node, so they don't trigger the deferred startup checks
+const LOCAL_COMMANDS: &[&str] = &["help", "quit", "exit", "alias", "aliases", "watches", "unwatch"];
+
+// Same info as the startup checks, but reported instead of fatal since the node may have no wallet
+fn run_deferred_startup_checks(session: &mut Session) {
+    session.startup_checks_pending = false;
+    match session.rpc_client.get_block_count() {
+        Ok(block_count) => out!(session, "Current block count: {}", block_count),
+        Err(e) => err!(session, "Failed to get block count. Error {:?}", e)
+    }
+    match session.rpc_client.get_balance(None, None) {
+        Ok(balance) => out!(session, "Current balance: {}", balance),
+        Err(e) => err!(session, "Balance unavailable. Error {}", e)
+    }
+    warn_if_pruned(session);
+}
+
+// For convenience. All these can be done from the CLI.
+// Returns false when the line was rejected before running (unknown command or bad arguments)
+pub fn handle_input_line(session: &mut Session, line: String) -> bool {
+    if let Some(log) = &session.activity_log {
+        log.record_command(&line);
+    }
+    session.emit(&Event::Command { line: &line });
+
+    if let Some(action) = session.pending_action.take() {
+        run_pending_action(session, action, &line);
+        return true;
+    }
+
+    // Enter on its own is the quickest way to stop a running mempoolwatch
+    if line.trim().is_empty() && session.mempool_watch.is_some() {
+        stop_mempool_watch(session);
+        return true;
+    }
+
+    let mut tokens = line.split(' ').filter(|token| !token.is_empty());
+    let Some(command) = tokens.next() else { return true };
+    let args: Vec<&str> = tokens.collect();
+
+    if find_command(command).is_none() {
+        err!(session, "Invalid command, type help for a list of commands");
+        return false;
+    }
+
+    if session.startup_checks_pending && !LOCAL_COMMANDS.contains(&command) {
+        run_deferred_startup_checks(session);
+    }
+
+    match run_command(session, command, &args) {
+        Ok(()) => return true,
+        Err(ArgError::Missing) => err!(session, "usage: {}", usage(command)),
+        Err(ArgError::Invalid(message)) => {
+            err!(session, "{}", message);
+            err!(session, "usage: {}", usage(command));
+        }
+    }
+    false
+}
+
+fn run_command(session: &mut Session, command: &str, args: &[&str]) -> Result<(), ArgError> {
+    match command {
+        "sendtoaddress" => {
+            require_args(args, 2)?;
+            let amount = parse_amount(args[1]).map_err(|e| format!("Error parsing amount: {}", e))?;
+            let options = parse_send_options(args[2..].iter().copied())?;
+            send_to_address(session, args[0], amount, &options);
+        }
+        "send" => {
+            if !args.is_empty() {
+                return Err("send takes no arguments, it prompts for each one (or use sendtoaddress)".to_string().into());
+            }
+            start_send_wizard(session);
+        }
+        "sendmany" => {
+            let (option_tokens, output_tokens): (Vec<&str>, Vec<&str>) = args.iter().partition(|token| is_send_option(token));
+            let outputs = parse_send_outputs(output_tokens.into_iter())?;
+            if outputs.is_empty() {
+                return Err(ArgError::Missing);
+            }
+            let options = parse_send_options(option_tokens.into_iter())?;
+            require_fee_rate_only(command, &options)?;
+            send_many(session, &outputs, &options);
+        }
+        "sweep" => {
+            require_args(args, 1)?;
+            let options = parse_send_options(args[1..].iter().copied())?;
+            require_fee_rate_only(command, &options)?;
+            sweep(session, args[0], &options);
+        }
+        "receive" => {
+            show_receive_address(session);
+        }
+        "receivedbyaddress" => {
+            require_args(args, 1)?;
+            let minconf = match args.get(1) {
+                Some(minconf) => Some(parse_count(minconf, "minconf")? as u32),
+                None => None,
+            };
+            show_received_by_address(session, args[0], minconf);
+        }
+        "receivedbylabel" => {
+            require_args(args, 1)?;
+            let minconf = match args.get(1) {
+                Some(minconf) => Some(parse_count(minconf, "minconf")? as u32),
+                None => None,
+            };
+            show_received_by_label(session, args[0], minconf);
+        }
+        "labels" => {
+            show_labels(session);
+        }
+        "labeltx" => {
+            require_args(args, 2)?;
+            let txid: Txid = parse_arg(args[0], "txid")?;
+            label_transaction(session, &txid, &args[1..].join(" "));
+        }
+        "conflicts" => {
+            show_conflicts(session);
+        }
+        "lockutxo" | "unlockutxo" => {
+            require_args(args, 1)?;
+            let outpoint: OutPoint = parse_arg(args[0], "outpoint")?;
+            set_utxo_locked(session, outpoint, command == "lockutxo");
+        }
+        "listunspent" => {
+            let filters = parse_unspent_filters(session, args)?;
+            list_unspent(session, &filters);
+        }
+        "lockedutxos" => {
+            list_locked_utxos(session);
+        }
+        "reorgcheck" => {
+            check_reorg_consistency(session);
+        }
+        "abandonall" => {
+            let skip_confirmation = match args.first() {
+                Some(&"--yes") => true,
+                Some(other) => return Err(format!("Unknown option {}", other).into()),
+                None => false,
+            };
+            request_abandon_all(session, skip_confirmation);
+        }
+        "getdescriptoraddress" => {
+            let count = match args.first() {
+                Some(count) => match parse_count(count, "address count")? {
+                    0 => return Err("address count must be at least 1".to_string().into()),
+                    count => count as u32,
+                },
+                None => DEFAULT_DESCRIPTOR_ADDRESS_COUNT,
+            };
+            show_descriptor_addresses(session, count);
+        }
+        "signmessage" => {
+            require_args(args, 2)?;
+            let source = parse_message_source(&args[1..]).ok_or(ArgError::Missing)?;
+            sign_message(session, args[0], source);
+        }
+        "verifymessage" => {
+            require_args(args, 3)?;
+            let source = parse_message_source(&args[2..]).ok_or(ArgError::Missing)?;
+            verify_message(session, args[0], args[1], source);
+        }
+        "watch" => {
+            require_args(args, 1)?;
+            watch_address(session, args[0]);
+        }
+        "unwatch" => {
+            require_args(args, 1)?;
+            unwatch_address(session, args[0]);
+        }
+        "watches" => {
+            list_watches(session);
+        }
+        "alias" => {
+            require_args(args, 2)?;
+            session.add_alias(args[0], args[1]);
+        }
+        "aliases" => {
+            session.list_aliases();
+        }
+        "generate" => {
+            require_args(args, 1)?;
+            let count = parse_count(args[0], "block count")?;
+            generate_blocks(session, count);
+        }
+        "automine" => {
+            let change = match (args.first(), args.get(1)) {
+                (None, _) => {
+                    show_auto_mine(session);
+                    return Ok(());
+                }
+                (Some(&"on"), _) => AutoMineChange::On,
+                (Some(&"off"), _) => AutoMineChange::Off,
+                (Some(&"interval"), Some(secs)) => match parse_count(secs, "interval")? {
+                    0 => return Err("Interval must be at least 1 second".to_string().into()),
+                    secs => AutoMineChange::Interval(secs),
+                },
+                (Some(&"interval"), None) => return Err(ArgError::Missing),
+                (Some(other), _) => return Err(format!("Expected on, off or interval, got '{}'", other).into()),
+            };
+            change_auto_mine(session, change);
+        }
+        "snapshot" => match (args.first(), args.get(1)) {
+            (Some(&"list"), _) => list_snapshots(session),
+            (Some(&"save"), Some(name)) => save_snapshot(session, name, args.get(2).copied()),
+            (Some(&"load"), Some(name)) => request_snapshot_load(session, name),
+            (Some(&"save" | &"load"), None) | (None, _) => return Err(ArgError::Missing),
+            (Some(other), _) => return Err(format!("Expected save, load or list, got '{}'", other).into()),
+        },
+        "simulate" => {
+            require_args(args, 1)?;
+            let duration_secs = parse_count(args[0], "duration")?;
+            let interval_secs = match args.get(1) {
+                Some(arg) => parse_count(arg, "block interval")?,
+                None => DEFAULT_SIMULATE_INTERVAL_SECS,
+            };
+            if interval_secs == 0 {
+                return Err("Block interval must be at least 1 second".to_string().into());
+            }
+            simulate_blocks(session, duration_secs, interval_secs);
+        }
+        "minetoheight" => {
+            require_args(args, 1)?;
+            let height = parse_count(args[0], "height")?;
+            mine_to_height(session, height);
+        }
+        "resetchain" => {
+            require_args(args, 1)?;
+            let length = parse_count(args[0], "chain length")?;
+            reset_chain(session, length, args.get(1).copied());
+        }
+        "generatetagged" => {
+            require_args(args, 2)?;
+            let count = parse_count(args[0], "block count")?;
+            generate_tagged_blocks(session, count, &args[1..].join(" "));
+        }
+        "eta" => {
+            require_args(args, 1)?;
+            let txid: Txid = parse_arg(args[0], "txid")?;
+            show_confirmation_eta(session, &txid);
+        }
+        "mempoolwatch" => match args.first() {
+            None => start_mempool_watch(session),
+            Some(&"stop") => stop_mempool_watch(session),
+            Some(arg) => return Err(format!("Unknown argument {}", arg).into()),
+        },
+        "waitmempool" => {
+            require_args(args, 1)?;
+            let txid: Txid = parse_arg(args[0], "txid")?;
+            let timeout_secs = match args.get(1) {
+                Some(timeout) => parse_count(timeout, "timeout")?,
+                None => DEFAULT_MEMPOOL_WAIT_SECS,
+            };
+            wait_for_mempool(session, txid, timeout_secs);
+        }
+        "mine" => {
+            generate_blocks_if_required(session, true);
+        }
+        "quit" | "exit" => {
+            session.quit_requested = true;
+        }
+        "balance" => {
+            check_balance(session);
+        }
+        "txoutsetinfo" => {
+            show_tx_out_set_info(session);
+        }
+        "compare" => {
+            compare_nodes(session);
+        }
+        "softforks" => {
+            show_softforks(session);
+        }
+        "verifyblock" => {
+            require_args(args, 1)?;
+            verify_block(session, parse_count(args[0], "height")?);
+        }
+        "audit" => {
+            audit_balance(session);
+        }
+        "totalbalance" => {
+            show_total_balance(session);
+        }
+        "blockcount" => {
+            check_block_count(session);
+        }
+        "uptime" => {
+            show_uptime(session);
+        }
+        "status" => {
+            show_status(session);
+        }
+        "syncstatus" => {
+            show_sync_status(session);
+        }
+        "waitsync" => {
+            let timeout_secs = match args.first() {
+                Some(timeout) => parse_count(timeout, "timeout")?,
+                None => DEFAULT_SYNC_WAIT_SECS,
+            };
+            wait_for_sync(session, timeout_secs);
+        }
+        "getblock" => {
+            require_args(args, 1)?;
+            show_block(session, args[0]);
+        }
+        "blockstats" => {
+            require_args(args, 1)?;
+            show_block_stats(session, args[0], &args[1..]);
+        }
+        "coinbases" => {
+            let count = match args.first() {
+                Some(count) => parse_count(count, "count")?,
+                None => DEFAULT_COINBASE_COUNT,
+            };
+            show_coinbases(session, count);
+        }
+        "feehistory" => {
+            let count = match args.first() {
+                Some(count) => parse_count(count, "count")?,
+                None => DEFAULT_FEE_HISTORY_BLOCKS,
+            };
+            if count == 0 || count > MAX_FEE_HISTORY_BLOCKS {
+                return Err(format!("Count must be between 1 and {}, got {}", MAX_FEE_HISTORY_BLOCKS, count).into());
+            }
+            show_fee_history(session, count);
+        }
+        "dumpheaders" => {
+            require_args(args, 2)?;
+            let from_height = parse_count(args[0], "from height")?;
+            let to_height = parse_count(args[1], "to height")?;
+            if from_height > to_height {
+                return Err(format!("From height {} is above to height {}", from_height, to_height).into());
+            }
+            dump_headers(session, from_height, to_height, args.get(2).copied());
+        }
+        "sendspec" => {
+            require_args(args, 1)?;
+            send_spec(session, args[0]);
+        }
+        "flood" => {
+            require_args(args, 2)?;
+            let count = parse_count(args[0], "transaction count")?;
+            let amount = parse_amount(args[1]).map_err(|e| format!("Error parsing amount: {}", e))?;
+            let skip_confirmation = match args.get(2) {
+                Some(&"--yes") => true,
+                Some(other) => return Err(format!("Unknown option {}", other).into()),
+                None => false,
+            };
+            request_flood(session, count, amount, skip_confirmation);
+        }
+        "doublespend" => {
+            require_args(args, 4)?;
+            let outpoint: OutPoint = parse_arg(args[0], "outpoint")?;
+            let amount = parse_amount(args[3]).map_err(|e| format!("Error parsing amount: {}", e))?;
+            let broadcast = match args.get(4) {
+                None => DoubleSpendBroadcast::None,
+                Some(&"--broadcast") => DoubleSpendBroadcast::SameNode,
+                Some(&"--split") => DoubleSpendBroadcast::SplitNodes,
+                Some(other) => return Err(format!("Unknown option {}", other).into()),
+            };
+            double_spend(session, outpoint, args[1], args[2], amount, broadcast);
+        }
+        "sendtoscript" => {
+            require_args(args, 2)?;
+            let amount = parse_amount(args[1]).map_err(|e| format!("Error parsing amount: {}", e))?;
+            send_to_script(session, args[0], amount);
+        }
+        "sendlocked" => {
+            require_args(args, 3)?;
+            let amount = parse_amount(args[1]).map_err(|e| format!("Error parsing amount: {}", e))?;
+            let lock_time = match parse_arg::<u32>(args[2], "locktime")? {
+                0 => return Err("A locktime of 0 has no effect, use sendtoaddress".to_string().into()),
+                lock_time => LockTime::from_consensus(lock_time),
+            };
+            send_locked(session, args[0], amount, lock_time);
+        }
+        "mempoollimits" => {
+            show_mempool_limits(session);
+        }
+        "replaytx" => {
+            require_args(args, 2)?;
+            let txid: Txid = parse_arg(args[1], "txid")?;
+            match args[0] {
+                "capture" => capture_transaction(session, txid),
+                "replay" => replay_transaction(session, &txid),
+                other => return Err(format!("Expected capture or replay, got '{}'", other).into()),
+            }
+        }
+        "broadcastall" => {
+            require_args(args, 1)?;
+            broadcast_to_all(session, args[0]);
+        }
+        "sendrawtx" => {
+            require_args(args, 1)?;
+            let allow_high_fee = match args.get(1) {
+                Some(&"--allow-high-fee") => true,
+                Some(other) => return Err(format!("Unknown option {}", other).into()),
+                None => false,
+            };
+            send_raw_transaction(session, args[0], allow_high_fee);
+        }
+        "getrawtx" => {
+            require_args(args, 1)?;
+            let txid: Txid = parse_arg(args[0], "txid")?;
+            let verbose = match args.get(1) {
+                None => false,
+                Some(&"--verbose") => true,
+                Some(flag) => return Err(format!("Unknown option {}", flag).into()),
+            };
+            show_raw_transaction(session, &txid, verbose);
+        }
+        "testmempoolaccept" => {
+            require_args(args, 1)?;
+            test_mempool_accept(session, args);
+        }
+        "sendpackage" => {
+            require_args(args, 1)?;
+            send_package(session, args);
+        }
+        "replace" => {
+            require_args(args, 2)?;
+            let old_txid: Txid = parse_arg(args[0], "txid")?;
+            let fee_rate = parse_fee_rate(args[1])?;
+            replace_transaction(session, old_txid, fee_rate);
+        }
+        "bumpfee" => {
+            require_args(args, 1)?;
+            let txid: Txid = parse_arg(args[0], "txid")?;
+            let total_fee = match args.get(1).map(|option| option.split_once('=')) {
+                Some(Some(("totalfee", sats))) => Some(Amount::from_sat(parse_arg(sats, "total fee")?)),
+                Some(_) => return Err(format!("Unknown option {}", args[1]).into()),
+                None => None,
+            };
+            bump_fee(session, txid, total_fee);
+        }
+        "note" => {
+            require_args(args, 2)?;
+            let height = parse_count(args[0], "height")?;
+            add_note(session, height, &args[1..].join(" "));
+        }
+        "notes" => {
+            list_notes(session);
+        }
+        "blocktemplate" => {
+            let mut rules = Vec::new();
+            for rule in args {
+                match parse_template_rule(rule) {
+                    Some(rule) => rules.push(rule),
+                    None => return Err(format!("Unknown rule {}", rule).into()),
+                }
+            }
+            if rules.is_empty() {
+                rules.push(GetBlockTemplateRules::SegWit);
+            }
+
+            show_block_template(session, &rules);
+        }
+        "walletinfo" => {
+            show_wallet_info(session);
+        }
+        "waitstable" => {
+            if args.first() == Some(&"stop") {
+                stop_stable_wait(session);
+                return Ok(());
+            }
+            let quiet_secs = match args.first() {
+                Some(quiet) => parse_count(quiet, "quiet period")?,
+                None => DEFAULT_STABLE_QUIET_SECS,
+            };
+            let timeout_secs = match args.get(1) {
+                Some(timeout) => parse_count(timeout, "timeout")?,
+                None => DEFAULT_STABLE_TIMEOUT_SECS,
+            };
+            if quiet_secs == 0 || quiet_secs > timeout_secs {
+                return Err(format!("Quiet period must be between 1 and the timeout of {}s, got {}", timeout_secs, quiet_secs).into());
+            }
+            start_stable_wait(session, quiet_secs, timeout_secs);
+        }
+        "summary" => {
+            show_wallet_summary(session);
+        }
+        "rescanstatus" => {
+            show_rescan_status(session);
+        }
+        "abortrescan" => {
+            abort_rescan(session);
+        }
+        "watchaddress" => {
+            require_args(args, 1)?;
+            let label = (args.len() > 1).then(|| args[1..].join(" "));
+            if let Some(address) = import_watch_only(session, args[0], label.as_deref()) {
+                // Same notifications as the watch command, on top of the wallet tracking the balance
+                if !session.watches.contains_key(&address.to_string()) {
+                    watch_address(session, &address.to_string());
+                }
+            }
+        }
+        "importmultisig" => {
+            require_args(args, 3)?;
+            let required = parse_count(args[0], "n")? as usize;
+            let total = parse_count(args[1], "m")? as usize;
+            if required == 0 || required > total {
+                return Err(format!("n must be between 1 and m, got {} of {}", required, total).into());
+            }
+            if total > MAX_MULTISIG_KEYS {
+                return Err(format!("m can be at most {}, got {}", MAX_MULTISIG_KEYS, total).into());
+            }
+            let pubkeys = args[2..].iter()
+                .map(|key| parse_arg::<PublicKey>(key, "public key"))
+                .collect::<Result<Vec<_>, _>>()?;
+            if pubkeys.len() != total {
+                return Err(format!("Expected {} public keys, got {}", total, pubkeys.len()).into());
+            }
+            import_multisig(session, required, &pubkeys);
+        }
+        "spawnwallet" => {
+            require_args(args, 2)?;
+            let amount = parse_amount(args[1]).map_err(|e| format!("Error parsing amount: {}", e))?;
+            spawn_wallet(session, args[0], amount);
+        }
+        "keypoolrefill" => {
+            let size = match args.first() {
+                Some(size) => parse_count(size, "keypool size")?,
+                None => DEFAULT_KEYPOOL_SIZE,
+            };
+            refill_keypool(session, size);
+        }
+        "dumpwallet" => {
+            require_args(args, 1)?;
+            dump_wallet(session, args[0]);
+        }
+        "estimatefee" => {
+            let conf_target = match args.first() {
+                Some(target) => parse_arg::<u16>(target, "confirmation target")?,
+                None => DEFAULT_ESTIMATE_TARGET,
+            };
+            if !(1..=1008).contains(&conf_target) {
+                return Err(format!("Confirmation target must be between 1 and 1008, got {}", conf_target).into());
+            }
+            show_fee_estimate(session, conf_target);
+        }
+        "settxfee" => {
+            require_args(args, 1)?;
+            // parse_amount rather than f64, so a typo past 21M BTC is an error instead of a float rounding surprise
+            let fee_rate = parse_amount(args[0]).map_err(|e| format!("Error parsing fee rate: {}", e))?;
+            set_tx_fee(session, fee_rate);
+        }
+        "proptime" => {
+            let timeout_secs = match args.first() {
+                Some(timeout) => parse_count(timeout, "timeout")?,
+                None => DEFAULT_PROPAGATION_TIMEOUT_SECS,
+            };
+            measure_propagation(session, timeout_secs);
+        }
+        "bench" => {
+            let batch = args.first() == Some(&"batch");
+            let count = match args.get(usize::from(batch)) {
+                Some(count) => parse_count(count, "count")?,
+                None => DEFAULT_BENCH_COUNT,
+            };
+            if count == 0 || count > MAX_BENCH_COUNT {
+                return Err(format!("Count must be between 1 and {}, got {}", MAX_BENCH_COUNT, count).into());
+            }
+            if batch {
+                bench_batch(session, count);
+            } else {
+                bench_rpc(session, count);
+            }
+        }
+        "setnetwork" => {
+            require_args(args, 1)?;
+            match args[0] {
+                "on" => set_network_active(session, true),
+                "off" => set_network_active(session, false),
+                other => return Err(format!("Expected on or off, got '{}'", other).into()),
+            }
+        }
+        "peerheights" => {
+            show_peer_heights(session);
+        }
+        "disconnect" => {
+            require_args(args, 1)?;
+            disconnect_peer(session, parse_peer_ref(args[0])?);
+        }
+        "help" => {
+            show_help(session, args.first().copied());
+        }
+        _ => err!(session, "Command {} is not implemented", command),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_session, ScriptedNode};
+
+    #[test]
+    fn parse_count_accepts_zero_up_to_the_cap() {
+        assert_eq!(parse_count("0", "count").unwrap(), 0);
+        assert_eq!(parse_count("101", "count").unwrap(), 101);
+        assert_eq!(parse_count("10000000", "count").unwrap(), MAX_COUNT);
+    }
+
+    #[test]
+    fn parse_count_rejects_values_above_the_cap() {
+        assert!(matches!(parse_count("10000001", "count"), Err(MinerError::CountTooLarge { max: MAX_COUNT, .. })));
+        assert!(matches!(parse_count("99999999999999999999999", "count"), Err(MinerError::CountTooLarge { .. })));
+    }
+
+    #[test]
+    fn parse_count_rejects_negative_empty_and_non_numeric_input() {
+        for input in ["-1", "", "foo", "1.5", "10k"] {
+            match parse_count(input, "block count") {
+                Err(e @ MinerError::InvalidCount { .. }) => {
+                    assert_eq!(e.to_string(), format!("expected a non-negative integer for block count, got '{}'", input));
+                }
+                other => panic!("{:?} parsed as {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn over_cap_amounts_are_rejected_before_any_rpc_call() {
+        let node = ScriptedNode::default();
+        let (mut session, out) = test_session(&node);
+
+        assert!(!handle_input_line(&mut session, "settxfee 9999999999".to_string()));
+        assert!(!handle_input_line(&mut session, "sendtoaddress bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080 9999999999".to_string()));
+
+        assert!(out.error_text().contains("Error parsing fee rate: amount 9999999999 exceeds the maximum of 21000000 BTC"), "{}", out.error_text());
+        assert!(out.error_text().contains("Error parsing amount: amount 9999999999 exceeds the maximum of 21000000 BTC"), "{}", out.error_text());
+        assert!(node.calls().is_empty());
+    }
+
+    #[test]
+    fn getdescriptoraddress_rejects_a_zero_count() {
+        let node = ScriptedNode::default();
+        let (mut session, out) = test_session(&node);
+
+        assert!(!handle_input_line(&mut session, "getdescriptoraddress 0".to_string()));
+
+        assert!(out.error_text().contains("address count must be at least 1"), "{}", out.error_text());
+        assert!(node.calls().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use bitcoin::Network;
+use bitcoincore_rpc::Auth;
+use serde::Deserialize;
+
+use crate::error::MinerError;
+
+const DEFAULT_RPC_URL: &str = "http://127.0.0.1:18443";
+const DEFAULT_RPC_USER: &str = "user";
+const DEFAULT_RPC_PASSWORD: &str = "password";
+
+// Connection settings as they appear in the config file. Every field is optional so that
+// top-level values, a [profiles.<name>] table and CLI flags can each fill in only what they change
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub network: Option<Network>,
+    pub wallet: Option<String>,
+}
+
+impl Profile {
+    // Fields set in `other` win
+    pub fn overridden_by(self, other: Profile) -> Profile {
+        Profile {
+            url: other.url.or(self.url),
+            user: other.user.or(self.user),
+            password: other.password.or(self.password),
+            network: other.network.or(self.network),
+            wallet: other.wallet.or(self.wallet),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub defaults: Profile,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+// Where a resolved connection setting came from, lowest precedence first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingSource {
+    Default,
+    ConfigFile,
+    Profile(String),
+    CommandLine,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingSource::Default => write!(f, "default"),
+            SettingSource::ConfigFile => write!(f, "config file"),
+            SettingSource::Profile(name) => write!(f, "profile {}", name),
+            SettingSource::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SettingSources {
+    pub url: SettingSource,
+    pub user: SettingSource,
+    pub password: SettingSource,
+    pub network: SettingSource,
+    pub wallet: SettingSource,
+}
+
+pub struct ConnectionSettings {
+    pub url: String,
+    pub auth: Auth,
+    // None means detect it from the node
+    pub network: Option<Network>,
+    pub wallet_name: String,
+    pub sources: SettingSources,
+}
+
+pub fn load_config(path: &Path) -> Result<ConfigFile, MinerError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| MinerError::Config(format!("failed to read {}: {}", path.display(), e)))?;
+    toml::from_str(&contents)
+        .map_err(|e| MinerError::Config(format!("failed to parse {}: {}", path.display(), e)))
+}
+
+// Top-level config values, followed by the named profile (if any) that overrides them
+pub fn config_layers(mut config: ConfigFile, name: Option<&str>) -> Result<Vec<(SettingSource, Profile)>, MinerError> {
+    let mut layers = vec![(SettingSource::ConfigFile, config.defaults)];
+    let Some(name) = name else { return Ok(layers) };
+
+    match config.profiles.remove(name) {
+        Some(profile) => {
+            layers.push((SettingSource::Profile(name.to_string()), profile));
+            Ok(layers)
+        }
+        None => Err(MinerError::UnknownProfile {
+            name: name.to_string(),
+            available: config.profiles.keys().cloned().collect(),
+        }),
+    }
+}
+
+// The last layer that sets a field wins, and is recorded as that field's source
+fn source_of(layers: &[(SettingSource, Profile)], is_set: impl Fn(&Profile) -> bool) -> SettingSource {
+    layers.iter().rev()
+        .find(|(_, profile)| is_set(profile))
+        .map_or(SettingSource::Default, |(source, _)| source.clone())
+}
+
+// Merges the layers in order, so later ones override earlier ones
+pub fn resolve_connection(layers: Vec<(SettingSource, Profile)>) -> Result<ConnectionSettings, MinerError> {
+    let sources = SettingSources {
+        url: source_of(&layers, |profile| profile.url.is_some()),
+        user: source_of(&layers, |profile| profile.user.is_some()),
+        password: source_of(&layers, |profile| profile.password.is_some()),
+        network: source_of(&layers, |profile| profile.network.is_some()),
+        wallet: source_of(&layers, |profile| profile.wallet.is_some()),
+    };
+    let profile = layers.into_iter().fold(Profile::default(), |merged, (_, layer)| merged.overridden_by(layer));
+
+    let wallet_name = profile.wallet
+        .ok_or_else(|| MinerError::Config("no wallet given, pass --wallet-name or set wallet in the config".to_string()))?;
+
+    Ok(ConnectionSettings {
+        url: profile.url.unwrap_or_else(|| DEFAULT_RPC_URL.to_string()),
+        auth: Auth::UserPass(
+            profile.user.unwrap_or_else(|| DEFAULT_RPC_USER.to_string()),
+            profile.password.unwrap_or_else(|| DEFAULT_RPC_PASSWORD.to_string()),
+        ),
+        network: profile.network,
+        wallet_name,
+        sources,
+    })
+}
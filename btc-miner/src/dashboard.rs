@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use bitcoin::BlockHash;
+use bitcoincore_rpc::json::{GetBalancesResult, GetBalancesResultEntry, GetBlockHeaderResult, GetMempoolInfoResult};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::Frame;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use crate::mining::{next_mine_deadline, on_mine_timer};
+use crate::output::Output;
+use crate::session::Session;
+
+const RECENT_BLOCK_COUNT: u64 = 8;
+const LOG_LINES: usize = 6;
+
+// Collects messages while the dashboard owns the terminal, shown in the log panel
+#[derive(Default)]
+struct LogOutput {
+    lines: RefCell<VecDeque<String>>,
+}
+
+impl LogOutput {
+    fn push(&self, message: &str) {
+        let mut lines = self.lines.borrow_mut();
+        lines.push_back(message.to_string());
+        while lines.len() > LOG_LINES {
+            lines.pop_front();
+        }
+    }
+}
+
+impl Output for LogOutput {
+    fn human(&self, message: &str) {
+        self.push(message);
+    }
+
+    fn json(&self, _value: &serde_json::Value) {}
+
+    fn error(&self, message: &str) {
+        self.push(message);
+    }
+
+    fn progress(&self, _label: &str, _done: u64, _total: u64) {}
+}
+
+struct RecentBlock {
+    height: u64,
+    hash: BlockHash,
+    n_tx: usize,
+    time: u64,
+}
+
+// Everything shown on screen, fetched in one go on each refresh
+#[derive(Default)]
+struct Snapshot {
+    height: Option<u64>,
+    balances: Option<GetBalancesResultEntry>,
+    mempool_size: Option<usize>,
+    peers: Option<usize>,
+    recent_blocks: Vec<RecentBlock>,
+    // Time the last refresh spent on RPC
+    fetch_ms: u128,
+}
+
+// Decodes one result of a batch into the type the typed client would have returned
+fn decode<T: DeserializeOwned>(result: &Result<serde_json::Value, bitcoincore_rpc::Error>) -> Option<T> {
+    result.as_ref().ok().and_then(|value| serde_json::from_value(value.clone()).ok())
+}
+
+impl Snapshot {
+    // Three batched round trips: the summary reads, then the recent block hashes, then their headers
+    fn fetch(session: &Session) -> Snapshot {
+        let started = Instant::now();
+        let rpc_client = &session.rpc_client;
+        let summary = rpc_client.batch(&[
+            ("getblockcount", vec![]),
+            ("getbalances", vec![]),
+            ("getmempoolinfo", vec![]),
+            ("getconnectioncount", vec![]),
+        ]);
+        let height: Option<u64> = decode(&summary[0]);
+
+        let mut recent_blocks = Vec::new();
+        if let Some(height) = height {
+            let heights: Vec<u64> = (height.saturating_sub(RECENT_BLOCK_COUNT - 1)..=height).rev().collect();
+            let hash_calls: Vec<_> = heights.iter().map(|height| ("getblockhash", vec![json!(height)])).collect();
+            let hashes: Vec<Option<BlockHash>> = rpc_client.batch(&hash_calls).iter().map(decode).collect();
+
+            let header_calls: Vec<_> = hashes.iter().flatten().map(|hash| ("getblockheader", vec![json!(hash)])).collect();
+            let mut headers = rpc_client.batch(&header_calls).into_iter();
+            for (block_height, hash) in heights.iter().zip(&hashes) {
+                if hash.is_none() {
+                    continue;
+                }
+                let header: Option<GetBlockHeaderResult> = headers.next().as_ref().and_then(decode);
+                if let Some(header) = header {
+                    recent_blocks.push(RecentBlock {
+                        height: *block_height,
+                        hash: header.hash,
+                        n_tx: header.n_tx,
+                        time: header.time as u64,
+                    });
+                }
+            }
+        }
+
+        Snapshot {
+            height,
+            balances: decode::<GetBalancesResult>(&summary[1]).map(|balances| balances.mine),
+            mempool_size: decode::<GetMempoolInfoResult>(&summary[2]).map(|info| info.size),
+            peers: decode(&summary[3]),
+            recent_blocks,
+            fetch_ms: started.elapsed().as_millis(),
+        }
+    }
+}
+
+fn or_unavailable<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map_or("unavailable".to_string(), |v| v.to_string())
+}
+
+fn render(frame: &mut Frame, session: &Session, snapshot: &Snapshot, log: &LogOutput) {
+    let [top, blocks_area, log_area] = Layout::vertical([
+        Constraint::Length(8),
+        Constraint::Min(RECENT_BLOCK_COUNT as u16 + 2),
+        Constraint::Length(LOG_LINES as u16 + 2),
+    ]).areas(frame.area());
+    let [chain_area, wallet_area] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+
+    let chain = vec![
+        Line::from(format!("Height:        {}", or_unavailable(&snapshot.height))),
+        Line::from(format!("Mempool txs:   {}", or_unavailable(&snapshot.mempool_size))),
+        Line::from(format!("Peers:         {}", or_unavailable(&snapshot.peers))),
+        Line::from(format!("Auto-mine:     {}", if session.auto_mine { "on" } else { "off" })),
+        Line::from(format!("Mined here:    {} block(s)", session.blocks_mined.get())),
+        Line::from(format!("Refresh:       {} ms", snapshot.fetch_ms)),
+    ];
+    frame.render_widget(Paragraph::new(chain).block(Block::default().borders(Borders::ALL).title(" Chain ")), chain_area);
+
+    let wallet = match &snapshot.balances {
+        Some(balances) => vec![
+            Line::from(format!("Trusted:       {}", balances.trusted)),
+            Line::from(format!("Pending:       {}", balances.untrusted_pending)),
+            Line::from(format!("Immature:      {}", balances.immature)),
+        ],
+        None => vec![Line::from("Balances unavailable")],
+    };
+    frame.render_widget(Paragraph::new(wallet).block(Block::default().borders(Borders::ALL).title(" Wallet ")), wallet_area);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let blocks: Vec<Line> = snapshot.recent_blocks.iter()
+        .map(|block| Line::from(format!(
+            "{:>8}  {}  {:>5} tx  {:>6}s ago",
+            block.height, block.hash, block.n_tx, now.saturating_sub(block.time)
+        )))
+        .collect();
+    frame.render_widget(Paragraph::new(blocks).block(Block::default().borders(Borders::ALL).title(" Recent blocks ")), blocks_area);
+
+    let log_lines: Vec<Line> = log.lines.borrow().iter().map(|line| Line::from(line.clone())).collect();
+    frame.render_widget(
+        Paragraph::new(log_lines).block(Block::default().borders(Borders::ALL).title(" Log (q to quit) ")),
+        log_area,
+    );
+}
+
+fn is_quit_key(event: &Event) -> bool {
+    match event {
+        Event::Key(key) if key.kind == KeyEventKind::Press => {
+            key.code == KeyCode::Char('q')
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        }
+        _ => false,
+    }
+}
+
+// Replaces the command prompt with a live view, refreshed (and auto-mined) on the usual timer
+pub fn run_dashboard(session: &mut Session) -> std::io::Result<()> {
+    let log = Rc::new(LogOutput::default());
+    let previous_out = std::mem::replace(&mut session.out, log.clone());
+
+    let mut terminal = ratatui::init();
+    let mut snapshot = Snapshot::fetch(session);
+    let mut deadline = next_mine_deadline(session).into_std();
+
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| render(frame, session, &snapshot, &log)) {
+            break Err(e);
+        }
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match event::poll(timeout) {
+            Ok(true) => match event::read() {
+                Ok(event) if is_quit_key(&event) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            },
+            Ok(false) => {
+                on_mine_timer(session);
+                snapshot = Snapshot::fetch(session);
+                deadline = next_mine_deadline(session).into_std();
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    ratatui::restore();
+    session.out = previous_out;
+    result
+}
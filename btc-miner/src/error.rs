@@ -0,0 +1,63 @@
+use std::fmt::{Display, Formatter};
+
+use bitcoin::Amount;
+
+#[derive(Debug)]
+pub enum MinerError {
+    InvalidAmount(String),
+    InvalidCount { label: String, input: String },
+    CountTooLarge { label: String, max: u64 },
+    ZeroAmount,
+    NegativeAmount,
+    AmountOverflow(String),
+    TotalOverflow,
+    InsufficientBalance { total: Amount, balance: Amount },
+    DustAmount { amount: Amount, threshold: Amount },
+    InvalidTransaction(String),
+    SigningIncomplete,
+    Replacement(String),
+    BlockConstruction(String),
+    Rpc(bitcoincore_rpc::Error),
+    Config(String),
+    UnknownProfile { name: String, available: Vec<String> },
+}
+
+impl Display for MinerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MinerError::InvalidAmount(input) => write!(f, "invalid amount '{}'", input),
+            MinerError::InvalidCount { label, input } => write!(f, "expected a non-negative integer for {}, got '{}'", label, input),
+            MinerError::CountTooLarge { label, max } => write!(f, "{} must be at most {}", label, max),
+            MinerError::ZeroAmount => write!(f, "amount must be greater than zero"),
+            MinerError::NegativeAmount => write!(f, "amount cannot be negative"),
+            MinerError::AmountOverflow(input) => write!(f, "amount {} exceeds the maximum of {}", input, Amount::MAX_MONEY),
+            MinerError::TotalOverflow => write!(f, "total of the amounts exceeds the maximum of {}", Amount::MAX_MONEY),
+            MinerError::InsufficientBalance { total, balance } => write!(f, "total {} exceeds balance {}", total, balance),
+            MinerError::DustAmount { amount, threshold } => write!(
+                f,
+                "amount {} is below the dust threshold of {} for this output and would be rejected by the node",
+                amount, threshold
+            ),
+            MinerError::InvalidTransaction(e) => write!(f, "invalid transaction: {}", e),
+            MinerError::SigningIncomplete => write!(f, "wallet could not sign all inputs"),
+            MinerError::Replacement(e) => write!(f, "{}", e),
+            MinerError::BlockConstruction(e) => write!(f, "cannot build block: {}", e),
+            MinerError::Rpc(e) => write!(f, "RPC error: {}", e),
+            MinerError::Config(e) => write!(f, "config error: {}", e),
+            MinerError::UnknownProfile { name, available } if available.is_empty() => {
+                write!(f, "unknown profile '{}', the config file defines no profiles", name)
+            }
+            MinerError::UnknownProfile { name, available } => {
+                write!(f, "unknown profile '{}', expected one of: {}", name, available.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for MinerError {}
+
+impl From<bitcoincore_rpc::Error> for MinerError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        MinerError::Rpc(e)
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::Txid;
+use bitcoincore_rpc::RpcApi;
+use serde::Serialize;
+
+use crate::output::Output;
+use crate::session::Session;
+
+// --events: one of these per line on stdout, tagged by "type"
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Command { line: &'a str },
+    BlocksMined { count: u64, total_mined: u64 },
+    TransactionSent { txid: &'a Txid },
+    Error { message: &'a str },
+    Progress { label: &'a str, done: u64, total: u64 },
+    PeerCountChanged { previous: Option<usize>, current: usize },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    // Unix millis
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+#[derive(Default)]
+pub struct EventStream {
+    // Last peer count seen by the mine timer, to report changes only
+    peer_count: Cell<Option<usize>>,
+}
+
+impl EventStream {
+    pub fn emit(&self, event: &Event) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        if let Ok(line) = serde_json::to_string(&Envelope { timestamp, event }) {
+            let mut stdout = io::stdout().lock();
+            let _ = writeln!(stdout, "{}", line);
+            let _ = stdout.flush();
+        }
+    }
+}
+
+// stdout belongs to the event stream, so all text goes to stderr and errors are also emitted as events
+pub struct EventOutput {
+    events: Rc<EventStream>,
+}
+
+impl EventOutput {
+    pub fn new(events: Rc<EventStream>) -> Self {
+        EventOutput { events }
+    }
+}
+
+impl Output for EventOutput {
+    fn human(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn json(&self, _value: &serde_json::Value) {}
+
+    fn error(&self, message: &str) {
+        eprintln!("{}", message);
+        self.events.emit(&Event::Error { message });
+    }
+
+    fn progress(&self, label: &str, done: u64, total: u64) {
+        self.events.emit(&Event::Progress { label, done, total });
+    }
+}
+
+// Run on every mine timer tick, since peers come and go without any command being typed
+pub fn poll_peer_count(session: &Session) {
+    let Some(events) = &session.events else { return };
+    let Ok(current) = session.rpc_client.get_connection_count() else { return };
+    let previous = events.peer_count.replace(Some(current));
+    if previous != Some(current) {
+        events.emit(&Event::PeerCountChanged { previous, current });
+    }
+}
@@ -1,166 +1,672 @@
-use std::str::FromStr;
+#[macro_use]
+mod output;
+mod activity;
+mod amount;
+mod chain;
+mod commands;
+mod config;
+mod dashboard;
+mod error;
+mod events;
+mod mempool;
+mod message;
+mod mining;
+mod peers;
+mod rawtx;
+mod rpc;
+mod script;
+mod send;
+mod session;
+mod snapshot;
+#[cfg(test)]
+mod testing;
+mod wallet;
+mod watch;
 
-use bitcoin::address::Address;
-use bitcoin::Amount;
-use bitcoincore_rpc::{Auth, Client, RpcApi};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use bitcoin::Network;
+use bitcoincore_rpc::{Auth, RpcApi};
+use bitcoincore_rpc::json::{AddressType, EstimateMode};
 use clap::Parser;
-use futures::{AsyncReadExt, executor::block_on, stream::StreamExt};
+use clap::error::ErrorKind;
+use futures::executor::block_on;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde_json::json;
 use tokio::{io, select};
 use tokio::io::AsyncBufReadExt;
-use tokio::time::{Duration, Instant, sleep};
+use tokio::time::{interval, sleep_until, Duration, Instant, MissedTickBehavior};
 
-macro_rules! conditional_print {
-    ($condition:expr, $($arg:tt)*) => {
-        if $condition {
-            println!($($arg)*);
-        }
-    };
-}
+use crate::activity::{replay, ActivityLog, LoggingOutput};
+use crate::chain::{check_block_count, warn_if_pruned};
+use crate::commands::handle_input_line;
+use crate::config::{config_layers, load_config, resolve_connection, ConnectionSettings, Profile, SettingSource};
+use crate::dashboard::run_dashboard;
+use crate::error::MinerError;
+use crate::events::{EventOutput, EventStream};
+use crate::mempool::poll_mempool_watch;
+use crate::mining::{
+    next_mine_deadline, on_mine_timer, startup_generate, validate_mining_address, DEFAULT_MINE_INTERVAL_SECS,
+};
+use crate::output::{JsonOutput, Output, StdoutOutput};
+use crate::rpc::{HttpNode, MinerClient};
+use crate::script::run_script;
+use crate::session::{parse_address, Session};
+use crate::snapshot::load_snapshots;
+use crate::wallet::{check_balance, open_wallet, poll_stable_wait, seed_wallet_descriptor, STABLE_POLL_INTERVAL};
+
+// Exit codes for wrapper scripts, following sysexits.h
+const EXIT_COMMAND_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 64;
+const EXIT_CONFIG: i32 = 78;
+// 128 + SIGINT, what a shell reports for a process killed by Ctrl-C
+const EXIT_INTERRUPTED: i32 = 130;
 
 #[derive(Debug, Parser)]
 #[clap(name = "btc client")]
 struct Opts {
+    /// Wallet to load and use, overrides the wallet set in the config file
     #[clap(long)]
-    #[arg(required = true)]
-    wallet_name: String,
-}
+    wallet_name: Option<String>,
 
-fn check_block_count(rpc_client: &Client) {
-    let block_count = rpc_client.get_block_count().expect("Failed to get block count");
-    println!("Current block count: {}", block_count);
-}
+    /// TOML file with connection settings and optional [profiles.<name>] tables
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Named profile from the config file to connect with
+    #[clap(long, requires = "config")]
+    profile: Option<String>,
+
+    /// Node RPC URL, overrides the config file
+    #[clap(long)]
+    rpc_url: Option<String>,
+
+    /// RPC username, overrides the config file
+    #[clap(long)]
+    rpc_user: Option<String>,
+
+    /// RPC password, overrides the config file
+    #[clap(long)]
+    rpc_password: Option<String>,
+
+    /// Print the connection settings after merging defaults, the config file and flags, with where each came from
+    #[clap(long)]
+    show_effective_config: bool,
+
+    /// Seconds to wait for each RPC response before giving up
+    #[clap(long, default_value_t = 15)]
+    rpc_timeout: u64,
+
+    /// Network addresses are validated against (bitcoin, testnet, signet, regtest), detected from the node if unset
+    #[clap(long)]
+    network: Option<Network>,
+
+    /// Mine a block on every timer tick when the mempool has transactions
+    #[clap(long)]
+    auto_mine: bool,
+
+    /// Mine a block right after each successful send (picking up anything else in the mempool too)
+    /// and never on the timer; cannot be combined with --auto-mine (regtest only)
+    #[clap(long, conflicts_with = "auto_mine")]
+    auto_mine_on_send: bool,
+
+    /// Address mined blocks pay to, instead of a fresh wallet address each time
+    #[clap(long)]
+    mine_to_address: Option<String>,
+
+    /// Fixed address printed by the receive command, instead of a fresh one each time
+    #[clap(long)]
+    receive_address: Option<String>,
+
+    /// Mine a block after every successful send so it confirms immediately (regtest only)
+    #[clap(long)]
+    confirm_sends: bool,
+
+    /// Allow dumpwallet to write the wallet's private keys to a file (regtest only)
+    #[clap(long)]
+    allow_key_export: bool,
+
+    /// Allow resetchain to invalidate the whole chain and mine a new one (regtest only)
+    #[clap(long)]
+    allow_chain_reset: bool,
+
+    /// Show a live dashboard of chain and wallet state instead of the command prompt (q to quit)
+    #[clap(long)]
+    dashboard: bool,
+
+    /// Seconds between mempool polls while mempoolwatch is running
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    mempoolwatch_interval: u64,
+
+    /// Random offset (in seconds, applied as +/- jitter) added to each auto-mine interval
+    #[clap(long)]
+    #[arg(default_value_t = 0)]
+    mine_interval_jitter: u64,
+
+    /// Seed for the RNG behind the mine timer jitter and simulate, for reproducible block timing
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// File used to load and persist address aliases across sessions. Defaults to the --log-file, or failing that
+    /// the --config file, with its extension replaced by .aliases
+    #[clap(long)]
+    alias_file: Option<PathBuf>,
+
+    /// File used to load and persist chain snapshots (snapshot save) across sessions
+    #[clap(long)]
+    snapshot_file: Option<PathBuf>,
+
+    /// Descriptor imported (with a rescan) after the wallet is loaded, creating a blank wallet if needed,
+    /// so every run starts from the same keys
+    #[clap(long)]
+    seed_descriptor: Option<String>,
+
+    /// File recording sends made with an id=<token> option, so re-running them skips the broadcast
+    #[clap(long)]
+    idempotency_log: Option<PathBuf>,
+
+    /// Number of blocks to mine to the wallet right after it is loaded
+    #[clap(long)]
+    startup_generate: Option<u64>,
+
+    /// Skip --startup-generate if the chain is already at least this high
+    #[clap(long, requires = "startup_generate")]
+    startup_generate_min_height: Option<u64>,
+
+    /// Skip the block count, balance and pruning checks at startup, showing them before the first
+    /// command that talks to the node instead
+    #[clap(long)]
+    no_startup_checks: bool,
+
+    /// Default confirmation target (in blocks) for fee estimation on sends without an explicit fee rate
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=1008))]
+    confirm_target: Option<u32>,
+
+    /// Fee estimation mode (unset, conservative or economical) for estimatefee and sends without an explicit fee rate
+    #[clap(long, value_parser = parse_estimate_mode)]
+    estimate_mode: Option<EstimateMode>,
+
+    /// Address type for change outputs of sends and funded transactions (legacy, p2sh-segwit, bech32 or bech32m)
+    #[clap(long, value_parser = parse_change_type)]
+    change_type: Option<AddressType>,
+
+    /// Print the fee and fee rate actually paid after each send (default: on, off with --json)
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    show_fee: Option<bool>,
+
+    /// Keep running the mine timer after stdin is closed instead of exiting, as long as it has work to do
+    /// (auto-mine, watched addresses or mempoolwatch)
+    #[clap(long)]
+    keep_alive: bool,
+
+    /// Additional node RPC URLs (same credentials) used by multi-node commands such as compare
+    #[clap(long = "node")]
+    nodes: Vec<String>,
+
+    /// Reload the wallet and retry once when a command fails because it was unloaded
+    #[clap(long)]
+    auto_reload_wallet: bool,
+
+    /// Shut down automatically after this many minutes, with a warning one minute before.
+    /// The limit is fixed once started: it cannot be extended or cancelled from the prompt
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_session_duration: Option<u64>,
+
+    /// Append every command and message to this file, in the format --replay reads
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+
+    /// Re-run the commands recorded in a --log-file before reading input
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    /// Multiplier applied to the recorded gaps between replayed commands (2 = twice as fast)
+    #[clap(long, requires = "replay", default_value_t = 1.0, value_parser = parse_replay_speed)]
+    replay_speed: f64,
 
-fn check_balance(rpc_client: &Client) {
-    let balance = rpc_client.get_balance(None, None).expect("Failed to get balance");
-    println!("Current balance: {}", balance);
+    /// Run the commands in this file (one per line, # for comments) before reading input,
+    /// stopping at the first one that fails
+    #[clap(long)]
+    script: Option<PathBuf>,
+
+    /// Check every --script line against the known commands and their required arguments before running any
+    #[clap(long, requires = "script")]
+    script_strict: bool,
+
+    /// Write command results as JSON lines on stdout (human-readable messages go to stderr)
+    #[clap(long)]
+    json: bool,
+
+    /// Stream a JSON line on stdout for every command, mined block, send, error and peer count change
+    /// (human-readable messages go to stderr)
+    #[clap(long, conflicts_with_all = ["json", "dashboard"])]
+    events: bool,
 }
 
-fn send_to_address(rpc_client: &Client, address_string: &str, amount: Amount) {
-    let recipient_address = match Address::from_str(address_string) {
-        Ok(addr) => addr.assume_checked(),
+#[tokio::main]
+async fn main() {
+    let opts = parse_opts();
+
+    let events = opts.events.then(|| Rc::new(EventStream::default()));
+    let mut out: Rc<dyn Output> = if let Some(events) = &events {
+        Rc::new(EventOutput::new(events.clone()))
+    } else if opts.json {
+        Rc::new(JsonOutput::default())
+    } else {
+        Rc::new(StdoutOutput::default())
+    };
+
+    let activity_log = match &opts.log_file {
+        Some(path) => match ActivityLog::open(path) {
+            Ok(log) => Some(Rc::new(log)),
+            Err(e) => {
+                out.error(&format!("Error: cannot open log file {}: {}", path.display(), e));
+                std::process::exit(EXIT_CONFIG);
+            }
+        },
+        None => None,
+    };
+    if let Some(log) = &activity_log {
+        out = Rc::new(LoggingOutput::new(out, log.clone()));
+    }
+
+    let settings = match connection_settings(&opts) {
+        Ok(settings) => settings,
         Err(e) => {
-            eprintln!("Error parsing address {:?}", e);
-            return;
+            out.error(&format!("Error: {}", e));
+            std::process::exit(EXIT_CONFIG);
         }
     };
-
-    match rpc_client.send_to_address(&recipient_address, amount, None, None, None, None, None, None) {
-        Ok(tx_id) => println!("TxID: {}", tx_id),
-        Err(e) => println!("Failed to send amount to address {}. Error {:?}", address_string, e)
+    if opts.show_effective_config {
+        show_effective_config(out.as_ref(), &settings);
     }
-}
 
-fn generate_blocks_if_required(rpc_client: &Client, do_print: bool) {
-    conditional_print!(do_print, "Checking for new transactions");
-
-    match rpc_client.get_raw_mempool() {
-        Ok(pending_transactions) => {
-            if pending_transactions.len() > 0 {
-                conditional_print!(do_print, "Found new transactions, generating block");
-                // If there are pending transactions, generate 1 block (bitcoin core should automatically mine the transactions in the mempool)
-                let new_address = rpc_client.get_new_address(None, None).unwrap().assume_checked();
-                match rpc_client.generate_to_address(1, &new_address) {
-                    Ok(_) => conditional_print!(do_print, "Generated and sent new block. Transaction count: {}", pending_transactions.len()),
-                    Err(e) => conditional_print!(do_print, "Error generating block {e}")
-                }
-            } else {
-                conditional_print!(do_print, "No new transactions found");
+    // Initialize the bitcoind RPC client
+    let rpc_timeout = Duration::from_secs(opts.rpc_timeout);
+    let rpc_client = HttpNode::connect(&settings.url, &settings.auth, rpc_timeout, &settings.wallet_name)
+        .expect("Error creating RPC client");
+    let rpc_client = MinerClient::new(rpc_client, settings.wallet_name.clone(), opts.auto_reload_wallet, out.clone());
+
+    let network = match (settings.network, rpc_client.get_blockchain_info()) {
+        (Some(network), Ok(info)) if info.chain != network => {
+            out.error(&format!("Warning: configured network {} but the node is on {}", network, info.chain));
+            network
+        }
+        (Some(network), _) => network,
+        (None, Ok(info)) => info.chain,
+        (None, Err(e)) => {
+            out.error(&format!("Failed to get chain, assuming regtest. Error {:?}", e));
+            Network::Regtest
+        }
+    };
+
+    let mut session = Session {
+        rpc_client,
+        rpc_url: settings.url,
+        rpc_auth: settings.auth,
+        rpc_timeout,
+        out,
+        activity_log,
+        events,
+        network,
+        aliases: BTreeMap::new(),
+        alias_file: alias_file(&opts),
+        idempotency_log: opts.idempotency_log.clone(),
+        pending_action: None,
+        confirm_target: opts.confirm_target,
+        estimate_mode: opts.estimate_mode,
+        change_type: opts.change_type,
+        show_fee: opts.show_fee.unwrap_or(!opts.json),
+        extra_nodes: opts.nodes.clone(),
+        watches: BTreeMap::new(),
+        notes: BTreeMap::new(),
+        snapshots: BTreeMap::new(),
+        snapshot_file: opts.snapshot_file.clone(),
+        captured_txs: BTreeMap::new(),
+        mempool_watch: None,
+        stable_wait: None,
+        quit_requested: false,
+        auto_mine: opts.auto_mine,
+        auto_mine_on_send: opts.auto_mine_on_send,
+        mine_interval_secs: DEFAULT_MINE_INTERVAL_SECS,
+        mine_interval_jitter: opts.mine_interval_jitter,
+        mine_schedule_changed: false,
+        next_mine_at: Cell::new(None),
+        mine_to_address: RefCell::new(None),
+        receive_address: None,
+        confirm_sends: opts.confirm_sends,
+        allow_key_export: opts.allow_key_export,
+        allow_chain_reset: opts.allow_chain_reset,
+        blocks_mined: Cell::new(0),
+        startup_checks_pending: opts.no_startup_checks,
+        interrupt_trap: None,
+        rng: RefCell::new(match opts.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }),
+    };
+
+    if let Some(address) = &opts.mine_to_address {
+        let validated = parse_address(address, session.network)
+            .and_then(|address| validate_mining_address(&session, &address).map(|()| address));
+        match validated {
+            Ok(address) => *session.mine_to_address.get_mut() = Some(address),
+            Err(e) => {
+                err!(session, "Error: invalid --mine-to-address: {}", e);
+                std::process::exit(EXIT_CONFIG);
             }
         }
-        Err(e) => {
-            if !do_print {
-                conditional_print!(do_print, "Error getting transactions from mempool {}", e)
+    }
+    if let Some(address) = &opts.receive_address {
+        match parse_address(address, session.network) {
+            Ok(address) => session.receive_address = Some(address),
+            Err(e) => {
+                err!(session, "Error: invalid --receive-address: {}", e);
+                std::process::exit(EXIT_CONFIG);
             }
         }
     }
-}
+    if session.confirm_sends && !session.require_regtest("--confirm-sends") {
+        session.confirm_sends = false;
+    }
+    if session.auto_mine_on_send && !session.require_regtest("--auto-mine-on-send") {
+        session.auto_mine_on_send = false;
+    }
 
-#[tokio::main]
-async fn main() {
-    // Set up RPC authentication
-    let rpc_url = "http://127.0.0.1:18443"; // Local node running on port 8332
-    let rpc_user = "user";
-    let rpc_password = "password";
-    let opts = Opts::parse();
+    open_wallet(&session, &settings.wallet_name, opts.seed_descriptor.is_some());
+    if let Some(descriptor) = &opts.seed_descriptor {
+        seed_wallet_descriptor(&session, descriptor);
+    }
 
-    // Initialize the bitcoind RPC client
-    let rpc_client = Client::new(rpc_url, Auth::UserPass(rpc_user.to_string(), rpc_password.to_string()))
-        .expect("Error creating RPC client");
+    if let Some(count) = opts.startup_generate {
+        startup_generate(&session, count, opts.startup_generate_min_height);
+    }
 
-    match rpc_client.load_wallet(opts.wallet_name.as_str()) {
-        Ok(v) => println!("Loaded wallet {}", v.name),
-        Err(e) => {
-            println!("Failed to load wallet {:?}", e);
-        }
+    if !opts.no_startup_checks {
+        check_block_count(&session);
+        check_balance(&session);
+        warn_if_pruned(&session);
     }
 
-    check_block_count(&rpc_client);
-    check_balance(&rpc_client);
-    println!("-- TYPE COMMANDS --");
+    session.load_aliases();
+    load_snapshots(&mut session);
+    out!(session, "-- TYPE COMMANDS --");
+
+    if let Some(path) = &opts.replay {
+        replay(&mut session, path, opts.replay_speed);
+    }
+    if let Some(path) = &opts.script {
+        run_script(&mut session, path, opts.script_strict);
+    }
+
+    if opts.dashboard {
+        if let Err(e) = run_dashboard(&mut session) {
+            err!(session, "Dashboard error {:?}", e);
+        }
+        end_session(&mut session);
+        return;
+    }
 
     let mut stdin = io::BufReader::new(io::stdin()).lines();
-    let sleep = sleep(Duration::from_secs(15));
+    let sleep = sleep_until(next_mine_deadline(&session));
     tokio::pin!(sleep);
 
+    // Both timers are only polled when --max-session-duration is set
+    let session_secs = opts.max_session_duration.map_or(0, |minutes| minutes * 60);
+    let session_end = sleep_until(Instant::now() + Duration::from_secs(session_secs));
+    let expiry_warning = sleep_until(Instant::now() + Duration::from_secs(session_secs.saturating_sub(60)));
+    tokio::pin!(session_end, expiry_warning);
+
+    let mut mempool_tick = interval(Duration::from_secs(opts.mempoolwatch_interval));
+    mempool_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut stable_tick = interval(STABLE_POLL_INTERVAL);
+    stable_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut stdin_open = true;
+    let mut expiry_warned = false;
+    let mut last_command_ok = true;
+    let mut exit_code = 0;
+
     block_on(async {
         loop {
+            if session.quit_requested {
+                break;
+            }
+            let interrupt_trap = session.interrupt_trap.clone();
+
             select! {
-                Ok(Some(line)) = stdin.next_line() => {
-                    handle_input_line(&rpc_client, line);
+                line = stdin.next_line(), if stdin_open => match line {
+                    Ok(Some(line)) => {
+                        last_command_ok = handle_input_line(&mut session, line);
+                        if session.mine_schedule_changed {
+                            session.mine_schedule_changed = false;
+                            sleep.as_mut().reset(next_mine_deadline(&session));
+                        }
+                    }
+                    Ok(None) => match on_input_closed(&session, opts.keep_alive, last_command_ok) {
+                        InputClosed::KeepTicking => {
+                            out!(session, "Input closed, continuing to run the mine timer");
+                            stdin_open = false;
+                        }
+                        InputClosed::Exit(code) => {
+                            if opts.keep_alive {
+                                out!(session, "Input closed and auto-mine is off with nothing watched, exiting");
+                            }
+                            exit_code = code;
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        err!(session, "Error reading input {:?}", e);
+                        break;
+                    }
+                },
+
+                () = &mut expiry_warning, if opts.max_session_duration.is_some() && !expiry_warned => {
+                    out!(session, "Warning: session expires in one minute");
+                    expiry_warned = true;
                 }
 
-                () = &mut sleep => {
-                    // generate_blocks_if_required(&rpc_client, false);
-                    sleep.as_mut().reset(Instant::now() + Duration::from_secs(15));
+                () = &mut session_end, if opts.max_session_duration.is_some() => {
+                    out!(session, "Maximum session duration reached");
+                    break;
                 }
-            }
-        }
-    });
-}
 
-// For convenience. All these can be done from the CLI
-fn handle_input_line(rpc_client: &Client, line: String) {
-    let mut args = line.split(' ');
-
-    match args.next() {
-        Some("sendtoaddress") => {
-            let address = match args.next() {
-                Some(address) => address,
-                None => {
-                    eprintln!("Bitcoin address required");
-                    return;
+                _ = mempool_tick.tick(), if session.mempool_watch.is_some() => {
+                    poll_mempool_watch(&mut session);
                 }
-            };
 
-            let amount = match args.next() {
-                Some(amount) => amount,
-                None => {
-                    eprintln!("Bitcoin address required");
-                    return;
+                _ = stable_tick.tick(), if session.stable_wait.is_some() => {
+                    poll_stable_wait(&mut session);
                 }
-            };
 
-            match f64::from_str(amount) {
-                Ok(amount_f64) => {
-                    let amt = Amount::from_btc(amount_f64).unwrap();
-                    send_to_address(rpc_client, address, amt);
+                // Only once a simulate has taken Ctrl-C over, before that the default handler still applies
+                () = async { interrupt_trap.as_ref().expect("checked by the precondition").quit.notified().await },
+                    if interrupt_trap.is_some() => {
+                    out!(session, "Interrupted");
+                    exit_code = EXIT_INTERRUPTED;
+                    break;
                 }
-                Err(e) => {
-                    eprintln!("Error parsing amount {:?}", e);
+
+                () = &mut sleep => {
+                    on_mine_timer(&mut session);
+                    sleep.as_mut().reset(next_mine_deadline(&session));
                 }
             }
         }
-        Some("mine") => {
-            generate_blocks_if_required(rpc_client, true);
-        }
-        Some("balance") => {
-            check_balance(rpc_client);
-        }
-        Some("blockcount") => {
-            check_block_count(rpc_client);
-        }
-        _ => {
-            eprintln!("Invalid command");
+    });
+
+    end_session(&mut session);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+fn parse_estimate_mode(arg: &str) -> Result<EstimateMode, String> {
+    match arg.to_lowercase().as_str() {
+        "unset" => Ok(EstimateMode::Unset),
+        "conservative" => Ok(EstimateMode::Conservative),
+        "economical" => Ok(EstimateMode::Economical),
+        _ => Err(format!("expected unset, conservative or economical, got '{}'", arg)),
+    }
+}
+
+fn parse_change_type(arg: &str) -> Result<AddressType, String> {
+    match arg.to_lowercase().as_str() {
+        "legacy" => Ok(AddressType::Legacy),
+        "p2sh-segwit" => Ok(AddressType::P2shSegwit),
+        "bech32" => Ok(AddressType::Bech32),
+        "bech32m" => Ok(AddressType::Bech32m),
+        _ => Err(format!("expected legacy, p2sh-segwit, bech32 or bech32m, got '{}'", arg)),
+    }
+}
+
+fn parse_replay_speed(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("expected a positive number, got '{}'", arg)),
+    }
+}
+
+// Argument errors get their own exit code so scripts can tell them apart from RPC failures
+fn parse_opts() -> Opts {
+    match Opts::try_parse() {
+        Ok(opts) => opts,
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => e.exit(),
+        Err(e) => {
+            // Drop clap's usage block, keeping just the error itself
+            let rendered = e.to_string();
+            let message = rendered.split("\n\nUsage:").next().unwrap_or(&rendered).trim_end();
+            eprintln!("{}", message);
+            eprintln!("Run with --help for the list of options");
+            std::process::exit(EXIT_USAGE);
         }
     }
-}
\ No newline at end of file
+}
+
+// Whether --keep-alive has a reason to keep ticking once input is closed
+fn timer_has_work(session: &Session) -> bool {
+    session.auto_mine || !session.watches.is_empty() || session.mempool_watch.is_some() || session.stable_wait.is_some()
+}
+
+#[derive(Debug, PartialEq)]
+enum InputClosed {
+    // --keep-alive and the timer still has work to do
+    KeepTicking,
+    Exit(i32),
+}
+
+// Nothing left for the timer to do, so a one-shot piped command shouldn't idle on it, and exits
+// with that command's status so a wrapper script can tell it failed
+fn on_input_closed(session: &Session, keep_alive: bool, last_command_ok: bool) -> InputClosed {
+    if keep_alive && timer_has_work(session) {
+        return InputClosed::KeepTicking;
+    }
+    InputClosed::Exit(if last_command_ok { 0 } else { EXIT_COMMAND_FAILED })
+}
+
+// Shared cleanup for quit, end of input and session expiry
+fn end_session(session: &mut Session) {
+    if session.pending_action.take().is_some() {
+        out!(session, "Discarding unconfirmed action");
+    }
+    if !session.watches.is_empty() {
+        out!(session, "Stopped watching {} address(es)", session.watches.len());
+    }
+    out!(session, "-- SESSION ENDED --");
+}
+
+// A logged or configured setup keeps its aliases without a separate flag
+fn alias_file(opts: &Opts) -> Option<PathBuf> {
+    let beside = |path: &PathBuf| path.with_extension("aliases");
+    opts.alias_file.clone()
+        .or_else(|| opts.log_file.as_ref().map(beside))
+        .or_else(|| opts.config.as_ref().map(beside))
+}
+
+// Config file (top level, then --profile), then individual CLI flags on top
+fn connection_settings(opts: &Opts) -> Result<ConnectionSettings, MinerError> {
+    let mut layers = match &opts.config {
+        Some(path) => config_layers(load_config(path)?, opts.profile.as_deref())?,
+        None => Vec::new(),
+    };
+
+    let cli = Profile {
+        url: opts.rpc_url.clone(),
+        user: opts.rpc_user.clone(),
+        password: opts.rpc_password.clone(),
+        network: opts.network,
+        wallet: opts.wallet_name.clone(),
+    };
+
+    layers.push((SettingSource::CommandLine, cli));
+    resolve_connection(layers)
+}
+
+// --show-effective-config: the merged connection settings and which layer each came from
+fn show_effective_config(out: &dyn Output, settings: &ConnectionSettings) {
+    let Auth::UserPass(user, _) = &settings.auth else { return };
+    let network = settings.network.map_or_else(|| "detect from node".to_string(), |network| network.to_string());
+    let sources = &settings.sources;
+    let rows = [
+        ("rpc url", settings.url.as_str(), &sources.url),
+        ("rpc user", user.as_str(), &sources.user),
+        ("rpc password", "<redacted>", &sources.password),
+        ("network", network.as_str(), &sources.network),
+        ("wallet", settings.wallet_name.as_str(), &sources.wallet),
+    ];
+
+    out.human("Effective configuration:");
+    for (name, value, source) in &rows {
+        out.human(&format!("  {:<12}  {:<30}  ({})", name, value, source));
+    }
+    let fields: serde_json::Map<String, serde_json::Value> = rows.iter()
+        .map(|(name, value, source)| (name.replace(' ', "_"), json!({ "value": value, "source": source.to_string() })))
+        .collect();
+    out.json(&json!({ "effective_config": fields }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_session, ScriptedNode};
+
+    #[test]
+    fn closed_input_exits_with_the_last_command_status() {
+        let node = ScriptedNode::default();
+        let (mut session, _) = test_session(&node);
+
+        let ok = handle_input_line(&mut session, "help".to_string());
+        assert_eq!(on_input_closed(&session, false, ok), InputClosed::Exit(0));
+
+        let ok = handle_input_line(&mut session, "frobnicate".to_string());
+        assert_eq!(on_input_closed(&session, false, ok), InputClosed::Exit(EXIT_COMMAND_FAILED));
+    }
+
+    #[test]
+    fn keep_alive_only_keeps_ticking_with_timer_work() {
+        let node = ScriptedNode::default();
+        let (mut session, _) = test_session(&node);
+
+        assert_eq!(on_input_closed(&session, true, true), InputClosed::Exit(0));
+        session.auto_mine = true;
+        assert_eq!(on_input_closed(&session, true, true), InputClosed::KeepTicking);
+        assert_eq!(on_input_closed(&session, false, true), InputClosed::Exit(0));
+    }
+
+    #[test]
+    fn aliases_persist_beside_the_log_or_config_file_by_default() {
+        let parse = |args: &[&str]| Opts::parse_from([&["btc-miner"], args].concat());
+
+        assert_eq!(alias_file(&parse(&[])), None);
+        assert_eq!(alias_file(&parse(&["--config", "miner.toml"])), Some(PathBuf::from("miner.aliases")));
+        let logged = parse(&["--config", "miner.toml", "--log-file", "logs/session.log"]);
+        assert_eq!(alias_file(&logged), Some(PathBuf::from("logs/session.aliases")));
+        let explicit = parse(&["--log-file", "session.log", "--alias-file", "book.txt"]);
+        assert_eq!(alias_file(&explicit), Some(PathBuf::from("book.txt")));
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::{Amount, Txid};
+use bitcoincore_rpc::RpcApi;
+use serde_json::json;
+
+use crate::rpc::rpc_error_code;
+use crate::session::Session;
+
+pub const DEFAULT_MEMPOOL_WAIT_SECS: u64 = 30;
+const MEMPOOL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// getmempoolentry reports RPC_INVALID_ADDRESS_OR_KEY for transactions not (yet) in the mempool
+pub const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+pub fn wait_for_mempool(session: &Session, txid: Txid, timeout_secs: u64) {
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(timeout_secs);
+    out!(session, "Waiting up to {}s for {} to enter the mempool", timeout_secs, txid);
+
+    loop {
+        match session.rpc_client.get_mempool_entry(&txid) {
+            Ok(entry) => {
+                let waited = started.elapsed().as_secs_f64();
+                out!(session, "Transaction {} arrived in the mempool after {:.1}s (vsize {}, fee {})", txid, waited, entry.vsize, entry.fees.base);
+                session.out.json(&json!({ "txid": txid, "arrived": true, "waited_secs": waited }));
+                return;
+            }
+            Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => {}
+            Err(e) => {
+                err!(session, "Failed to query mempool for {}. Error {:?}", txid, e);
+                return;
+            }
+        }
+
+        if Instant::now() >= deadline {
+            out!(session, "Timed out after {}s waiting for {} to enter the mempool", timeout_secs, txid);
+            session.out.json(&json!({ "txid": txid, "arrived": false, "waited_secs": timeout_secs }));
+            return;
+        }
+        thread::sleep(MEMPOOL_POLL_INTERVAL);
+    }
+}
+
+// Mempool contents with each transaction's fee, diffed on every mempoolwatch tick
+fn mempool_fees(session: &Session) -> Result<HashMap<Txid, Amount>, bitcoincore_rpc::Error> {
+    let entries: HashMap<Txid, serde_json::Value> = session.rpc_client.call("getrawmempool", &[true.into()])?;
+    Ok(entries.into_iter()
+        .map(|(txid, entry)| {
+            let fee = entry["fees"]["base"].as_f64().and_then(|btc| Amount::from_btc(btc).ok()).unwrap_or(Amount::ZERO);
+            (txid, fee)
+        })
+        .collect())
+}
+
+pub fn start_mempool_watch(session: &mut Session) {
+    if session.mempool_watch.is_some() {
+        out!(session, "Already watching the mempool, stop with mempoolwatch stop or an empty line");
+        return;
+    }
+    match mempool_fees(session) {
+        Ok(snapshot) => {
+            out!(session, "Watching the mempool ({} transaction(s)), stop with mempoolwatch stop or an empty line", snapshot.len());
+            session.mempool_watch = Some(snapshot);
+        }
+        Err(e) => err!(session, "Failed to read the mempool. Error {:?}", e)
+    }
+}
+
+pub fn stop_mempool_watch(session: &mut Session) {
+    match session.mempool_watch.take() {
+        Some(_) => out!(session, "Stopped watching the mempool"),
+        None => out!(session, "Not watching the mempool"),
+    }
+}
+
+pub fn poll_mempool_watch(session: &mut Session) {
+    let Some(previous) = &session.mempool_watch else { return };
+    let current = match mempool_fees(session) {
+        Ok(current) => current,
+        Err(e) => {
+            err!(session, "Failed to read the mempool. Error {:?}", e);
+            return;
+        }
+    };
+
+    for (txid, fee) in &current {
+        if !previous.contains_key(txid) {
+            out!(session, "+ {} fee {}", txid, fee);
+            session.out.json(&json!({ "event": "arrived", "txid": txid, "fee": fee.to_btc() }));
+        }
+    }
+    // Usually mined, but evicted and replaced transactions disappear the same way
+    for (txid, fee) in previous {
+        if !current.contains_key(txid) {
+            out!(session, "- {} fee {} (left the mempool)", txid, fee);
+            session.out.json(&json!({ "event": "left", "txid": txid, "fee": fee.to_btc() }));
+        }
+    }
+    session.mempool_watch = Some(current);
+}
+
+// A block holds 4M weight units, i.e. 1M vB, less a little the node reserves for the coinbase
+const BLOCK_VSIZE_CAPACITY: u64 = 996_000;
+// Default -blockmintxfee, below which the node's block template leaves a transaction out
+const BLOCK_MIN_FEE_RATE: f64 = 1.0;
+
+// Ancestor fee rate in sat/vB, roughly the order the block template picks transactions in
+fn mining_score(entry: &serde_json::Value) -> f64 {
+    let fee = entry["fees"]["ancestor"].as_f64().unwrap_or(0.0) * 100_000_000.0;
+    let vsize = entry["ancestorsize"].as_f64().unwrap_or(1.0).max(1.0);
+    fee / vsize
+}
+
+pub fn show_confirmation_eta(session: &Session, txid: &Txid) {
+    let rpc_client = &session.rpc_client;
+    let entries: HashMap<Txid, serde_json::Value> = match rpc_client.call("getrawmempool", &[true.into()]) {
+        Ok(entries) => entries,
+        Err(e) => {
+            err!(session, "Failed to read the mempool. Error {:?}", e);
+            return;
+        }
+    };
+    let Some(entry) = entries.get(txid) else {
+        // Confirmed, or never seen: the wallet knows about its own transactions even without -txindex
+        let confirmations = rpc_client.get_raw_transaction_info(txid, None)
+            .map(|info| info.confirmations.unwrap_or(0))
+            .or_else(|_| rpc_client.get_transaction(txid, None).map(|wallet_tx| wallet_tx.info.confirmations.max(0) as u32));
+        match confirmations {
+            Ok(confirmations) if confirmations > 0 => {
+                out!(session, "{} is already confirmed ({} confirmation(s))", txid, confirmations);
+                session.out.json(&json!({ "txid": txid, "confirmed": true, "confirmations": confirmations }));
+            }
+            _ => {
+                out!(session, "{} is not in the mempool", txid);
+                session.out.json(&json!({ "txid": txid, "confirmed": false, "in_mempool": false }));
+            }
+        }
+        return;
+    };
+
+    let score = mining_score(entry);
+    let vsize_ahead: u64 = entries.iter()
+        .filter(|(other, other_entry)| *other != txid && mining_score(other_entry) > score)
+        .map(|(_, other_entry)| other_entry["vsize"].as_u64().unwrap_or(0))
+        .sum();
+    // Every auto-mine tick mines one block, so a crowded mempool takes several ticks to reach us
+    let blocks = vsize_ahead / BLOCK_VSIZE_CAPACITY + 1;
+    out!(session, "{} pays {:.2} sat/vB, {} vB ahead of it in the mempool", txid, score, vsize_ahead);
+    if score < BLOCK_MIN_FEE_RATE {
+        out!(session, "Warning: below the default block minimum of {} sat/vB, blocks may leave it out", BLOCK_MIN_FEE_RATE);
+    }
+
+    let eta_secs = match (session.auto_mine, session.auto_mine_on_send) {
+        (true, _) => {
+            let next_tick = session.next_mine_at.get()
+                .map_or(session.mine_interval_secs, |at| at.saturating_duration_since(Instant::now()).as_secs());
+            let eta_secs = next_tick + (blocks - 1) * session.mine_interval_secs;
+            match blocks {
+                1 => out!(session, "ETA: ~ next block in {}s", eta_secs),
+                _ => out!(session, "ETA: ~ {}s, about {} auto-mined blocks from now", eta_secs, blocks),
+            }
+            Some(eta_secs)
+        }
+        (false, true) => {
+            out!(session, "ETA: with the next successful send, which mines a block ({} needed)", blocks);
+            None
+        }
+        (false, false) => {
+            out!(session, "ETA: none, auto-mine is off (generate {} to confirm it)", blocks);
+            None
+        }
+    };
+    session.out.json(&json!({
+        "txid": txid,
+        "in_mempool": true,
+        "fee_rate": score,
+        "vsize_ahead": vsize_ahead,
+        "blocks": blocks,
+        "eta_secs": eta_secs,
+    }));
+}
+
+// Core's -mempoolexpiry default, which no RPC reports
+const DEFAULT_MEMPOOL_EXPIRY_HOURS: u64 = 336;
+
+// BTC/kvB as the RPCs report fee rates, to sat/vB
+fn sat_per_vbyte(btc_per_kvb: &serde_json::Value) -> Option<f64> {
+    btc_per_kvb.as_f64().map(|rate| rate * 100_000.0)
+}
+
+fn megabytes(bytes: &serde_json::Value) -> Option<f64> {
+    bytes.as_f64().map(|bytes| bytes / 1_000_000.0)
+}
+
+fn format_optional(value: Option<f64>, unit: &str) -> String {
+    value.map_or_else(|| "unknown".to_string(), |value| format!("{:.2} {}", value, unit))
+}
+
+pub fn show_mempool_limits(session: &Session) {
+    let results = session.rpc_client.batch(&[("getmempoolinfo", vec![]), ("getnetworkinfo", vec![])]);
+    let (mempool, network) = match (&results[0], &results[1]) {
+        (Ok(mempool), Ok(network)) => (mempool, network),
+        (Err(e), _) | (_, Err(e)) => {
+            err!(session, "Failed to get mempool policy. Error {:?}", e);
+            return;
+        }
+    };
+
+    let max_size = megabytes(&mempool["maxmempool"]);
+    let usage = megabytes(&mempool["usage"]);
+    let min_fee = sat_per_vbyte(&mempool["mempoolminfee"]);
+    let relay_fee = sat_per_vbyte(&mempool["minrelaytxfee"]).or_else(|| sat_per_vbyte(&network["relayfee"]));
+    // Moved from getnetworkinfo to getmempoolinfo in v24
+    let incremental_fee = sat_per_vbyte(&mempool["incrementalrelayfee"]).or_else(|| sat_per_vbyte(&network["incrementalfee"]));
+
+    out!(session, "Max mempool size:      {} ({} in use by {} tx(s))",
+        format_optional(max_size, "MB"), format_optional(usage, "MB"), mempool["size"].as_u64().unwrap_or(0));
+    out!(session, "Min relay fee:         {}", format_optional(relay_fee, "sat/vB"));
+    out!(session, "Mempool min fee:       {}", format_optional(min_fee, "sat/vB"));
+    out!(session, "Incremental relay fee: {}", format_optional(incremental_fee, "sat/vB"));
+    out!(session, "Expiry:                {}h unless -mempoolexpiry was changed (not reported over RPC)", DEFAULT_MEMPOOL_EXPIRY_HOURS);
+    if let Some(full_rbf) = mempool["fullrbf"].as_bool() {
+        out!(session, "Full RBF:              {}", if full_rbf { "on" } else { "off" });
+    }
+
+    // How each limit shows up when transactions broadcast here seem to disappear
+    if let (Some(min_fee), Some(relay_fee)) = (min_fee, relay_fee) {
+        if min_fee > relay_fee {
+            out!(session, "The mempool is full: anything paying under {:.2} sat/vB is rejected or evicted", min_fee);
+        }
+    }
+    out!(session, "Transactions under the min relay fee are rejected on broadcast and never relayed");
+    out!(session, "When the mempool reaches its max size the lowest fee rate transactions are evicted first, \
+        and the mempool min fee rises by the incremental relay fee");
+    match (session.auto_mine, session.auto_mine_on_send) {
+        (true, _) => out!(
+            session,
+            "Auto-mine empties the mempool every {}s, except transactions under {} sat/vB that blocks leave out; \
+             those stay until they expire",
+            session.mine_interval_secs, BLOCK_MIN_FEE_RATE
+        ),
+        (false, true) => out!(session, "Auto-mine on send mines the mempool after each send, so only a \
+            transaction under {} sat/vB left out of blocks can sit long enough to expire", BLOCK_MIN_FEE_RATE),
+        (false, false) => out!(session, "Auto-mine is off, so unmined transactions stay until evicted or expired"),
+    }
+
+    session.out.json(&json!({
+        "max_mempool_mb": max_size,
+        "usage_mb": usage,
+        "size": mempool["size"],
+        "min_relay_fee_sat_vb": relay_fee,
+        "mempool_min_fee_sat_vb": min_fee,
+        "incremental_relay_fee_sat_vb": incremental_fee,
+        "default_expiry_hours": DEFAULT_MEMPOOL_EXPIRY_HOURS,
+        "full_rbf": mempool["fullrbf"],
+    }));
+}
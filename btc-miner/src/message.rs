@@ -0,0 +1,93 @@
+use std::fs;
+
+use bitcoincore_rpc::RpcApi;
+use serde_json::json;
+
+use crate::rpc::{rpc_error_code, rpc_error_message};
+use crate::session::Session;
+
+// RPC_TYPE_ERROR, e.g. signing with a segwit address, which signmessage only supports for legacy keys
+const RPC_TYPE_ERROR: i32 = -3;
+
+// The message is either the rest of the command line or, with --file <path>, the file's contents
+pub enum MessageSource<'a> {
+    Inline(String),
+    File(&'a str),
+}
+
+pub fn parse_message_source<'a>(args: &[&'a str]) -> Option<MessageSource<'a>> {
+    match args {
+        [] => None,
+        ["--file", path] => Some(MessageSource::File(path)),
+        ["--file", ..] => None,
+        words => Some(MessageSource::Inline(words.join(" "))),
+    }
+}
+
+// Read as raw bytes so line endings are kept exactly; the RPC takes a string, so it must be UTF-8
+fn read_message(session: &Session, source: MessageSource) -> Option<String> {
+    let path = match source {
+        MessageSource::Inline(message) => return Some(message),
+        MessageSource::File(path) => path,
+    };
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            err!(session, "Failed to read message file {}: {}", path, e);
+            return None;
+        }
+    };
+    match String::from_utf8(bytes) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            err!(session, "Message file {} is not valid UTF-8 (byte {}), only text messages can be signed", path, e.utf8_error().valid_up_to());
+            None
+        }
+    }
+}
+
+fn describe_message_error(e: &bitcoincore_rpc::Error) -> String {
+    match rpc_error_code(e) {
+        Some(RPC_TYPE_ERROR) => format!("{} (only legacy P2PKH addresses can sign messages)", rpc_error_message(e).unwrap_or_default()),
+        Some(_) => rpc_error_message(e).unwrap_or_default().to_string(),
+        None => format!("{:?}", e),
+    }
+}
+
+pub fn sign_message(session: &Session, address_string: &str, source: MessageSource) {
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+    let Some(message) = read_message(session, source) else { return };
+
+    match session.rpc_client.call::<String>("signmessage", &[json!(address), json!(message)]) {
+        Ok(signature) => {
+            out!(session, "Signature: {}", signature);
+            session.out.json(&json!({ "address": address, "signature": signature, "message_bytes": message.len() }));
+        }
+        Err(e) => err!(session, "Signing failed: {}", describe_message_error(&e))
+    }
+}
+
+pub fn verify_message(session: &Session, address_string: &str, signature: &str, source: MessageSource) {
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+    let Some(message) = read_message(session, source) else { return };
+
+    match session.rpc_client.call::<bool>("verifymessage", &[json!(address), json!(signature), json!(message)]) {
+        Ok(valid) => {
+            out!(session, "Signature is {}", if valid { "VALID" } else { "INVALID" });
+            session.out.json(&json!({ "address": address, "valid": valid }));
+        }
+        Err(e) => err!(session, "Verification failed: {}", describe_message_error(&e))
+    }
+}
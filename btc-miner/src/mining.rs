@@ -0,0 +1,679 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version as BlockVersion};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::script::{Builder, PushBytesBuf};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode,
+    TxOut, Witness,
+};
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::json::{GetBlockTemplateModes, GetBlockTemplateRules};
+use rand::Rng;
+use serde_json::json;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+use crate::chain::check_block_count;
+use crate::error::MinerError;
+use crate::events::poll_peer_count;
+use crate::mempool::RPC_INVALID_ADDRESS_OR_KEY;
+use crate::rpc::{rpc_error_code, rpc_error_message};
+use crate::session::Session;
+use crate::watch::poll_watches;
+
+pub const DEFAULT_MINE_INTERVAL_SECS: u64 = 15;
+// Blocks requested per generatetoaddress call, so progress can be reported between calls
+const GENERATE_BATCH_SIZE: u64 = 10;
+// Largest jump minetoheight makes in one go, so a typo'd height doesn't mine for hours
+const MAX_FAST_FORWARD_BLOCKS: u64 = 10_000;
+// Consensus limit on the coinbase scriptSig, which has to hold the BIP34 height as well as the tag
+const MAX_COINBASE_SCRIPT_SIG_LEN: usize = 100;
+const RPC_WALLET_KEYPOOL_RAN_OUT: i32 = -12;
+// Mainnet's ten minutes scaled down 60x, so a simulate run shows realistic spread in minutes rather than hours
+pub const DEFAULT_SIMULATE_INTERVAL_SECS: u64 = 10;
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Picks the next auto-mine deadline, offsetting the session's interval by a uniform random jitter.
+// Read on every tick, so automine interval takes effect from the next deadline
+pub fn next_mine_deadline(session: &Session) -> Instant {
+    let mut interval_secs = session.mine_interval_secs as i64;
+    if session.mine_interval_jitter > 0 {
+        let jitter = session.mine_interval_jitter as i64;
+        interval_secs += session.rng.borrow_mut().gen_range(-jitter..=jitter);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(interval_secs.max(1) as u64);
+    session.next_mine_at.set(Some(deadline.into_std()));
+    deadline
+}
+
+// --mine-to-address if configured (validated for the network when it is set), otherwise a fresh wallet address
+pub fn mining_address(session: &Session) -> Result<Address, bitcoincore_rpc::Error> {
+    let configured = session.mine_to_address.borrow().clone();
+    match configured {
+        Some(address) => Ok(address),
+        None => Ok(session.rpc_client.get_new_address(None, None)?.assume_checked()),
+    }
+}
+
+// Startup check that the node itself accepts the --mine-to-address, so auto-mine can't fail on its first tick
+pub fn validate_mining_address(session: &Session, address: &Address) -> Result<(), String> {
+    let validation = session.rpc_client.call::<serde_json::Value>("validateaddress", &[json!(address)])
+        .map_err(|e| format!("could not validate {} with the node: {}", address, e))?;
+    if validation["isvalid"].as_bool() != Some(true) {
+        let reason = validation["error"].as_str().unwrap_or("rejected by the node");
+        return Err(format!("{} cannot be mined to: {}", address, reason));
+    }
+
+    match session.rpc_client.get_address_info(address) {
+        Ok(info) if info.is_mine != Some(true) => {
+            out!(session, "Note: --mine-to-address {} is not a wallet address, block rewards will not show in the balance", address)
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Called after every successful send. --auto-mine-on-send mines the whole mempool, the send included,
+// otherwise --confirm-sends mines one block so it confirms immediately
+pub fn confirm_send(session: &Session) {
+    if session.auto_mine_on_send {
+        generate_blocks_if_required(session, true);
+        return;
+    }
+    if !session.confirm_sends || !session.is_regtest() {
+        return;
+    }
+
+    let mined = mining_address(session).and_then(|address| session.rpc_client.generate_to_address(1, &address));
+    match mined {
+        Ok(hashes) => {
+            session.record_blocks_mined(hashes.len());
+            for hash in &hashes {
+                out!(session, "Confirmed in block {}", hash);
+            }
+            session.out.json(&json!({ "confirming_blocks": hashes }));
+        }
+        Err(e) => err!(session, "Failed to mine confirming block. Error {:?}", e)
+    }
+}
+
+// Work done on every tick of the mine timer, in both the prompt and the dashboard
+pub fn on_mine_timer(session: &mut Session) {
+    // Only the restart warning matters here, a failed call shows up in the work below anyway
+    let _ = session.rpc_client.check_uptime();
+    if session.auto_mine {
+        generate_blocks_if_required(session, false);
+    }
+    poll_watches(session);
+    poll_peer_count(session);
+}
+
+// The auto-mine path shouldn't give up on an exhausted keypool or an address the node stopped accepting,
+// so each of those gets one recovery attempt before the error is reported
+fn mine_block_with_recovery(session: &Session) -> Result<Vec<BlockHash>, bitcoincore_rpc::Error> {
+    let rpc_client = &session.rpc_client;
+    let address = match mining_address(session) {
+        Err(e) if rpc_error_code(&e) == Some(RPC_WALLET_KEYPOOL_RAN_OUT) => {
+            out!(session, "Keypool ran out while getting a mining address, refilling it and retrying");
+            rpc_client.key_pool_refill(None)?;
+            mining_address(session)?
+        }
+        result => result?,
+    };
+
+    match rpc_client.generate_to_address(1, &address) {
+        Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => {
+            let fresh_address = rpc_client.get_new_address(None, None)?.assume_checked();
+            out!(
+                session,
+                "Node rejected mining address {} ({}), retrying once with fresh address {}",
+                address, rpc_error_message(&e).unwrap_or("invalid address"), fresh_address
+            );
+            // The configured address would be rejected the same way on every later block
+            if session.mine_to_address.borrow().as_ref() == Some(&address) {
+                session.mine_to_address.replace(None);
+                err!(session, "Dropped --mine-to-address {}, mining to a fresh wallet address per block from now on", address);
+            }
+            rpc_client.generate_to_address(1, &fresh_address)
+        }
+        result => result,
+    }
+}
+
+pub fn generate_blocks_if_required(session: &Session, do_print: bool) {
+    let rpc_client = &session.rpc_client;
+    conditional_print!(session, do_print, "Checking for new transactions");
+
+    match rpc_client.get_raw_mempool() {
+        Ok(pending_transactions) => {
+            if !pending_transactions.is_empty() {
+                conditional_print!(session, do_print, "Found new transactions, generating block");
+                // If there are pending transactions, generate 1 block (bitcoin core should automatically mine the transactions in the mempool)
+                match mine_block_with_recovery(session) {
+                    Ok(hashes) => {
+                        session.record_blocks_mined(hashes.len());
+                        conditional_print!(session, do_print, "Generated and sent new block. Transaction count: {}", pending_transactions.len());
+                        session.out.json(&json!({ "blocks": hashes, "transactions": pending_transactions.len() }));
+                    }
+                    Err(e) => err!(session, "Error generating block {e}")
+                }
+            } else {
+                conditional_print!(session, do_print, "No new transactions found");
+            }
+        }
+        Err(e) => err!(session, "Error getting transactions from mempool {}", e)
+    }
+}
+
+pub fn generate_blocks(session: &Session, count: u64) {
+    match mining_address(session) {
+        Ok(address) => generate_blocks_to(session, count, &address),
+        Err(e) => err!(session, "Failed to get new address. Error {:?}", e)
+    }
+}
+
+fn generate_blocks_to(session: &Session, count: u64, address: &Address) {
+    let rpc_client = &session.rpc_client;
+    let mut hashes = Vec::new();
+    while (hashes.len() as u64) < count {
+        let batch = GENERATE_BATCH_SIZE.min(count - hashes.len() as u64);
+        match rpc_client.generate_to_address(batch, address) {
+            Ok(batch_hashes) => {
+                session.record_blocks_mined(batch_hashes.len());
+                hashes.extend(batch_hashes);
+            }
+            Err(e) => {
+                err!(session, "Error generating blocks after {} of {}. Error {:?}", hashes.len(), count, e);
+                return;
+            }
+        }
+        session.out.progress("Mined blocks", hashes.len() as u64, count);
+    }
+
+    out!(session, "Generated {} blocks to {}", hashes.len(), address);
+    session.out.json(&json!({ "blocks": hashes, "address": address }));
+}
+
+pub fn mine_to_height(session: &Session, target_height: u64) {
+    if !session.require_regtest("minetoheight") {
+        return;
+    }
+
+    let start_height = match session.rpc_client.get_block_count() {
+        Ok(height) => height,
+        Err(e) => {
+            err!(session, "Failed to get block count. Error {:?}", e);
+            return;
+        }
+    };
+    if start_height > target_height {
+        err!(session, "Chain is already at height {}, past the target {}", start_height, target_height);
+        return;
+    }
+    if start_height == target_height {
+        out!(session, "Chain is already at height {}", start_height);
+        return;
+    }
+
+    let count = target_height - start_height;
+    if count > MAX_FAST_FORWARD_BLOCKS {
+        err!(session, "Reaching height {} needs {} blocks, more than the limit of {} per minetoheight", target_height, count, MAX_FAST_FORWARD_BLOCKS);
+        return;
+    }
+
+    out!(session, "Mining {} block(s) from height {} to {}", count, start_height, target_height);
+    generate_blocks(session, count);
+    match session.rpc_client.get_block_count() {
+        Ok(end_height) => {
+            out!(session, "Chain height is now {} (started at {})", end_height, start_height);
+            session.out.json(&json!({ "start_height": start_height, "end_height": end_height, "target_height": target_height }));
+        }
+        Err(e) => err!(session, "Failed to get block count. Error {:?}", e)
+    }
+}
+
+// Ctrl-C once simulate has taken the signal over. Until the first simulation the default handler is
+// left alone; tokio can't hand the signal back afterwards, so from then on Ctrl-C outside a simulation
+// wakes the main loop to quit through end_session instead
+#[derive(Default)]
+pub struct InterruptTrap {
+    simulating: AtomicBool,
+    interrupted: AtomicBool,
+    pub quit: Notify,
+}
+
+fn trap_interrupts(session: &mut Session) -> Arc<InterruptTrap> {
+    session.interrupt_trap.get_or_insert_with(|| {
+        let trap = Arc::new(InterruptTrap::default());
+        let listener = trap.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if listener.simulating.load(Ordering::SeqCst) {
+                    listener.interrupted.store(true, Ordering::SeqCst);
+                } else {
+                    listener.quit.notify_one();
+                }
+            }
+        });
+        trap
+    }).clone()
+}
+
+// Sleeps in short steps so Ctrl-C is noticed promptly, false if it was pressed
+fn sleep_unless_interrupted(trap: &InterruptTrap, duration: Duration) -> bool {
+    let deadline = std::time::Instant::now() + duration;
+    while !trap.interrupted.load(Ordering::SeqCst) {
+        let now = std::time::Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(INTERRUPT_POLL_INTERVAL.min(deadline - now));
+    }
+    false
+}
+
+// Mines one block at a time for duration_secs of wall-clock time, with exponentially distributed gaps
+// averaging interval_secs, the way proof-of-work block arrivals are spread on a real network
+pub fn simulate_blocks(session: &mut Session, duration_secs: u64, interval_secs: u64) {
+    if !session.require_regtest("simulate") {
+        return;
+    }
+
+    let trap = trap_interrupts(session);
+    trap.interrupted.store(false, Ordering::SeqCst);
+    trap.simulating.store(true, Ordering::SeqCst);
+    out!(session, "Simulating {}s of blocks, one every {}s on average (Ctrl-C to stop)", duration_secs, interval_secs);
+
+    let started = std::time::Instant::now();
+    let end = started + Duration::from_secs(duration_secs);
+    let mut blocks = 0u64;
+    let mut interrupted = false;
+    loop {
+        // 1 - U is in (0, 1], so the logarithm is finite
+        let gap = -(1.0 - session.rng.borrow_mut().gen::<f64>()).ln() * interval_secs as f64;
+        let next_block = std::time::Instant::now() + Duration::from_secs_f64(gap);
+        if next_block > end {
+            interrupted = !sleep_unless_interrupted(&trap, end.saturating_duration_since(std::time::Instant::now()));
+            break;
+        }
+        if !sleep_unless_interrupted(&trap, next_block.saturating_duration_since(std::time::Instant::now())) {
+            interrupted = true;
+            break;
+        }
+
+        match mine_block_with_recovery(session) {
+            Ok(hashes) => {
+                session.record_blocks_mined(hashes.len());
+                blocks += hashes.len() as u64;
+                if let Some(hash) = hashes.first() {
+                    out!(session, "Block {} after {:.1}s: {}", blocks, gap, hash);
+                }
+            }
+            Err(e) => {
+                err!(session, "Error mining block after {} block(s). Error {:?}", blocks, e);
+                break;
+            }
+        }
+    }
+    trap.simulating.store(false, Ordering::SeqCst);
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let effective_interval = (blocks > 0).then(|| elapsed / blocks as f64);
+    if interrupted {
+        out!(session, "Simulation interrupted after {:.1}s", elapsed);
+    }
+    match effective_interval {
+        Some(secs) => out!(session, "Mined {} block(s) in {:.1}s, one every {:.1}s (target {}s)", blocks, elapsed, secs, interval_secs),
+        None => out!(session, "Mined no blocks in {:.1}s (target one every {}s)", elapsed, interval_secs),
+    }
+    session.out.json(&json!({
+        "blocks": blocks,
+        "elapsed_secs": elapsed,
+        "target_interval_secs": interval_secs,
+        "effective_interval_secs": effective_interval,
+        "interrupted": interrupted,
+    }));
+}
+
+// Invalidates block 1, which drops the whole chain back to genesis, then mines a fresh chain to one fixed address
+pub fn reset_chain(session: &Session, length: u64, address_string: Option<&str>) {
+    if !session.allow_chain_reset {
+        err!(session, "resetchain discards the whole chain, start with --allow-chain-reset to enable it");
+        return;
+    }
+    if !session.require_regtest("resetchain") {
+        return;
+    }
+
+    let address = match address_string {
+        Some(address_string) => match session.resolve_address(address_string) {
+            Ok(address) => address,
+            Err(e) => {
+                err!(session, "{}", e);
+                return;
+            }
+        },
+        None => match session.mine_to_address.borrow().clone() {
+            Some(address) => address,
+            None => {
+                err!(session, "resetchain needs a fixed address for a repeatable chain, pass one or start with --mine-to-address");
+                return;
+            }
+        },
+    };
+
+    let rpc_client = &session.rpc_client;
+    match rpc_client.get_block_count() {
+        Ok(0) => out!(session, "Chain is already at genesis"),
+        Ok(height) => {
+            let invalidated = rpc_client.get_block_hash(1).and_then(|hash| rpc_client.invalidate_block(&hash));
+            if let Err(e) = invalidated {
+                err!(session, "Failed to invalidate the chain. Error {:?}", e);
+                return;
+            }
+            out!(session, "Invalidated {} block(s), back at genesis", height);
+        }
+        Err(e) => {
+            err!(session, "Failed to get block count. Error {:?}", e);
+            return;
+        }
+    }
+
+    // Transactions from the invalidated blocks are back in the mempool and end up in the new chain
+    generate_blocks_to(session, length, &address);
+    match rpc_client.get_best_block_hash().and_then(|hash| Ok((rpc_client.get_block_count()?, hash))) {
+        Ok((height, tip)) => {
+            out!(session, "New tip: {} at height {}", tip, height);
+            session.out.json(&json!({ "height": height, "tip": tip, "address": address }));
+        }
+        Err(e) => err!(session, "Failed to get the new tip. Error {:?}", e)
+    }
+}
+
+pub fn startup_generate(session: &Session, count: u64, min_height: Option<u64>) {
+    if let Some(min_height) = min_height {
+        match session.rpc_client.get_block_count() {
+            Ok(height) if height >= min_height => {
+                out!(session, "Chain height {} already reaches {}, skipping startup generation", height, min_height);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                err!(session, "Failed to get block count, skipping startup generation. Error {:?}", e);
+                return;
+            }
+        }
+    }
+
+    generate_blocks(session, count);
+    check_block_count(session);
+    match session.rpc_client.get_balances() {
+        Ok(balances) => out!(session, "Spendable balance: {}", balances.mine.trusted),
+        Err(e) => err!(session, "Failed to get balances. Error {:?}", e)
+    }
+}
+
+fn coinbase_script_sig(height: u64, message: &str) -> Result<ScriptBuf, MinerError> {
+    let too_long = |len: usize| MinerError::BlockConstruction(format!(
+        "message is too long: the coinbase scriptSig would be {} bytes, the limit is {}",
+        len, MAX_COINBASE_SCRIPT_SIG_LEN
+    ));
+
+    let tag = PushBytesBuf::try_from(message.as_bytes().to_vec()).map_err(|_| too_long(message.len()))?;
+    let script_sig = Builder::new().push_int(height as i64).push_slice(tag).into_script();
+    if script_sig.len() > MAX_COINBASE_SCRIPT_SIG_LEN {
+        return Err(too_long(script_sig.len()));
+    }
+
+    Ok(script_sig)
+}
+
+// generatetoaddress can't set the coinbase scriptSig, so build the block from a template and grind the nonce ourselves
+fn build_tagged_block(session: &Session, message: &str, address: &Address) -> Result<Block, MinerError> {
+    let template = session.rpc_client.get_block_template(GetBlockTemplateModes::Template, &[GetBlockTemplateRules::SegWit], &[])?;
+    let script_sig = coinbase_script_sig(template.height, message)?;
+
+    let mut coinbase_outputs = vec![TxOut { value: template.coinbase_value, script_pubkey: address.script_pubkey() }];
+    let mut coinbase_witness = Witness::new();
+    // The node's commitment assumes the template's transactions in order and an all-zero witness reserved value
+    if !template.default_witness_commitment.is_empty() {
+        coinbase_outputs.push(TxOut { value: Amount::ZERO, script_pubkey: template.default_witness_commitment.clone() });
+        coinbase_witness.push([0u8; 32]);
+    }
+
+    let coinbase = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence: Sequence::MAX,
+            witness: coinbase_witness,
+        }],
+        output: coinbase_outputs,
+    };
+
+    let mut txdata = vec![coinbase];
+    for template_tx in &template.transactions {
+        let tx = deserialize::<Transaction>(&template_tx.raw_tx)
+            .map_err(|e| MinerError::BlockConstruction(format!("template transaction {} is invalid: {}", template_tx.txid, e)))?;
+        txdata.push(tx);
+    }
+
+    let bits = <[u8; 4]>::try_from(template.bits.as_slice())
+        .map_err(|_| MinerError::BlockConstruction("template has malformed bits".to_string()))?;
+    let mut block = Block {
+        header: Header {
+            version: BlockVersion::from_consensus(template.version as i32),
+            prev_blockhash: template.previous_block_hash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: template.current_time as u32,
+            bits: CompactTarget::from_consensus(u32::from_be_bytes(bits)),
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.compute_merkle_root()
+        .ok_or_else(|| MinerError::BlockConstruction("block has no transactions".to_string()))?;
+
+    let target = block.header.target();
+    while block.header.validate_pow(target).is_err() {
+        block.header.nonce = block.header.nonce.checked_add(1)
+            .ok_or_else(|| MinerError::BlockConstruction("exhausted the nonce range".to_string()))?;
+    }
+
+    Ok(block)
+}
+
+pub fn generate_tagged_blocks(session: &Session, count: u64, message: &str) {
+    // Nonce grinding on the CPU is only practical at regtest difficulty
+    if !session.require_regtest("generatetagged") {
+        return;
+    }
+
+    let address = match mining_address(session) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "Failed to get new address. Error {:?}", e);
+            return;
+        }
+    };
+
+    let mut hashes = Vec::new();
+    while (hashes.len() as u64) < count {
+        let submitted = build_tagged_block(session, message, &address).and_then(|block| {
+            session.rpc_client.submit_block(&block)?;
+            Ok(block.block_hash())
+        });
+
+        match submitted {
+            Ok(hash) => {
+                session.record_blocks_mined(1);
+                out!(session, "{}", hash);
+                hashes.push(hash);
+            }
+            Err(e) => {
+                err!(session, "Failed to mine tagged block after {} of {}. Error {}", hashes.len(), count, e);
+                return;
+            }
+        }
+    }
+
+    out!(session, "Generated {} block(s) tagged '{}' to {}", hashes.len(), message, address);
+    session.out.json(&json!({ "blocks": hashes, "address": address, "message": message }));
+}
+
+// Runtime changes made with the automine command
+pub enum AutoMineChange {
+    On,
+    Off,
+    Interval(u64),
+}
+
+pub fn show_auto_mine(session: &Session) {
+    let mode = match (session.auto_mine, session.auto_mine_on_send) {
+        (true, _) => "on (timer, when the mempool has transactions)",
+        (false, true) => "on send (after each successful send, never on the timer)",
+        (false, false) => "off",
+    };
+    let mine_to_address = session.mine_to_address.borrow().clone();
+    let target = mine_to_address.as_ref()
+        .map_or("fresh wallet address per block".to_string(), |address| address.to_string());
+    out!(session, "Auto-mine: {}", mode);
+    out!(session, "Interval: {}s +/- {}s jitter", session.mine_interval_secs, session.mine_interval_jitter);
+    out!(session, "Mines to: {}", target);
+    out!(session, "Blocks per tick: 1");
+    session.out.json(&json!({
+        "auto_mine": session.auto_mine,
+        "auto_mine_on_send": session.auto_mine_on_send,
+        "interval_secs": session.mine_interval_secs,
+        "jitter_secs": session.mine_interval_jitter,
+        "mine_to_address": mine_to_address,
+    }));
+}
+
+pub fn change_auto_mine(session: &mut Session, change: AutoMineChange) {
+    match change {
+        AutoMineChange::On => {
+            // Same conflict as --auto-mine with --auto-mine-on-send
+            if session.auto_mine_on_send {
+                session.auto_mine_on_send = false;
+                out!(session, "Mining on send turned off, the timer mines instead");
+            }
+            session.auto_mine = true;
+            out!(session, "Auto-mine on, every {}s", session.mine_interval_secs);
+        }
+        AutoMineChange::Off => {
+            session.auto_mine = false;
+            session.auto_mine_on_send = false;
+            out!(session, "Auto-mine off");
+        }
+        AutoMineChange::Interval(secs) => {
+            let previous = std::mem::replace(&mut session.mine_interval_secs, secs);
+            session.mine_schedule_changed = true;
+            out!(session, "Auto-mine interval {}s -> {}s, next tick rescheduled", previous, secs);
+        }
+    }
+    session.out.json(&json!({
+        "auto_mine": session.auto_mine,
+        "auto_mine_on_send": session.auto_mine_on_send,
+        "interval_secs": session.mine_interval_secs,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+
+    use super::*;
+    use crate::testing::{test_session, ScriptedNode};
+
+    const BLOCK_HASH: &str = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+
+    fn regtest_address() -> Address {
+        Address::p2wsh(&ScriptBuf::new(), Network::Regtest)
+    }
+
+    #[test]
+    fn refills_exhausted_keypool_once() {
+        let node = ScriptedNode::default();
+        node.fail("getnewaddress", RPC_WALLET_KEYPOOL_RAN_OUT, "Error: Keypool ran out, please call keypoolrefill first")
+            .respond("getnewaddress", json!(regtest_address()));
+        node.respond("keypoolrefill", json!(null));
+        node.respond("generatetoaddress", json!([BLOCK_HASH]));
+        let (session, out) = test_session(&node);
+
+        let hashes = mine_block_with_recovery(&session).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(node.calls(), ["getnewaddress", "keypoolrefill", "getnewaddress", "generatetoaddress"]);
+        assert!(out.text().contains("refilling it and retrying"));
+    }
+
+    #[test]
+    fn retries_rejected_address_once_with_a_fresh_one() {
+        let node = ScriptedNode::default();
+        node.respond("getnewaddress", json!(regtest_address()));
+        node.fail("generatetoaddress", RPC_INVALID_ADDRESS_OR_KEY, "Error: Invalid address")
+            .respond("generatetoaddress", json!([BLOCK_HASH]));
+        let (session, out) = test_session(&node);
+
+        let hashes = mine_block_with_recovery(&session).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(node.calls(), ["getnewaddress", "generatetoaddress", "getnewaddress", "generatetoaddress"]);
+        assert!(out.text().contains("retrying once with fresh address"));
+    }
+
+    #[test]
+    fn drops_a_rejected_mine_to_address_after_the_first_rejection() {
+        let node = ScriptedNode::default();
+        node.respond("getnewaddress", json!(regtest_address()));
+        node.fail("generatetoaddress", RPC_INVALID_ADDRESS_OR_KEY, "Error: Invalid address")
+            .respond("generatetoaddress", json!([BLOCK_HASH]));
+        let (session, out) = test_session(&node);
+        let configured = Address::p2wsh(&ScriptBuf::from_bytes(vec![0x51]), Network::Regtest);
+        session.mine_to_address.replace(Some(configured));
+
+        mine_block_with_recovery(&session).unwrap();
+        mine_block_with_recovery(&session).unwrap();
+
+        assert!(session.mine_to_address.borrow().is_none());
+        assert_eq!(out.error_text().matches("Dropped --mine-to-address").count(), 1, "{}", out.error_text());
+        assert_eq!(node.calls(), ["generatetoaddress", "getnewaddress", "generatetoaddress", "getnewaddress", "generatetoaddress"]);
+    }
+
+    #[test]
+    fn gives_up_after_one_retry() {
+        let node = ScriptedNode::default();
+        node.respond("getnewaddress", json!(regtest_address()));
+        node.fail("generatetoaddress", RPC_INVALID_ADDRESS_OR_KEY, "Error: Invalid address");
+        let (session, _) = test_session(&node);
+
+        let result = mine_block_with_recovery(&session);
+        assert_eq!(rpc_error_code(&result.unwrap_err()), Some(RPC_INVALID_ADDRESS_OR_KEY));
+        assert_eq!(node.count("generatetoaddress"), 2);
+    }
+
+    #[test]
+    fn quiet_auto_mine_still_reports_failures() {
+        let node = ScriptedNode::default();
+        node.fail("getrawmempool", -1, "mempool unavailable")
+            .respond("getrawmempool", json!(["11".repeat(32)]));
+        node.respond("getnewaddress", json!(regtest_address()));
+        node.fail("generatetoaddress", -1, "Error: mining failed");
+        let (session, out) = test_session(&node);
+
+        generate_blocks_if_required(&session, false);
+        generate_blocks_if_required(&session, false);
+
+        assert!(out.human.borrow().is_empty(), "{}", out.text());
+        assert!(out.error_text().contains("Error getting transactions from mempool"), "{}", out.error_text());
+        assert!(out.error_text().contains("Error generating block"), "{}", out.error_text());
+    }
+}
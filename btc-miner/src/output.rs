@@ -0,0 +1,245 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, IsTerminal, Write};
+
+// Every command writes through this so formatting is decided in one place
+pub trait Output {
+    fn human(&self, message: &str);
+    fn json(&self, value: &serde_json::Value);
+    fn error(&self, message: &str);
+    // label names what is being counted, e.g. "Mined blocks"
+    fn progress(&self, label: &str, done: u64, total: u64);
+}
+
+// Where an Output writes one of its streams: stdout/stderr normally, a buffer in tests
+pub type Writer = RefCell<Box<dyn Write>>;
+
+fn write_line(writer: &Writer, text: &str) {
+    let mut writer = writer.borrow_mut();
+    // A closed pipe shouldn't take the session down with it
+    let _ = writeln!(writer, "{}", text);
+    let _ = writer.flush();
+}
+
+// Default output: text on stdout, errors on stderr, structured results dropped
+pub struct StdoutOutput {
+    stdout: Writer,
+    stderr: Writer,
+    // \r progress lines only make sense on a terminal
+    show_progress: bool,
+    // Set while a \r progress line is on screen so the next line starts fresh
+    progress_open: Cell<bool>,
+}
+
+impl Default for StdoutOutput {
+    fn default() -> Self {
+        StdoutOutput::new(Box::new(io::stdout()), Box::new(io::stderr()), io::stdout().is_terminal())
+    }
+}
+
+impl StdoutOutput {
+    pub fn new(stdout: Box<dyn Write>, stderr: Box<dyn Write>, show_progress: bool) -> Self {
+        StdoutOutput { stdout: RefCell::new(stdout), stderr: RefCell::new(stderr), show_progress, progress_open: Cell::new(false) }
+    }
+
+    fn close_progress(&self) {
+        if self.progress_open.replace(false) {
+            write_line(&self.stdout, "");
+        }
+    }
+}
+
+impl Output for StdoutOutput {
+    fn human(&self, message: &str) {
+        self.close_progress();
+        write_line(&self.stdout, message);
+    }
+
+    fn json(&self, _value: &serde_json::Value) {}
+
+    fn error(&self, message: &str) {
+        self.close_progress();
+        write_line(&self.stderr, message);
+    }
+
+    fn progress(&self, label: &str, done: u64, total: u64) {
+        if !self.show_progress {
+            return;
+        }
+
+        let mut stdout = self.stdout.borrow_mut();
+        let _ = write!(stdout, "\r{}: {}/{}", label, done, total);
+        if done >= total {
+            let _ = writeln!(stdout);
+        }
+        let _ = stdout.flush();
+        self.progress_open.set(done < total);
+    }
+}
+
+// --json: one JSON value per line on stdout, human text moved to stderr so it can't corrupt the stream
+pub struct JsonOutput {
+    stdout: Writer,
+    stderr: Writer,
+}
+
+impl Default for JsonOutput {
+    fn default() -> Self {
+        JsonOutput::new(Box::new(io::stdout()), Box::new(io::stderr()))
+    }
+}
+
+impl JsonOutput {
+    pub fn new(stdout: Box<dyn Write>, stderr: Box<dyn Write>) -> Self {
+        JsonOutput { stdout: RefCell::new(stdout), stderr: RefCell::new(stderr) }
+    }
+}
+
+impl Output for JsonOutput {
+    fn human(&self, message: &str) {
+        write_line(&self.stderr, message);
+    }
+
+    fn json(&self, value: &serde_json::Value) {
+        write_line(&self.stdout, &value.to_string());
+    }
+
+    fn error(&self, message: &str) {
+        write_line(&self.stderr, message);
+        write_line(&self.stdout, &serde_json::json!({ "error": message }).to_string());
+    }
+
+    fn progress(&self, label: &str, done: u64, total: u64) {
+        write_line(&self.stdout, &serde_json::json!({ "progress": label, "done": done, "total": total }).to_string());
+    }
+}
+
+macro_rules! out {
+    ($session:expr, $($arg:tt)*) => {
+        $session.out.human(&format!($($arg)*))
+    };
+}
+
+macro_rules! err {
+    ($session:expr, $($arg:tt)*) => {
+        $session.out.error(&format!($($arg)*))
+    };
+}
+
+macro_rules! conditional_print {
+    ($session:expr, $condition:expr, $($arg:tt)*) => {
+        if $condition {
+            out!($session, $($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::commands::handle_input_line;
+    use crate::testing::{test_session, ScriptedNode};
+
+    // A Vec<u8> the test keeps a handle to after the output takes ownership of its writer
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn stdout_output(show_progress: bool) -> (StdoutOutput, Captured, Captured) {
+        let (stdout, stderr) = (Captured::default(), Captured::default());
+        (StdoutOutput::new(Box::new(stdout.clone()), Box::new(stderr.clone()), show_progress), stdout, stderr)
+    }
+
+    #[test]
+    fn stdout_output_splits_text_and_errors() {
+        let (output, stdout, stderr) = stdout_output(false);
+        output.human("Current block count: 101");
+        output.error("Error: wallet not loaded");
+        output.json(&json!({ "height": 101 }));
+
+        assert_eq!(stdout.text(), "Current block count: 101\n");
+        assert_eq!(stderr.text(), "Error: wallet not loaded\n");
+    }
+
+    #[test]
+    fn stdout_output_ends_an_open_progress_line_before_the_next_message() {
+        let (output, stdout, _) = stdout_output(true);
+        output.progress("Mined blocks", 3, 10);
+        output.human("Interrupted");
+        output.progress("Synced blocks", 10, 10);
+
+        assert_eq!(stdout.text(), "\rMined blocks: 3/10\nInterrupted\n\rSynced blocks: 10/10\n");
+    }
+
+    #[test]
+    fn stdout_output_skips_progress_off_a_terminal() {
+        let (output, stdout, _) = stdout_output(false);
+        output.progress("Mined blocks", 3, 10);
+
+        assert_eq!(stdout.text(), "");
+    }
+
+    #[test]
+    fn json_output_keeps_stdout_machine_readable() {
+        let (stdout, stderr) = (Captured::default(), Captured::default());
+        let output = JsonOutput::new(Box::new(stdout.clone()), Box::new(stderr.clone()));
+        output.human("Current block count: 101");
+        output.json(&json!({ "height": 101 }));
+        output.progress("Mined blocks", 10, 10);
+        output.error("Error: wallet not loaded");
+
+        assert_eq!(
+            stdout.text(),
+            "{\"height\":101}\n{\"done\":10,\"progress\":\"Mined blocks\",\"total\":10}\n{\"error\":\"Error: wallet not loaded\"}\n"
+        );
+        assert_eq!(stderr.text(), "Current block count: 101\nError: wallet not loaded\n");
+    }
+
+    #[test]
+    fn commands_write_through_the_session_output() {
+        let node = ScriptedNode::default();
+        let (mut session, _) = test_session(&node);
+        let (output, stdout, stderr) = stdout_output(false);
+        session.out = Rc::new(output);
+
+        assert!(handle_input_line(&mut session, "help generate".to_string()));
+        assert!(!handle_input_line(&mut session, "frobnicate".to_string()));
+
+        assert!(stdout.text().starts_with("usage: generate <count>\n"));
+        assert_eq!(stderr.text(), "Invalid command, type help for a list of commands\n");
+        assert!(node.calls().is_empty());
+    }
+
+    #[test]
+    fn rpc_failures_reach_the_json_error_stream() {
+        let node = ScriptedNode::default();
+        node.fail("uptime", -1, "uptime is unavailable");
+        let (mut session, _) = test_session(&node);
+        let (stdout, stderr) = (Captured::default(), Captured::default());
+        session.out = Rc::new(JsonOutput::new(Box::new(stdout.clone()), Box::new(stderr.clone())));
+
+        handle_input_line(&mut session, "uptime".to_string());
+
+        let error: serde_json::Value = serde_json::from_str(stdout.text().lines().last().unwrap()).unwrap();
+        assert!(error["error"].as_str().unwrap().starts_with("Failed to get uptime"));
+    }
+}
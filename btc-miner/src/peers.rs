@@ -0,0 +1,116 @@
+use bitcoincore_rpc::RpcApi;
+use serde_json::json;
+
+use crate::rpc::rpc_error_code;
+use crate::session::Session;
+
+// disconnectnode reports RPC_CLIENT_NODE_NOT_CONNECTED for unknown peers
+const RPC_CLIENT_NODE_NOT_CONNECTED: i32 = -29;
+// Peers this many blocks below our height are flagged in peerheights
+const PEER_BEHIND_THRESHOLD: i64 = 6;
+
+pub fn set_network_active(session: &Session, active: bool) {
+    if let Err(e) = session.rpc_client.set_network_active(active) {
+        err!(session, "Failed to set network active state. Error {:?}", e);
+        return;
+    }
+
+    // Read the state back rather than trusting the setter's return value
+    match session.rpc_client.get_network_info() {
+        Ok(info) => {
+            out!(
+                session,
+                "Networking is now {} ({} connection(s))",
+                if info.network_active { "on" } else { "off" },
+                info.connections
+            );
+            session.out.json(&json!({ "network_active": info.network_active, "connections": info.connections }));
+        }
+        Err(e) => err!(session, "Failed to read back network state. Error {:?}", e)
+    }
+}
+
+// A peer given as a numeric id (from getpeerinfo) or a host:port address
+pub enum PeerRef<'a> {
+    Id(u32),
+    Address(&'a str),
+}
+
+pub fn parse_peer_ref(arg: &str) -> Result<PeerRef<'_>, String> {
+    if let Ok(id) = arg.parse::<u32>() {
+        return Ok(PeerRef::Id(id));
+    }
+
+    match arg.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(PeerRef::Address(arg)),
+        _ => Err(format!("Invalid peer '{}', expected a numeric peer id or host:port", arg)),
+    }
+}
+
+pub fn disconnect_peer(session: &Session, peer: PeerRef) {
+    let (result, peer_name) = match peer {
+        PeerRef::Id(id) => (session.rpc_client.disconnect_node_by_id(id), format!("peer {}", id)),
+        PeerRef::Address(address) => (session.rpc_client.disconnect_node(address), address.to_string()),
+    };
+
+    match result {
+        Ok(()) => {
+            out!(session, "Disconnected {}", peer_name);
+            session.out.json(&json!({ "disconnected": peer_name }));
+        }
+        Err(e) if rpc_error_code(&e) == Some(RPC_CLIENT_NODE_NOT_CONNECTED) => out!(session, "{} is not connected", peer_name),
+        Err(e) => err!(session, "Failed to disconnect {}. Error {:?}", peer_name, e)
+    }
+}
+
+// Each peer's last block and header we know they have, against our own height
+pub fn show_peer_heights(session: &Session) {
+    let rpc_client = &session.rpc_client;
+    let fetched = rpc_client.get_block_count().and_then(|height| Ok((height as i64, rpc_client.get_peer_info()?)));
+    let (local_height, peers) = match fetched {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            err!(session, "Failed to get peer info. Error {:?}", e);
+            return;
+        }
+    };
+
+    if peers.is_empty() {
+        out!(session, "No peers connected, local height {}", local_height);
+        session.out.json(&json!({ "local_height": local_height, "peers": [] }));
+        return;
+    }
+
+    let addr_width = peers.iter().map(|peer| peer.addr.len()).max().unwrap_or(0).max("address".len());
+    out!(session, "Local height: {}", local_height);
+    out!(session, "{:>5}  {:<width$}  {:>8}  {:>8}  {:>6}", "id", "address", "blocks", "headers", "diff", width = addr_width);
+    let mut rows = Vec::new();
+    for peer in &peers {
+        // -1 until the peer has announced anything we could match
+        let known = peer.synced_blocks >= 0;
+        let diff = peer.synced_blocks - local_height;
+        let status = match known {
+            false => "unknown",
+            true if diff <= -PEER_BEHIND_THRESHOLD => "BEHIND",
+            true if diff > 0 => "ahead",
+            true => "",
+        };
+        let diff_text = if known { format!("{:+}", diff) } else { "-".to_string() };
+        out!(
+            session,
+            "{:>5}  {:<width$}  {:>8}  {:>8}  {:>6}  {}",
+            peer.id, peer.addr, peer.synced_blocks, peer.synced_headers, diff_text, status,
+            width = addr_width
+        );
+        rows.push(json!({
+            "id": peer.id,
+            "addr": peer.addr,
+            "synced_blocks": peer.synced_blocks,
+            "synced_headers": peer.synced_headers,
+            "diff": known.then_some(diff),
+            "behind": known && diff <= -PEER_BEHIND_THRESHOLD,
+        }));
+    }
+
+    session.out.json(&json!({ "local_height": local_height, "peers": rows }));
+}
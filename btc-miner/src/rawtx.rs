@@ -0,0 +1,1005 @@
+use std::collections::HashMap;
+use std::fs;
+
+use bitcoin::{
+    absolute, transaction, Address, Amount, BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid, Witness, Wtxid,
+};
+use bitcoin::consensus::encode::{deserialize_hex, serialize_hex};
+use bitcoincore_rpc::json::{CreateRawTransactionInput, FundRawTransactionOptions};
+use bitcoincore_rpc::{RawTx, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::amount::{check_dust, format_btc_value};
+use crate::error::MinerError;
+use crate::mempool::RPC_INVALID_ADDRESS_OR_KEY;
+use crate::mining::confirm_send;
+use crate::rpc::{rpc_error_code, MinerClient};
+use crate::session::Session;
+
+// A wallet-funded and signed transaction that has not been broadcast yet
+pub struct FundedTransaction {
+    pub tx: Transaction,
+    pub fee: Amount,
+}
+
+impl FundedTransaction {
+    // sat/vB, computed from the signed size so witness data is accounted for
+    pub fn fee_rate(&self) -> f64 {
+        self.fee.to_sat() as f64 / self.tx.vsize() as f64
+    }
+}
+
+// Converts a sat/vB rate to the BTC/kvB amount fundrawtransaction expects
+pub fn fee_rate_to_btc_per_kvb(fee_rate: f64) -> Amount {
+    Amount::from_sat((fee_rate * 1000.0).round() as u64)
+}
+
+// Coin control building block: the wallet adds inputs and change as needed, then signs
+pub fn build_funded_transaction(
+    rpc_client: &MinerClient,
+    inputs: &[CreateRawTransactionInput],
+    outputs: &HashMap<String, Amount>,
+    options: &FundRawTransactionOptions,
+) -> Result<FundedTransaction, MinerError> {
+    let unfunded = rpc_client.create_raw_transaction_hex(inputs, outputs, None, None)?;
+    fund_and_sign(rpc_client, unfunded, options)
+}
+
+// For transactions built locally, e.g. with outputs createrawtransaction can't express
+pub fn fund_and_sign<R: RawTx>(
+    rpc_client: &MinerClient,
+    unfunded: R,
+    options: &FundRawTransactionOptions,
+) -> Result<FundedTransaction, MinerError> {
+    let funded = rpc_client.fund_raw_transaction(unfunded, Some(options), None)?;
+    let signed = rpc_client.sign_raw_transaction_with_wallet(&funded.hex, None, None)?;
+    if !signed.complete {
+        return Err(MinerError::SigningIncomplete);
+    }
+
+    let tx = signed.transaction().map_err(|e| MinerError::InvalidTransaction(e.to_string()))?;
+    Ok(FundedTransaction { tx, fee: funded.fee })
+}
+
+// Maps the reject reasons bitcoind reports for sendrawtransaction to something readable
+fn describe_rejection(message: &str) -> &'static str {
+    if message.contains("max-fee-exceeded") || message.contains("absurdly-high-fee") {
+        "fee exceeds the node's max fee rate (retry with --allow-high-fee)"
+    } else if message.contains("txn-mempool-conflict") {
+        "conflicts with a transaction already in the mempool"
+    } else if message.contains("missingorspent") || message.contains("missing-inputs") {
+        "inputs are missing or already spent"
+    } else if message.contains("already in block chain") || message.contains("txn-already-known") {
+        "transaction is already known to the node"
+    } else if message.contains("non-final") {
+        "transaction is not final yet"
+    } else if message.contains("insufficient fee") || message.contains("min relay fee not met") {
+        "fee is too low for relay"
+    } else {
+        "rejected by node"
+    }
+}
+
+pub fn send_raw_transaction(session: &Session, tx_hex: &str, allow_high_fee: bool) {
+    if let Err(e) = deserialize_hex::<Transaction>(tx_hex) {
+        err!(session, "Error parsing raw transaction {:?}", e);
+        return;
+    }
+
+    // A max fee rate of 0 disables the node's high-fee safety check
+    let mut params = vec![tx_hex.into()];
+    if allow_high_fee {
+        params.push(0.into());
+    }
+
+    match session.rpc_client.call::<Txid>("sendrawtransaction", &params) {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            session.out.json(&json!({ "txid": tx_id }));
+        }
+        Err(e) => {
+            let message = e.to_string();
+            err!(session, "Transaction rejected: {}. Error {}", describe_rejection(&message), message)
+        }
+    }
+}
+
+pub enum BroadcastOutcome {
+    Accepted,
+    AlreadyInMempool,
+    Rejected(String),
+}
+
+// Submits one transaction to the primary node and every --node endpoint at once, so each
+// node's verdict reflects only its own mempool and chain rather than relay between them
+pub fn broadcast_to_all(session: &Session, tx_hex: &str) {
+    let tx = match deserialize_hex::<Transaction>(tx_hex) {
+        Ok(tx) => tx,
+        Err(e) => {
+            err!(session, "Error parsing raw transaction {:?}", e);
+            return;
+        }
+    };
+    let txid = tx.compute_txid();
+
+    let results = session.query_nodes(|client| {
+        if client.get_mempool_entry(&txid).is_ok() {
+            return Ok(BroadcastOutcome::AlreadyInMempool);
+        }
+        match client.send_raw_transaction(tx_hex) {
+            Ok(_) => Ok(BroadcastOutcome::Accepted),
+            // Only an answer from the node is a verdict, transport failures mean it was unreachable
+            Err(e) if rpc_error_code(&e).is_some() => {
+                let message = e.to_string();
+                Ok(BroadcastOutcome::Rejected(format!("{} ({})", describe_rejection(&message), message)))
+            }
+            Err(e) => Err(e),
+        }
+    });
+
+    let urls = session.node_urls();
+    let url_width = urls.iter().map(|url| url.len()).max().unwrap_or(0);
+    out!(session, "Broadcasting {} to {} node(s)", txid, urls.len());
+    for (url, result) in urls.iter().zip(&results) {
+        match result {
+            Ok(BroadcastOutcome::Accepted) => out!(session, "{:<width$}  ACCEPTED", url, width = url_width),
+            Ok(BroadcastOutcome::AlreadyInMempool) => out!(session, "{:<width$}  ALREADY IN MEMPOOL", url, width = url_width),
+            Ok(BroadcastOutcome::Rejected(reason)) => out!(session, "{:<width$}  REJECTED {}", url, reason, width = url_width),
+            Err(e) => out!(session, "{:<width$}  UNREACHABLE ({})", url, e, width = url_width),
+        }
+    }
+
+    let accepted = results.iter().filter(|result| matches!(result, Ok(BroadcastOutcome::Accepted))).count();
+    if accepted > 0 {
+        session.record_transaction_sent(&txid);
+    }
+    let known = accepted + results.iter().filter(|result| matches!(result, Ok(BroadcastOutcome::AlreadyInMempool))).count();
+    out!(session, "{} of {} node(s) now have {} in their mempool ({} newly accepted)", known, results.len(), txid, accepted);
+
+    let nodes: Vec<serde_json::Value> = urls.iter().zip(&results)
+        .map(|(url, result)| match result {
+            Ok(BroadcastOutcome::Accepted) => json!({ "url": url, "result": "accepted" }),
+            Ok(BroadcastOutcome::AlreadyInMempool) => json!({ "url": url, "result": "already_in_mempool" }),
+            Ok(BroadcastOutcome::Rejected(reason)) => json!({ "url": url, "result": "rejected", "reason": reason }),
+            Err(e) => json!({ "url": url, "result": "unreachable", "error": e.to_string() }),
+        })
+        .collect();
+    session.out.json(&json!({ "txid": txid, "nodes": nodes }));
+}
+
+// Several transactions are checked by the node as one package, so they may spend each other's outputs
+pub fn test_mempool_accept(session: &Session, tx_hexes: &[&str]) {
+    let mut txs = Vec::new();
+    for (index, tx_hex) in tx_hexes.iter().enumerate() {
+        match deserialize_hex::<Transaction>(tx_hex) {
+            Ok(tx) => txs.push(tx),
+            Err(e) => {
+                err!(session, "Error parsing raw transaction {} {:?}", index + 1, e);
+                return;
+            }
+        }
+    }
+
+    match session.rpc_client.test_mempool_accept(&txs.iter().collect::<Vec<_>>()) {
+        Ok(results) => {
+            for result in &results {
+                match (&result.reject_reason, &result.fees) {
+                    (_, Some(fees)) if result.allowed => out!(session, "{}  allowed  fee {}", result.txid, fees.base),
+                    (_, None) if result.allowed => out!(session, "{}  allowed", result.txid),
+                    (Some(reason), _) => out!(session, "{}  rejected: {} ({})", result.txid, describe_rejection(reason), reason),
+                    (None, _) => out!(session, "{}  rejected", result.txid),
+                }
+            }
+            let results: Vec<serde_json::Value> = results.iter()
+                .map(|result| json!({
+                    "txid": result.txid,
+                    "allowed": result.allowed,
+                    "reject_reason": result.reject_reason,
+                    "vsize": result.vsize,
+                    "fee": result.fees.as_ref().map(|fees| fees.base.to_btc()),
+                }))
+                .collect();
+            session.out.json(&json!({ "results": results }));
+        }
+        Err(e) => err!(session, "Failed to test mempool acceptance. Error {:?}", e)
+    }
+}
+
+// Without -txindex the node only finds mempool transactions and, via the wallet, its own
+fn advise_missing_transaction(session: &Session, txid: &Txid) {
+    let txindex = session.rpc_client.call::<serde_json::Value>("getindexinfo", &["txindex".into()])
+        .map(|info| info["txindex"]["synced"].as_bool());
+    match txindex {
+        Ok(Some(true)) => out!(session, "Transaction {} not found in the mempool or the chain", txid),
+        Ok(Some(false)) => out!(session, "Transaction {} not found, the txindex is still being built", txid),
+        _ => out!(session, "Transaction {} not found: outside the mempool this needs the node to run with -txindex", txid),
+    }
+}
+
+pub fn show_raw_transaction(session: &Session, txid: &Txid, verbose: bool) {
+    let rpc_client = &session.rpc_client;
+    if !verbose {
+        match rpc_client.get_raw_transaction_hex(txid, None) {
+            Ok(hex) => {
+                out!(session, "{}", hex);
+                session.out.json(&json!({ "txid": txid, "hex": hex }));
+            }
+            Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => advise_missing_transaction(session, txid),
+            Err(e) => err!(session, "Failed to get transaction {}. Error {:?}", txid, e)
+        }
+        return;
+    }
+
+    match rpc_client.get_raw_transaction_info(txid, None) {
+        Ok(info) => {
+            out!(session, "TxID: {}", info.txid);
+            out!(session, "Size: {} bytes, vsize {} vB, version {}, locktime {}", info.size, info.vsize, info.version, info.locktime);
+            match (&info.blockhash, info.confirmations) {
+                (Some(blockhash), Some(confirmations)) => out!(session, "Confirmations: {} (block {})", confirmations, blockhash),
+                _ => out!(session, "Confirmations: 0 (in mempool)"),
+            }
+            for (index, input) in info.vin.iter().enumerate() {
+                match (&input.txid, input.vout) {
+                    (Some(prev_txid), Some(vout)) => out!(session, "  in  {}: {}:{}", index, prev_txid, vout),
+                    _ => out!(session, "  in  {}: coinbase", index),
+                }
+            }
+            for output in &info.vout {
+                let address = output.script_pub_key.address.as_ref()
+                    .map_or(output.script_pub_key.asm.clone(), |address| address.clone().assume_checked().to_string());
+                out!(session, "  out {}: {} to {}", output.n, output.value, address);
+            }
+
+            let inputs: Vec<serde_json::Value> = info.vin.iter()
+                .map(|input| json!({ "txid": input.txid, "vout": input.vout, "coinbase": input.is_coinbase() }))
+                .collect();
+            let outputs: Vec<serde_json::Value> = info.vout.iter()
+                .map(|output| json!({ "n": output.n, "value": output.value.to_btc(), "address": output.script_pub_key.address }))
+                .collect();
+            session.out.json(&json!({
+                "txid": info.txid,
+                "size": info.size,
+                "vsize": info.vsize,
+                "confirmations": info.confirmations.unwrap_or(0),
+                "blockhash": info.blockhash,
+                "inputs": inputs,
+                "outputs": outputs,
+            }));
+        }
+        Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => advise_missing_transaction(session, txid),
+        Err(e) => err!(session, "Failed to get transaction {}. Error {:?}", txid, e)
+    }
+}
+
+// Same inputs and outputs as the original, with the extra fee taken out of the wallet's change output
+fn build_replacement(session: &Session, old_txid: &Txid, fee_rate: f64) -> Result<(Transaction, Amount, Amount), MinerError> {
+    let rpc_client = &session.rpc_client;
+    let wallet_tx = rpc_client.get_transaction(old_txid, None)?;
+    if wallet_tx.info.confirmations > 0 {
+        return Err(MinerError::Replacement(format!("{} is already confirmed", old_txid)));
+    }
+
+    let mut tx = wallet_tx.transaction().map_err(|e| MinerError::InvalidTransaction(e.to_string()))?;
+    if !tx.is_explicitly_rbf() {
+        return Err(MinerError::Replacement(format!("{} does not signal BIP125 replaceability", old_txid)));
+    }
+
+    let old_fee = wallet_tx.fee
+        .and_then(|fee| (-fee).to_unsigned().ok())
+        .ok_or_else(|| MinerError::Replacement(format!("{} was not sent by this wallet", old_txid)))?;
+    let new_fee = Amount::from_sat((fee_rate * tx.vsize() as f64).ceil() as u64);
+    if new_fee <= old_fee {
+        return Err(MinerError::Replacement(format!(
+            "fee rate {} sat/vB gives a fee of {}, which does not exceed the original fee of {}",
+            fee_rate, new_fee, old_fee
+        )));
+    }
+
+    let mut change_index = None;
+    for (index, output) in tx.output.iter().enumerate() {
+        let Ok(address) = Address::from_script(&output.script_pubkey, session.network) else { continue };
+        // The typed getaddressinfo result doesn't expose ischange
+        let info = rpc_client.call::<serde_json::Value>("getaddressinfo", &[address.to_string().into()])?;
+        if info["ischange"].as_bool() == Some(true) {
+            change_index = Some(index);
+            break;
+        }
+    }
+    let change_index = change_index
+        .ok_or_else(|| MinerError::Replacement(format!("{} has no change output to pay the extra fee from", old_txid)))?;
+
+    let change = &mut tx.output[change_index];
+    let dust_threshold = change.script_pubkey.minimal_non_dust();
+    change.value = change.value.checked_sub(new_fee - old_fee)
+        .filter(|value| *value >= dust_threshold)
+        .ok_or_else(|| MinerError::Replacement(format!("change output of {} is too small to pay a fee of {}", change.value, new_fee)))?;
+
+    // Signatures commit to the outputs, so strip the old ones and have the wallet sign again
+    for input in &mut tx.input {
+        input.script_sig = ScriptBuf::new();
+        input.witness = Witness::new();
+    }
+    let signed = rpc_client.sign_raw_transaction_with_wallet(&tx, None, None)?;
+    if !signed.complete {
+        return Err(MinerError::SigningIncomplete);
+    }
+
+    let replacement = signed.transaction().map_err(|e| MinerError::InvalidTransaction(e.to_string()))?;
+    Ok((replacement, old_fee, new_fee))
+}
+
+pub fn replace_transaction(session: &Session, old_txid: Txid, fee_rate: f64) {
+    let (replacement, old_fee, new_fee) = match build_replacement(session, &old_txid, fee_rate) {
+        Ok(built) => built,
+        Err(e) => {
+            err!(session, "Cannot replace {}: {}", old_txid, e);
+            return;
+        }
+    };
+
+    match session.rpc_client.send_raw_transaction(&replacement) {
+        Ok(new_txid) => {
+            session.record_transaction_sent(&new_txid);
+            out!(session, "Old TxID: {} (fee {})", old_txid, old_fee);
+            out!(session, "New TxID: {} (fee {})", new_txid, new_fee);
+            session.out.json(&json!({
+                "old_txid": old_txid,
+                "new_txid": new_txid,
+                "old_fee": old_fee.to_btc(),
+                "new_fee": new_fee.to_btc(),
+            }));
+        }
+        Err(e) => {
+            let message = e.to_string();
+            err!(session, "Replacement rejected: {}. Error {}", describe_rejection(&message), message)
+        }
+    }
+}
+
+// The two ways bumpfee rejects a fee that isn't high enough
+fn describe_bump_rejection(message: &str) -> Option<&'static str> {
+    if message.contains("Insufficient total fee") || message.contains("incrementalFee") || message.contains("too-low-increment") {
+        Some("new fee does not exceed the old fee by the node's incremental relay fee (too-low-increment)")
+    } else if message.contains("less than") || message.contains("lower than") {
+        Some("new fee is less than the old fee")
+    } else {
+        None
+    }
+}
+
+// Modern nodes only take a fee rate, so an absolute total fee is converted using the original size
+pub fn bump_fee(session: &Session, txid: Txid, total_fee: Option<Amount>) {
+    let rpc_client = &session.rpc_client;
+    let mut params = vec![txid.to_string().into()];
+
+    if let Some(total_fee) = total_fee {
+        let wallet_tx = match rpc_client.get_transaction(&txid, None) {
+            Ok(wallet_tx) => wallet_tx,
+            Err(e) => {
+                err!(session, "Failed to get transaction {}. Error {:?}", txid, e);
+                return;
+            }
+        };
+        let old_fee = wallet_tx.fee.and_then(|fee| (-fee).to_unsigned().ok()).unwrap_or(Amount::ZERO);
+        if total_fee <= old_fee {
+            err!(session, "Total fee {} must be higher than the original fee of {}", total_fee, old_fee);
+            return;
+        }
+
+        let vsize = match wallet_tx.transaction() {
+            Ok(tx) => tx.vsize(),
+            Err(e) => {
+                err!(session, "Failed to decode transaction {}. Error {:?}", txid, e);
+                return;
+            }
+        };
+        // bumpfee accepts sat/vB with up to 3 decimals, round up so the total is never undershot
+        let fee_rate = (total_fee.to_sat() as f64 * 1000.0 / vsize as f64).ceil() / 1000.0;
+        params.push(json!({ "fee_rate": fee_rate }));
+    }
+
+    match rpc_client.call::<serde_json::Value>("bumpfee", &params) {
+        Ok(result) => {
+            if let Some(new_txid) = result["txid"].as_str().and_then(|new_txid| new_txid.parse().ok()) {
+                session.record_transaction_sent(&new_txid);
+            }
+            out!(session, "Old fee: {}", format_btc_value(&result["origfee"]));
+            out!(session, "New fee: {}", format_btc_value(&result["fee"]));
+            out!(session, "New TxID: {}", result["txid"].as_str().unwrap_or("unknown"));
+            session.out.json(&json!({ "old_txid": txid, "txid": result["txid"], "old_fee": result["origfee"], "fee": result["fee"] }));
+        }
+        Err(e) => {
+            let message = e.to_string();
+            match describe_bump_rejection(&message) {
+                Some(reason) => err!(session, "Fee bump rejected: {}. Error {}", reason, message),
+                None => err!(session, "Failed to bump fee for {}. Error {}", txid, message),
+            }
+        }
+    }
+}
+
+// sendspec file: every input is spent, and the wallet only adds a change output unless add_inputs is set
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SendSpec {
+    inputs: Vec<SpecInput>,
+    outputs: Vec<SpecOutput>,
+    // sat/vB
+    fee_rate: Option<f64>,
+    #[serde(default)]
+    add_inputs: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecInput {
+    txid: Txid,
+    vout: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecOutput {
+    // Address or alias
+    address: String,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    amount: Amount,
+}
+
+fn read_send_spec(path: &str) -> Result<SendSpec, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let deserializer = &mut serde_json::Deserializer::from_str(&contents);
+    // The path names the malformed field, e.g. "outputs[1].amount"
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| format!("Invalid spec {}: field {}: {}", path, e.path(), e.inner()))
+}
+
+pub fn send_spec(session: &Session, path: &str) {
+    let spec = match read_send_spec(path) {
+        Ok(spec) => spec,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+    if spec.outputs.is_empty() {
+        err!(session, "Invalid spec {}: no outputs", path);
+        return;
+    }
+
+    let mut outputs = HashMap::new();
+    for output in &spec.outputs {
+        let address = match session.resolve_address(&output.address) {
+            Ok(address) => address,
+            Err(e) => {
+                err!(session, "Invalid spec {}: {}", path, e);
+                return;
+            }
+        };
+        if outputs.insert(address.to_string(), output.amount).is_some() {
+            err!(session, "Invalid spec {}: address {} appears more than once", path, address);
+            return;
+        }
+    }
+
+    let inputs: Vec<CreateRawTransactionInput> = spec.inputs.iter()
+        .map(|input| CreateRawTransactionInput { txid: input.txid, vout: input.vout, sequence: None })
+        .collect();
+    let fund_options = FundRawTransactionOptions {
+        add_inputs: Some(spec.add_inputs || inputs.is_empty()),
+        fee_rate: spec.fee_rate.map(fee_rate_to_btc_per_kvb),
+        conf_target: spec.fee_rate.map_or(session.confirm_target, |_| None),
+        estimate_mode: spec.fee_rate.map_or(session.estimate_mode, |_| None),
+        change_type: session.change_type,
+        ..Default::default()
+    };
+
+    let funded = match build_funded_transaction(&session.rpc_client, &inputs, &outputs, &fund_options) {
+        Ok(funded) => funded,
+        Err(e) => {
+            err!(session, "Failed to build transaction from {}. Error {}", path, e);
+            return;
+        }
+    };
+
+    match session.rpc_client.send_raw_transaction(&funded.tx) {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            out!(session, "Spent {} input(s) to {} output(s), fee {} ({:.2} sat/vB)",
+                funded.tx.input.len(), funded.tx.output.len(), funded.fee, funded.fee_rate());
+            session.out.json(&json!({
+                "txid": tx_id,
+                "inputs": funded.tx.input.len(),
+                "outputs": funded.tx.output.len(),
+                "fee": funded.fee.to_btc(),
+                "fee_rate": funded.fee_rate(),
+            }));
+            confirm_send(session);
+        }
+        Err(e) => {
+            let message = e.to_string();
+            err!(session, "Transaction rejected: {}. Error {}", describe_rejection(&message), message)
+        }
+    }
+}
+
+// Where doublespend sends its two transactions, if anywhere
+#[derive(Clone, Copy, PartialEq)]
+pub enum DoubleSpendBroadcast {
+    None,
+    // Both to the primary node, which should reject the second
+    SameNode,
+    // The first to the primary node, the second to the first --node, to watch which one the network keeps
+    SplitNodes,
+}
+
+pub fn double_spend(
+    session: &Session,
+    outpoint: OutPoint,
+    first_address: &str,
+    second_address: &str,
+    amount: Amount,
+    broadcast: DoubleSpendBroadcast,
+) {
+    let peer = match broadcast {
+        DoubleSpendBroadcast::SplitNodes => {
+            let Some(peer_url) = session.extra_nodes.first() else {
+                err!(session, "Splitting the broadcast needs a second node, add one with --node <URL>");
+                return;
+            };
+            match session.node_client(peer_url) {
+                Ok(peer) => Some((peer_url, peer)),
+                Err(e) => {
+                    err!(session, "Failed to create client for {}. Error {:?}", peer_url, e);
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let mut destinations = Vec::new();
+    for address_string in [first_address, second_address] {
+        match session.resolve_address(address_string) {
+            Ok(address) => destinations.push(address),
+            Err(e) => {
+                err!(session, "{}", e);
+                return;
+            }
+        }
+    }
+    if destinations[0] == destinations[1] {
+        err!(session, "Both spends go to {}, use two different addresses", destinations[0]);
+        return;
+    }
+
+    out!(session, "Warning: doublespend deliberately creates conflicting transactions, use it on test networks only");
+
+    // Only the given input, so the two transactions conflict on exactly that outpoint
+    let inputs = [CreateRawTransactionInput { txid: outpoint.txid, vout: outpoint.vout, sequence: None }];
+    let fund_options = FundRawTransactionOptions {
+        add_inputs: Some(false),
+        change_type: session.change_type,
+        ..Default::default()
+    };
+    let mut spends = Vec::new();
+    for address in &destinations {
+        let outputs = HashMap::from([(address.to_string(), amount)]);
+        match build_funded_transaction(&session.rpc_client, &inputs, &outputs, &fund_options) {
+            Ok(funded) => spends.push(funded),
+            Err(e) => {
+                err!(session, "Failed to build the spend of {} to {}. Error {}", outpoint, address, e);
+                return;
+            }
+        }
+    }
+
+    let mut results = Vec::new();
+    for (index, (spend, address)) in spends.iter().zip(&destinations).enumerate() {
+        let txid = spend.tx.compute_txid();
+        // Checked one at a time, as a package the node would reject the pair outright
+        let accept = session.rpc_client.test_mempool_accept(&[&spend.tx]).map(|mut accept| accept.remove(0));
+        let (allowed, reason) = match &accept {
+            Ok(accept) => (accept.allowed, accept.reject_reason.clone()),
+            Err(e) => (false, Some(e.to_string())),
+        };
+        match (allowed, &reason) {
+            (true, _) => out!(session, "Spend {}: {} to {} ({}), accepted by testmempoolaccept", index + 1, txid, address, amount),
+            (false, reason) => out!(
+                session,
+                "Spend {}: {} to {} ({}), rejected: {}",
+                index + 1, txid, address, amount, reason.as_deref().unwrap_or("unknown")
+            ),
+        }
+
+        let broadcast_result = match (broadcast, index, &peer) {
+            (DoubleSpendBroadcast::None, _, _) => None,
+            (DoubleSpendBroadcast::SplitNodes, 1, Some((peer_url, peer))) => {
+                Some((peer_url.as_str(), peer.send_raw_transaction(&spend.tx)))
+            }
+            _ => Some((session.rpc_url.as_str(), session.rpc_client.send_raw_transaction(&spend.tx))),
+        };
+        let broadcast_json = match &broadcast_result {
+            None => serde_json::Value::Null,
+            Some((url, Ok(_))) => {
+                session.record_transaction_sent(&txid);
+                out!(session, "  broadcast to {}: accepted", url);
+                json!({ "node": url, "accepted": true })
+            }
+            Some((url, Err(e))) => {
+                let message = e.to_string();
+                out!(session, "  broadcast to {}: {} ({})", url, describe_rejection(&message), message);
+                json!({ "node": url, "accepted": false, "error": message })
+            }
+        };
+        results.push(json!({
+            "txid": txid,
+            "address": address,
+            "allowed": allowed,
+            "reject_reason": reason,
+            "broadcast": broadcast_json,
+        }));
+    }
+
+    session.out.json(&json!({ "outpoint": outpoint.to_string(), "spends": results }));
+}
+
+// Policy's MAX_OP_RETURN_RELAY: OP_RETURN, a push opcode and 80 bytes of data
+const MAX_OP_RETURN_RELAY: usize = 83;
+
+// Why the node's default policy would refuse to relay an output, if it would
+fn non_standard_reason(script: &ScriptBuf, amount: Amount) -> Option<String> {
+    if script.is_op_return() {
+        return (script.len() > MAX_OP_RETURN_RELAY)
+            .then(|| format!("OP_RETURN output of {} bytes exceeds {}", script.len(), MAX_OP_RETURN_RELAY));
+    }
+    // Unknown witness versions are standard to send to, they are reserved for future soft forks
+    let known = script.is_p2pk() || script.is_p2pkh() || script.is_p2sh() || script.is_witness_program() || script.is_multisig();
+    if !known {
+        return Some("script does not match a standard output template".to_string());
+    }
+    let dust_threshold = script.minimal_non_dust();
+    (amount < dust_threshold).then(|| format!("amount is below the dust threshold of {}", dust_threshold))
+}
+
+// A single output paying straight to a scriptPubKey, the wallet adds inputs and change
+pub fn send_to_script(session: &Session, script_hex: &str, amount: Amount) {
+    let script = match ScriptBuf::from_hex(script_hex) {
+        Ok(script) if !script.is_empty() => script,
+        Ok(_) => {
+            err!(session, "Script is empty");
+            return;
+        }
+        Err(e) => {
+            err!(session, "Error parsing script hex: {}", e);
+            return;
+        }
+    };
+    let non_standard = non_standard_reason(&script, amount);
+    if let Some(reason) = &non_standard {
+        out!(session, "Warning: output is non-standard ({}), nodes with default policy will not relay it", reason);
+    }
+
+    let unfunded = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: Vec::new(),
+        output: vec![TxOut { value: amount, script_pubkey: script.clone() }],
+    };
+    let fund_options = FundRawTransactionOptions {
+        conf_target: session.confirm_target,
+        estimate_mode: session.estimate_mode,
+        change_type: session.change_type,
+        ..Default::default()
+    };
+    let funded = match fund_and_sign(&session.rpc_client, &unfunded, &fund_options) {
+        Ok(funded) => funded,
+        Err(e) => {
+            err!(session, "Failed to build transaction to script {}. Error {}", script_hex, e);
+            return;
+        }
+    };
+
+    match session.rpc_client.send_raw_transaction(&funded.tx) {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            out!(session, "Sent {} to {}, fee {} ({:.2} sat/vB)", amount, script.to_asm_string(), funded.fee, funded.fee_rate());
+            session.out.json(&json!({
+                "txid": tx_id,
+                "script": script_hex,
+                "amount": amount.to_btc(),
+                "fee": funded.fee.to_btc(),
+                "fee_rate": funded.fee_rate(),
+                "non_standard": non_standard,
+            }));
+            confirm_send(session);
+        }
+        Err(e) => {
+            let message = e.to_string();
+            err!(session, "Transaction rejected: {}. Error {}", describe_rejection(&message), message)
+        }
+    }
+}
+
+// submitpackage wants parents before children, so order the transactions by their in-package dependencies
+fn order_package(mut txs: Vec<Transaction>) -> Result<Vec<Transaction>, String> {
+    let mut ordered: Vec<Transaction> = Vec::with_capacity(txs.len());
+    while !txs.is_empty() {
+        let pending: Vec<Txid> = txs.iter().map(|tx| tx.compute_txid()).collect();
+        // Ready once none of its inputs spend a transaction still waiting to be placed
+        let Some(ready) = txs.iter().position(|tx| tx.input.iter().all(|input| !pending.contains(&input.previous_output.txid)))
+        else {
+            return Err("transactions spend each other in a cycle".to_string());
+        };
+        ordered.push(txs.remove(ready));
+    }
+    Ok(ordered)
+}
+
+pub fn send_package(session: &Session, tx_hexes: &[&str]) {
+    let mut txs = Vec::new();
+    for (index, tx_hex) in tx_hexes.iter().enumerate() {
+        match deserialize_hex::<Transaction>(tx_hex) {
+            Ok(tx) => txs.push(tx),
+            Err(e) => {
+                err!(session, "Error parsing raw transaction {} {:?}", index + 1, e);
+                return;
+            }
+        }
+    }
+    let mut txids: Vec<Txid> = txs.iter().map(|tx| tx.compute_txid()).collect();
+    txids.sort();
+    if txids.windows(2).any(|pair| pair[0] == pair[1]) {
+        err!(session, "The package contains the same transaction more than once");
+        return;
+    }
+    let txs = match order_package(txs) {
+        Ok(txs) => txs,
+        Err(e) => {
+            err!(session, "Invalid package: {}", e);
+            return;
+        }
+    };
+
+    let ordered_hexes: Vec<String> = txs.iter().map(serialize_hex).collect();
+    let result = match session.rpc_client.call::<serde_json::Value>("submitpackage", &[json!(ordered_hexes)]) {
+        Ok(result) => result,
+        Err(e) => {
+            let message = e.to_string();
+            err!(session, "Package rejected: {}. Error {}", describe_rejection(&message), message);
+            return;
+        }
+    };
+
+    // "tx-results" is keyed by wtxid; newer nodes report a failed package here instead of as an RPC error
+    let package_msg = result["package_msg"].as_str().unwrap_or("success");
+    out!(session, "Package: {}", package_msg);
+    let mut results = Vec::new();
+    for tx in &txs {
+        let txid = tx.compute_txid();
+        let tx_result = &result["tx-results"][tx.compute_wtxid().to_string()];
+        match tx_result["error"].as_str() {
+            Some(error) => err!(session, "{}  rejected: {} ({})", txid, describe_rejection(error), error),
+            None if tx_result.is_object() => {
+                session.record_transaction_sent(&txid);
+                // sat/vB, from the BTC/kvB feerate covering the transaction and any package members it was assessed with
+                let fee_rate = tx_result["fees"]["effective-feerate"].as_f64().map(|rate| rate * 100_000.0);
+                match fee_rate {
+                    Some(fee_rate) => out!(session, "{}  accepted  fee {}  effective {:.2} sat/vB",
+                        txid, format_btc_value(&tx_result["fees"]["base"]), fee_rate),
+                    None => out!(session, "{}  accepted", txid),
+                }
+            }
+            None => out!(session, "{}  not evaluated", txid),
+        }
+        results.push(json!({
+            "txid": txid,
+            "error": tx_result["error"],
+            "vsize": tx_result["vsize"],
+            "fee": tx_result["fees"]["base"],
+            "effective_feerate": tx_result["fees"]["effective-feerate"],
+        }));
+    }
+    session.out.json(&json!({ "package_msg": package_msg, "results": results }));
+    if package_msg == "success" {
+        confirm_send(session);
+    }
+}
+
+// Builds a send with the given nLockTime, broadcasting it only once the chain has reached the lock
+pub fn send_locked(session: &Session, address_string: &str, amount: Amount, lock_time: absolute::LockTime) {
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+    if let Err(e) = check_dust(amount, &address) {
+        err!(session, "{}", e);
+        return;
+    }
+
+    let info = match session.rpc_client.get_blockchain_info() {
+        Ok(info) => info,
+        Err(e) => {
+            err!(session, "Failed to get blockchain info. Error {:?}", e);
+            return;
+        }
+    };
+    // The next block is at tip + 1 and a time lock is checked against the tip's median time past
+    let next_height = info.blocks + 1;
+    let (final_now, describe_lock) = match lock_time {
+        absolute::LockTime::Blocks(height) => (
+            height.to_consensus_u32() < next_height as u32,
+            format!("height {} (next block {})", height, next_height),
+        ),
+        absolute::LockTime::Seconds(time) => (
+            (time.to_consensus_u32() as u64) < info.median_time,
+            format!("time {} (median time past {})", time, info.median_time),
+        ),
+    };
+
+    // Funding picks a non-final input sequence, so the lock time is enforced
+    let unfunded = Transaction {
+        version: transaction::Version::TWO,
+        lock_time,
+        input: Vec::new(),
+        output: vec![TxOut { value: amount, script_pubkey: address.script_pubkey() }],
+    };
+    let fund_options = FundRawTransactionOptions {
+        conf_target: session.confirm_target,
+        estimate_mode: session.estimate_mode,
+        change_type: session.change_type,
+        ..Default::default()
+    };
+    let funded = match fund_and_sign(&session.rpc_client, &unfunded, &fund_options) {
+        Ok(funded) => funded,
+        Err(e) => {
+            err!(session, "Failed to build time-locked transaction to {}. Error {}", address, e);
+            return;
+        }
+    };
+    let txid = funded.tx.compute_txid();
+
+    if !final_now {
+        let hex = serialize_hex(&funded.tx);
+        out!(session, "Transaction {} is not final yet: locked until {}", txid, describe_lock);
+        out!(session, "Not broadcast, send it with sendrawtx once the lock is reached:");
+        out!(session, "{}", hex);
+        session.out.json(&json!({ "txid": txid, "final": false, "locktime": lock_time.to_consensus_u32(), "hex": hex }));
+        return;
+    }
+
+    match session.rpc_client.send_raw_transaction(&funded.tx) {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            out!(session, "Sent {} to {} with lock time {}, fee {}", amount, address, describe_lock, funded.fee);
+            session.out.json(&json!({
+                "txid": tx_id,
+                "final": true,
+                "locktime": lock_time.to_consensus_u32(),
+                "fee": funded.fee.to_btc(),
+            }));
+            confirm_send(session);
+        }
+        Err(e) => {
+            let message = e.to_string();
+            err!(session, "Transaction rejected: {}. Error {}", describe_rejection(&message), message)
+        }
+    }
+}
+
+// replaytx capture: a transaction exactly as the node had it, to re-broadcast after a reset
+#[derive(Debug, Clone)]
+pub struct CapturedTx {
+    pub hex: String,
+    pub wtxid: Wtxid,
+    pub blockhash: Option<BlockHash>,
+}
+
+// Without -txindex only the wallet still has a confirmed transaction's hex
+fn fetch_transaction_hex(rpc_client: &MinerClient, txid: &Txid) -> Result<(String, Option<BlockHash>), bitcoincore_rpc::Error> {
+    match rpc_client.get_raw_transaction_info(txid, None) {
+        Ok(info) => Ok((hex::encode(&info.hex), info.blockhash)),
+        Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => {
+            let wallet_tx = rpc_client.get_transaction(txid, None)?;
+            Ok((hex::encode(&wallet_tx.hex), wallet_tx.info.blockhash))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn capture_transaction(session: &mut Session, txid: Txid) {
+    let (hex, blockhash) = match fetch_transaction_hex(&session.rpc_client, &txid) {
+        Ok(result) => result,
+        Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => {
+            advise_missing_transaction(session, &txid);
+            return;
+        }
+        Err(e) => {
+            err!(session, "Failed to get transaction {}. Error {:?}", txid, e);
+            return;
+        }
+    };
+    let wtxid = match deserialize_hex::<Transaction>(&hex) {
+        Ok(tx) => tx.compute_wtxid(),
+        Err(e) => {
+            err!(session, "Node returned an unparseable transaction {:?}", e);
+            return;
+        }
+    };
+
+    match blockhash {
+        Some(blockhash) => out!(session, "Captured {} ({} bytes, confirmed in block {})", txid, hex.len() / 2, blockhash),
+        None => out!(session, "Captured {} ({} bytes, unconfirmed)", txid, hex.len() / 2),
+    }
+    out!(session, "Reset the chain (e.g. snapshot load), then run replaytx replay {}", txid);
+    session.out.json(&json!({ "txid": txid, "wtxid": wtxid, "hex": hex, "blockhash": blockhash }));
+    session.captured_txs.insert(txid, CapturedTx { hex, wtxid, blockhash });
+}
+
+// Re-broadcasts the captured bytes and checks the node ends up holding the very same transaction
+pub fn replay_transaction(session: &Session, txid: &Txid) {
+    let Some(captured) = session.captured_txs.get(txid) else {
+        err!(session, "{} has not been captured, run replaytx capture {} first", txid, txid);
+        return;
+    };
+    let rpc_client = &session.rpc_client;
+
+    let mut divergences = Vec::new();
+    let accepted_txid = match rpc_client.send_raw_transaction(captured.hex.as_str()) {
+        Ok(accepted_txid) => {
+            session.record_transaction_sent(&accepted_txid);
+            Some(accepted_txid)
+        }
+        Err(e) => {
+            let message = e.to_string();
+            divergences.push(format!("rebroadcast rejected: {} ({})", describe_rejection(&message), message));
+            None
+        }
+    };
+    if let Some(accepted_txid) = accepted_txid.filter(|accepted_txid| accepted_txid != txid) {
+        divergences.push(format!("node returned txid {} instead of {}", accepted_txid, txid));
+    }
+
+    // Compare what the node now holds, even after a rejection it may already have the transaction
+    let landed = fetch_transaction_hex(rpc_client, txid);
+    match &landed {
+        Ok((hex, _)) if *hex != captured.hex => divergences.push("node holds different bytes for this txid".to_string()),
+        Ok(_) => {}
+        Err(e) => divergences.push(format!("node no longer has the transaction ({})", e)),
+    }
+    let blockhash = landed.ok().and_then(|(_, blockhash)| blockhash);
+
+    match blockhash {
+        Some(blockhash) => out!(session, "{} is confirmed in block {}", txid, blockhash),
+        None if divergences.is_empty() => out!(session, "{} is back in the mempool", txid),
+        None => {}
+    }
+    if let (Some(before), Some(after)) = (captured.blockhash, blockhash) {
+        if before != after {
+            out!(session, "Note: originally confirmed in {}, now in {}", before, after);
+        }
+    }
+    if divergences.is_empty() {
+        out!(session, "REPLAY MATCHES: same txid {} and wtxid {}", txid, captured.wtxid);
+    } else {
+        out!(session, "REPLAY DIVERGED:");
+        for divergence in &divergences {
+            out!(session, "  {}", divergence);
+        }
+    }
+    session.out.json(&json!({
+        "txid": txid,
+        "wtxid": captured.wtxid,
+        "matches": divergences.is_empty(),
+        "divergences": divergences,
+        "blockhash": blockhash,
+    }));
+}
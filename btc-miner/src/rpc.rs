@@ -0,0 +1,403 @@
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use bitcoincore_rpc::{Auth, Client, Error, RpcApi};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::simple_http::{self, SimpleHttpTransport};
+
+use crate::output::Output;
+
+// bitcoind error codes we react to
+pub const RPC_WALLET_NOT_FOUND: i32 = -18;
+pub const RPC_METHOD_NOT_FOUND: i32 = -32601;
+const RPC_WALLET_ALREADY_LOADED: i32 = -35;
+const RPC_IN_WARMUP: i32 = -28;
+// HTTP 503, sent when the node's RPC work queue is full ("Work queue depth exceeded")
+const HTTP_SERVICE_UNAVAILABLE: u16 = 503;
+
+const TRANSIENT_RETRIES: u32 = 5;
+const TRANSIENT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+pub fn rpc_error_code(e: &Error) -> Option<i32> {
+    match e {
+        Error::JsonRpc(jsonrpc::error::Error::Rpc(rpc_error)) => Some(rpc_error.code),
+        _ => None,
+    }
+}
+
+// The node's own error message, without the transport wrapping
+pub fn rpc_error_message(e: &Error) -> Option<&str> {
+    match e {
+        Error::JsonRpc(jsonrpc::error::Error::Rpc(rpc_error)) => Some(&rpc_error.message),
+        _ => None,
+    }
+}
+
+// Same transport as Client::new, but with a configurable request timeout
+pub fn connect(url: &str, auth: &Auth, timeout: Duration) -> Result<Client, Error> {
+    let (user, pass) = auth.clone().get_user_pass()?;
+    let mut builder = SimpleHttpTransport::builder()
+        .url(url)
+        .map_err(|e| Error::JsonRpc(e.into()))?
+        .timeout(timeout);
+    if let Some(user) = user {
+        builder = builder.auth(user, pass);
+    }
+
+    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(builder.build())))
+}
+
+// Wallet names can hold spaces, slashes and anything else, so percent-encode all but the unreserved characters
+pub fn wallet_url(rpc_url: &str, wallet_name: &str) -> String {
+    let mut url = format!("{}/wallet/", rpc_url);
+    for byte in wallet_name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => url.push(byte as char),
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+// True when the node didn't answer within the configured --rpc-timeout
+pub fn is_timeout(e: &Error) -> bool {
+    let Error::JsonRpc(jsonrpc::error::Error::Transport(transport_error)) = e else { return false };
+    match transport_error.downcast_ref::<simple_http::Error>() {
+        Some(simple_http::Error::SocketError(io_error)) => {
+            matches!(io_error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+        }
+        _ => false,
+    }
+}
+
+// The request never got an answer, as opposed to the node answering with an error
+fn is_unreachable(e: &Error) -> bool {
+    let Error::JsonRpc(jsonrpc::error::Error::Transport(transport_error)) = e else { return false };
+    matches!(transport_error.downcast_ref::<simple_http::Error>(), Some(simple_http::Error::SocketError(_)))
+}
+
+// Errors the node returns before executing the request, so retrying is safe even for sends
+fn is_transient(e: &Error) -> bool {
+    if rpc_error_code(e) == Some(RPC_IN_WARMUP) {
+        return true;
+    }
+    let Error::JsonRpc(jsonrpc::error::Error::Transport(transport_error)) = e else { return false };
+    matches!(
+        transport_error.downcast_ref::<simple_http::Error>(),
+        Some(simple_http::Error::HttpErrorCode(HTTP_SERVICE_UNAVAILABLE))
+    )
+}
+
+// The raw node connection under MinerClient, behind a trait so the recovery logic can run against a scripted node
+pub trait NodeTransport {
+    fn call_value(&self, cmd: &str, args: &[serde_json::Value]) -> Result<serde_json::Value, Error>;
+    // One response per call in order, None where the node left a call unanswered; Err if the batch itself failed
+    fn call_batch(&self, calls: &[(&str, Vec<serde_json::Value>)]) -> Result<Vec<Option<Result<serde_json::Value, Error>>>, Error>;
+    // Another wallet's endpoint on the same node, for connections that know where they were opened
+    fn wallet(&self, wallet_name: &str) -> Result<Box<dyn NodeTransport>, Error> {
+        Err(Error::ReturnedError(format!("cannot open wallet {} from this connection", wallet_name)))
+    }
+}
+
+impl NodeTransport for Client {
+    fn call_value(&self, cmd: &str, args: &[serde_json::Value]) -> Result<serde_json::Value, Error> {
+        RpcApi::call(self, cmd, args)
+    }
+
+    fn call_batch(&self, calls: &[(&str, Vec<serde_json::Value>)]) -> Result<Vec<Option<Result<serde_json::Value, Error>>>, Error> {
+        let params = calls.iter().map(|(_, args)| serde_json::value::to_raw_value(args)).collect::<Result<Vec<_>, _>>()?;
+        let jsonrpc_client = self.get_jsonrpc_client();
+        let requests: Vec<_> = calls.iter().zip(&params)
+            .map(|((cmd, _), params)| jsonrpc_client.build_request(cmd, Some(params)))
+            .collect();
+        let responses = jsonrpc_client.send_batch(&requests)?;
+
+        Ok(responses.into_iter()
+            .map(|response| response.map(|response| response.result::<serde_json::Value>().map_err(Error::from)))
+            .collect())
+    }
+}
+
+// The session's connection: a wallet endpoint, so wallet calls keep working when the node has several loaded
+pub struct HttpNode {
+    client: Client,
+    url: String,
+    auth: Auth,
+    timeout: Duration,
+}
+
+impl HttpNode {
+    pub fn connect(url: &str, auth: &Auth, timeout: Duration, wallet_name: &str) -> Result<Self, Error> {
+        let client = connect(&wallet_url(url, wallet_name), auth, timeout)?;
+        Ok(HttpNode { client, url: url.to_string(), auth: auth.clone(), timeout })
+    }
+}
+
+impl NodeTransport for HttpNode {
+    fn call_value(&self, cmd: &str, args: &[serde_json::Value]) -> Result<serde_json::Value, Error> {
+        self.client.call_value(cmd, args)
+    }
+
+    fn call_batch(&self, calls: &[(&str, Vec<serde_json::Value>)]) -> Result<Vec<Option<Result<serde_json::Value, Error>>>, Error> {
+        self.client.call_batch(calls)
+    }
+
+    fn wallet(&self, wallet_name: &str) -> Result<Box<dyn NodeTransport>, Error> {
+        Ok(Box::new(HttpNode::connect(&self.url, &self.auth, self.timeout, wallet_name)?))
+    }
+}
+
+// MinerClient::batch without the recovery, for the extra --node endpoints. A node that answers but rejects
+// the batch gets the calls one at a time; Err only when it can't be reached at all
+pub fn batch_calls(transport: &dyn NodeTransport, calls: &[(&str, Vec<serde_json::Value>)]) -> Result<Vec<Result<serde_json::Value, Error>>, Error> {
+    match transport.call_batch(calls) {
+        Ok(responses) => Ok(calls.iter().zip(responses)
+            .map(|((cmd, args), response)| response.unwrap_or_else(|| transport.call_value(cmd, args)))
+            .collect()),
+        Err(e) if is_unreachable(&e) => Err(e),
+        Err(_) => Ok(calls.iter().map(|(cmd, args)| transport.call_value(cmd, args)).collect()),
+    }
+}
+
+// Wraps the RPC client so every call made by the tool goes through the same recovery logic
+pub struct MinerClient {
+    inner: Box<dyn NodeTransport>,
+    wallet_name: String,
+    auto_reload_wallet: bool,
+    out: Rc<dyn Output>,
+    // Node uptime as of the last check, a lower value later means it restarted in between
+    last_uptime: Cell<Option<u64>>,
+}
+
+impl MinerClient {
+    pub fn new(inner: impl NodeTransport + 'static, wallet_name: String, auto_reload_wallet: bool, out: Rc<dyn Output>) -> Self {
+        Self::from_transport(Box::new(inner), wallet_name, auto_reload_wallet, out)
+    }
+
+    fn from_transport(inner: Box<dyn NodeTransport>, wallet_name: String, auto_reload_wallet: bool, out: Rc<dyn Output>) -> Self {
+        MinerClient { inner, wallet_name, auto_reload_wallet, out, last_uptime: Cell::new(None) }
+    }
+
+    pub fn wallet_name(&self) -> &str {
+        &self.wallet_name
+    }
+
+    // A client for another wallet on the same node, reloading it the same way this one does
+    pub fn wallet(&self, wallet_name: &str) -> Result<MinerClient, Error> {
+        let inner = self.inner.wallet(wallet_name)?;
+        Ok(Self::from_transport(inner, wallet_name.to_string(), self.auto_reload_wallet, self.out.clone()))
+    }
+
+    // Catches restarts that happened between calls, which leave no error behind except an unloaded wallet
+    pub fn check_uptime(&self) -> Result<u64, Error> {
+        let uptime = self.uptime()?;
+        if let Some(previous) = self.last_uptime.replace(Some(uptime)) {
+            if uptime < previous {
+                err!(self, "Warning: node restarted (uptime went from {}s to {}s)", previous, uptime);
+                if self.auto_reload_wallet {
+                    self.reload_wallet();
+                }
+            }
+        }
+        Ok(uptime)
+    }
+
+    // Retries a busy or warming-up node with exponential backoff, separately from the wallet reload
+    fn call_with_retry(&self, cmd: &str, args: &[serde_json::Value]) -> Result<serde_json::Value, Error> {
+        let mut backoff = TRANSIENT_INITIAL_BACKOFF;
+        for _ in 0..TRANSIENT_RETRIES {
+            match self.inner.call_value(cmd, args) {
+                Err(e) if is_transient(&e) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+
+        let result = self.inner.call_value(cmd, args);
+        if matches!(&result, Err(e) if is_transient(e)) {
+            err!(self, "Node is still busy after {} retries of {}, giving up", TRANSIENT_RETRIES, cmd);
+        }
+        result
+    }
+
+    // Sends independent reads as one JSON-RPC batch, so a refresh costs one round trip instead of one per call.
+    // Falls back to separate calls if the node rejects the batch, and redoes any call that needs the usual recovery
+    pub fn batch(&self, calls: &[(&str, Vec<serde_json::Value>)]) -> Vec<Result<serde_json::Value, Error>> {
+        let Ok(responses) = self.inner.call_batch(calls) else {
+            return calls.iter().map(|(cmd, args)| self.call(cmd, args)).collect();
+        };
+        calls.iter().zip(responses)
+            .map(|((cmd, args), result)| {
+                match result {
+                    Some(Err(e)) if is_transient(&e) || rpc_error_code(&e) == Some(RPC_WALLET_NOT_FOUND) => self.call(cmd, args),
+                    Some(result) => result,
+                    None => self.call(cmd, args),
+                }
+            })
+            .collect()
+    }
+
+    fn reload_wallet(&self) -> bool {
+        out!(self, "Wallet {} is not loaded, trying to reload it", self.wallet_name);
+        match self.inner.call_value("loadwallet", &[self.wallet_name.clone().into()]) {
+            Ok(_) => {
+                out!(self, "Reloaded wallet {}", self.wallet_name);
+                true
+            }
+            Err(e) if rpc_error_code(&e) == Some(RPC_WALLET_ALREADY_LOADED) => true,
+            Err(e) => {
+                err!(self, "Failed to reload wallet {}. Error {}", self.wallet_name, e);
+                false
+            }
+        }
+    }
+}
+
+impl RpcApi for MinerClient {
+    fn call<T: for<'a> serde::de::Deserialize<'a>>(&self, cmd: &str, args: &[serde_json::Value]) -> Result<T, Error> {
+        let value = match self.call_with_retry(cmd, args) {
+            Err(e) if self.auto_reload_wallet && rpc_error_code(&e) == Some(RPC_WALLET_NOT_FOUND) => {
+                if self.reload_wallet() {
+                    self.call_with_retry(cmd, args)
+                } else {
+                    Err(e)
+                }
+            }
+            result => result,
+        }?;
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use bitcoincore_rpc::RpcApi;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{rpc_error, test_client, RecordingOutput, ScriptedNode};
+
+    fn http_error(status: u16) -> Error {
+        Error::JsonRpc(jsonrpc::error::Error::Transport(Box::new(simple_http::Error::HttpErrorCode(status))))
+    }
+
+    #[test]
+    fn classifies_transient_errors() {
+        let cases = [
+            (http_error(HTTP_SERVICE_UNAVAILABLE), true),
+            (rpc_error(RPC_IN_WARMUP, "Loading block index..."), true),
+            (http_error(500), false),
+            (http_error(401), false),
+            (rpc_error(RPC_WALLET_NOT_FOUND, "Requested wallet does not exist or is not loaded"), false),
+            (rpc_error(-26, "min relay fee not met"), false),
+            (rpc_error(-32601, "Method not found"), false),
+        ];
+        for (error, transient) in &cases {
+            assert_eq!(is_transient(error), *transient, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn retries_warming_up_node_until_it_answers() {
+        let node = ScriptedNode::default();
+        node.fail("getblockcount", RPC_IN_WARMUP, "Loading block index...").respond("getblockcount", json!(101));
+        let client = test_client(&node, false, Rc::new(RecordingOutput::default()));
+
+        assert_eq!(client.call::<u64>("getblockcount", &[]).unwrap(), 101);
+        assert_eq!(node.calls(), ["getblockcount", "getblockcount"]);
+    }
+
+    #[test]
+    fn passes_other_errors_straight_through() {
+        let node = ScriptedNode::default();
+        node.fail("sendrawtransaction", -26, "min relay fee not met").respond("sendrawtransaction", json!("00"));
+        let client = test_client(&node, false, Rc::new(RecordingOutput::default()));
+
+        let result = client.call::<String>("sendrawtransaction", &[]);
+        assert_eq!(rpc_error_code(&result.unwrap_err()), Some(-26));
+        assert_eq!(node.calls(), ["sendrawtransaction"]);
+    }
+
+    #[test]
+    fn reloads_unloaded_wallet_and_retries_once() {
+        let node = ScriptedNode::default();
+        node.fail("getbalance", RPC_WALLET_NOT_FOUND, "Requested wallet does not exist or is not loaded")
+            .respond("getbalance", json!(1.5));
+        node.respond("loadwallet", json!({ "name": "test", "warning": "" }));
+        let out = Rc::new(RecordingOutput::default());
+        let client = test_client(&node, true, out.clone());
+
+        assert_eq!(client.call::<f64>("getbalance", &[]).unwrap(), 1.5);
+        assert_eq!(node.calls(), ["getbalance", "loadwallet", "getbalance"]);
+        assert!(out.text().contains("Reloaded wallet test"));
+    }
+
+    #[test]
+    fn reports_unloaded_wallet_without_auto_reload() {
+        let node = ScriptedNode::default();
+        node.fail("getbalance", RPC_WALLET_NOT_FOUND, "Requested wallet does not exist or is not loaded")
+            .respond("getbalance", json!(1.5));
+        let client = test_client(&node, false, Rc::new(RecordingOutput::default()));
+
+        let result = client.call::<f64>("getbalance", &[]);
+        assert_eq!(rpc_error_code(&result.unwrap_err()), Some(RPC_WALLET_NOT_FOUND));
+        assert_eq!(node.calls(), ["getbalance"]);
+    }
+
+    #[test]
+    fn returns_original_error_when_reload_fails() {
+        let node = ScriptedNode::default();
+        node.fail("getbalance", RPC_WALLET_NOT_FOUND, "Requested wallet does not exist or is not loaded");
+        node.fail("loadwallet", -4, "Wallet file verification failed");
+        let out = Rc::new(RecordingOutput::default());
+        let client = test_client(&node, true, out.clone());
+
+        let result = client.call::<f64>("getbalance", &[]);
+        assert_eq!(rpc_error_code(&result.unwrap_err()), Some(RPC_WALLET_NOT_FOUND));
+        assert_eq!(node.calls(), ["getbalance", "loadwallet"]);
+        assert!(out.error_text().contains("Failed to reload wallet test"));
+    }
+
+    #[test]
+    fn wallet_url_percent_encodes_the_name() {
+        assert_eq!(wallet_url("http://127.0.0.1:18443", "miner"), "http://127.0.0.1:18443/wallet/miner");
+        assert_eq!(wallet_url("http://127.0.0.1:18443", "my wallet/2"), "http://127.0.0.1:18443/wallet/my%20wallet%2F2");
+        assert_eq!(wallet_url("http://127.0.0.1:18443", "a?b#c%"), "http://127.0.0.1:18443/wallet/a%3Fb%23c%25");
+        assert_eq!(wallet_url("http://127.0.0.1:18443", "café"), "http://127.0.0.1:18443/wallet/caf%C3%A9");
+    }
+
+    #[test]
+    fn batch_is_one_round_trip_when_the_node_accepts_it() {
+        let node = ScriptedNode::default();
+        node.accept_batches().respond("getblockcount", json!(101)).respond("getconnectioncount", json!(8));
+        let client = test_client(&node, false, Rc::new(RecordingOutput::default()));
+
+        let results = client.batch(&[("getblockcount", vec![]), ("getconnectioncount", vec![])]);
+
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [json!(101), json!(8)]);
+        assert_eq!(node.round_trips(), 1);
+    }
+
+    #[test]
+    fn rejected_batch_falls_back_to_separate_calls() {
+        let node = ScriptedNode::default();
+        node.respond("getblockcount", json!(101)).respond("getconnectioncount", json!(8));
+        let calls = [("getblockcount", vec![]), ("getconnectioncount", vec![])];
+
+        let results = batch_calls(&node, &calls).unwrap();
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [json!(101), json!(8)]);
+        // The rejected batch, then one request per call
+        assert_eq!(node.round_trips(), 3);
+
+        let client = test_client(&node, false, Rc::new(RecordingOutput::default()));
+        assert!(client.batch(&calls).iter().all(Result::is_ok));
+        assert_eq!(node.round_trips(), 6);
+    }
+}
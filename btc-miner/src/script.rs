@@ -0,0 +1,57 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::commands::{check_command_line, handle_input_line};
+use crate::session::Session;
+
+// (line number, command), skipping blank lines and # comments
+fn read_script(path: &Path) -> io::Result<Vec<(usize, String)>> {
+    let contents = fs::read_to_string(path)?;
+    let lines = contents.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| (number, line.to_string()))
+        .collect();
+
+    Ok(lines)
+}
+
+// Runs a --script file line by line, stopping at the first line that fails.
+// With strict set every line is checked first, so a typo near the end can't leave the chain half set up
+pub fn run_script(session: &mut Session, path: &Path, strict: bool) {
+    let lines = match read_script(path) {
+        Ok(lines) => lines,
+        Err(e) => {
+            err!(session, "Failed to read script {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    if strict {
+        let malformed: Vec<(usize, String)> = lines.iter()
+            .filter_map(|(number, line)| check_command_line(line).err().map(|e| (*number, e)))
+            .collect();
+        if !malformed.is_empty() {
+            for (number, e) in &malformed {
+                err!(session, "{}:{}: {}", path.display(), number, e);
+            }
+            err!(session, "Script {} not run: {} malformed line(s)", path.display(), malformed.len());
+            return;
+        }
+    }
+
+    out!(session, "Running {} command(s) from {}", lines.len(), path.display());
+    for (number, line) in &lines {
+        out!(session, "> {}", line);
+        if !handle_input_line(session, line.clone()) {
+            err!(session, "Script stopped at {}:{}: {}", path.display(), number, line);
+            return;
+        }
+        if session.quit_requested {
+            return;
+        }
+    }
+    out!(session, "Script {} finished", path.display());
+}
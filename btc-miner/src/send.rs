@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::str::FromStr;
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Address, Amount, Txid};
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::json::{AddressType, FundRawTransactionOptions};
+use serde_json::json;
+
+use crate::amount::{check_dust, parse_amount, parse_amount_with_unit};
+use crate::error::MinerError;
+use crate::mining::confirm_send;
+use crate::rawtx::{build_funded_transaction, fee_rate_to_btc_per_kvb};
+use crate::rpc::rpc_error_message;
+use crate::session::{PendingAction, Session};
+
+const FEE_LOOKUP_RETRY_DELAY: Duration = Duration::from_millis(250);
+// Bitcoin Core's default -maxfeerate (0.1 BTC/kvB), anything above it is a typo the node would refuse anyway
+pub const MAX_FEE_RATE_SAT_PER_VB: f64 = 10_000.0;
+
+// Optional key=value tokens accepted after a send command's positional arguments
+#[derive(Default)]
+pub struct SendOptions {
+    // sat/vB, overrides fee estimation entirely
+    pub fee_rate: Option<f64>,
+    // Fund and sign but don't broadcast, just report the real size and fee
+    pub estimate_size: bool,
+    // Caller-chosen token that, with --idempotency-log, makes re-running the same send a no-op
+    pub id: Option<String>,
+}
+
+// sat/vB, shared by feerate=, replace and the send wizard
+pub fn parse_fee_rate(value: &str) -> Result<f64, String> {
+    match f64::from_str(value) {
+        Ok(fee_rate) if fee_rate.is_finite() && fee_rate > MAX_FEE_RATE_SAT_PER_VB => Err(format!(
+            "Fee rate {} sat/vB is above the maximum of {} sat/vB", fee_rate, MAX_FEE_RATE_SAT_PER_VB
+        )),
+        Ok(fee_rate) if fee_rate.is_finite() && fee_rate > 0.0 => Ok(fee_rate),
+        _ => Err(format!("Invalid fee rate '{}', expected a positive sat/vB value", value)),
+    }
+}
+
+pub fn parse_send_options<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<SendOptions, String> {
+    let mut options = SendOptions::default();
+    for token in tokens.filter(|t| !t.is_empty()) {
+        if token == "--estimate-size" {
+            options.estimate_size = true;
+            continue;
+        }
+
+        match token.split_once('=') {
+            Some(("id", value)) if !value.is_empty() => options.id = Some(value.to_string()),
+            Some(("feerate", value)) => options.fee_rate = Some(parse_fee_rate(value)?),
+            _ => return Err(format!("Unknown send option '{}'", token)),
+        }
+    }
+
+    Ok(options)
+}
+
+// Identifies a send by destination, amount and id so the log never holds the raw values
+fn send_key(address: &Address, amount: Amount, id: &str) -> String {
+    let preimage = format!("{}:{}:{}", address, amount.to_sat(), id);
+    sha256::Hash::hash(preimage.as_bytes()).to_string()
+}
+
+// Each log line is "<send key> <txid>"
+fn find_recorded_send(path: &Path, key: &str) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    contents.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(recorded_key, _)| *recorded_key == key)
+        .map(|(_, txid)| txid.to_string())
+}
+
+fn record_send(path: &Path, key: &str, txid: &Txid) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {}", key, txid)
+}
+
+// conf_target and estimate_mode, the positional pair sendtoaddress and sendmany use when estimating the fee
+fn estimation_params(session: &Session) -> [serde_json::Value; 2] {
+    [session.confirm_target.into(), json!(session.estimate_mode)]
+}
+
+// The typed wrapper has no fee_rate parameter, so call sendtoaddress with positional arguments
+pub fn call_send_to_address(
+    session: &Session,
+    address: &Address,
+    amount: Amount,
+    subtract_fee: bool,
+    fee_rate: Option<f64>,
+) -> Result<Txid, bitcoincore_rpc::Error> {
+    if let Some(change_type) = session.change_type {
+        let outputs = json!({ address.to_string(): amount.to_btc() });
+        return call_send_with_change_type(session, outputs, subtract_fee, fee_rate, change_type);
+    }
+
+    let mut params = vec![address.to_string().into(), amount.to_btc().into()];
+    // comment, comment_to
+    params.extend([serde_json::Value::Null, serde_json::Value::Null]);
+    params.push(subtract_fee.into());
+    // replaceable
+    params.push(serde_json::Value::Null);
+    // An explicit fee rate replaces estimation, so the confirmation target only applies without one
+    match fee_rate {
+        Some(fee_rate) => params.extend([
+            serde_json::Value::Null,
+            serde_json::Value::Null,
+            serde_json::Value::Null,
+            fee_rate.into(),
+        ]),
+        None => params.extend(estimation_params(session)),
+    }
+
+    session.rpc_client.call::<Txid>("sendtoaddress", &params)
+}
+
+// sendtoaddress only uses the node's -changetype, but the send RPC takes a change type per call
+// (and so does sendmany). subtract_fee takes the fee out of the first output, for single-output sends
+fn call_send_with_change_type(
+    session: &Session,
+    outputs: serde_json::Value,
+    subtract_fee: bool,
+    fee_rate: Option<f64>,
+    change_type: AddressType,
+) -> Result<Txid, bitcoincore_rpc::Error> {
+    let mut options = json!({ "change_type": change_type });
+    if subtract_fee {
+        options["subtract_fee_from_outputs"] = json!([0]);
+    }
+    // outputs, conf_target, estimate_mode, fee_rate, options
+    let mut params = vec![outputs];
+    match fee_rate {
+        Some(fee_rate) => params.extend([serde_json::Value::Null, serde_json::Value::Null, fee_rate.into()]),
+        None => params.extend(estimation_params(session).into_iter().chain([serde_json::Value::Null])),
+    }
+    params.push(options);
+
+    let result = session.rpc_client.call::<serde_json::Value>("send", &params)?;
+    result["txid"].as_str()
+        .and_then(|txid| txid.parse().ok())
+        .ok_or_else(|| bitcoincore_rpc::Error::ReturnedError(format!("send did not complete: {}", result)))
+}
+
+// The wallet can take a moment to index a transaction it just broadcast, so retry once
+fn paid_fee(session: &Session, txid: &Txid) -> Result<(Amount, f64), MinerError> {
+    let wallet_tx = match session.rpc_client.get_transaction(txid, None) {
+        Ok(wallet_tx) => wallet_tx,
+        Err(_) => {
+            thread::sleep(FEE_LOOKUP_RETRY_DELAY);
+            session.rpc_client.get_transaction(txid, None)?
+        }
+    };
+
+    let fee = wallet_tx.fee
+        .and_then(|fee| (-fee).to_unsigned().ok())
+        .ok_or_else(|| MinerError::InvalidTransaction("wallet reported no fee".to_string()))?;
+    let vsize = wallet_tx.transaction().map_err(|e| MinerError::InvalidTransaction(e.to_string()))?.vsize();
+    Ok((fee, fee.to_sat() as f64 / vsize as f64))
+}
+
+// Prints the fee paid and adds it to the command's JSON result
+fn report_fee(session: &Session, txid: &Txid, result: &mut serde_json::Value) -> Option<Amount> {
+    match paid_fee(session, txid) {
+        Ok((fee, fee_rate)) => {
+            out!(session, "Fee: {} ({:.2} sat/vB)", fee, fee_rate);
+            result["fee"] = fee.to_btc().into();
+            result["fee_rate"] = fee_rate.into();
+            Some(fee)
+        }
+        Err(e) => {
+            err!(session, "Could not look up the fee for {}. Error {}", txid, e);
+            None
+        }
+    }
+}
+
+pub fn send_to_address(session: &Session, address_string: &str, amount: Amount, options: &SendOptions) {
+    let recipient_address = match session.resolve_address(address_string) {
+        Ok(addr) => addr,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = check_dust(amount, &recipient_address) {
+        err!(session, "{}", e);
+        return;
+    }
+
+    if options.estimate_size {
+        estimate_send_size(session, &recipient_address, amount, options);
+        return;
+    }
+
+    // Checked before the RPC call and recorded only after a successful broadcast
+    let idempotency = match (&session.idempotency_log, &options.id) {
+        (Some(path), Some(id)) => Some((path, send_key(&recipient_address, amount, id))),
+        _ => None,
+    };
+    if let Some((path, key)) = &idempotency {
+        if let Some(txid) = find_recorded_send(path, key) {
+            out!(session, "Already sent (id={}), TxID: {}", options.id.as_deref().unwrap_or_default(), txid);
+            session.out.json(&json!({ "txid": txid, "already_sent": true }));
+            return;
+        }
+    }
+
+    let result = call_send_to_address(session, &recipient_address, amount, false, options.fee_rate);
+
+    match result {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            if let Some((path, key)) = &idempotency {
+                if let Err(e) = record_send(path, key, &tx_id) {
+                    err!(session, "Warning: failed to record send in {}: {}", path.display(), e);
+                }
+            }
+
+            let mut result = json!({ "txid": tx_id, "address": recipient_address, "amount": amount.to_btc() });
+            if session.show_fee {
+                report_fee(session, &tx_id, &mut result);
+            }
+            session.out.json(&result);
+            confirm_send(session);
+        }
+        Err(e) => err!(session, "Failed to send amount to address {}. Error {:?}", address_string, e)
+    }
+}
+
+fn estimate_send_size(session: &Session, address: &Address, amount: Amount, options: &SendOptions) {
+    let outputs = HashMap::from([(address.to_string(), amount)]);
+    let fund_options = FundRawTransactionOptions {
+        fee_rate: options.fee_rate.map(fee_rate_to_btc_per_kvb),
+        conf_target: options.fee_rate.map_or(session.confirm_target, |_| None),
+        estimate_mode: options.fee_rate.map_or(session.estimate_mode, |_| None),
+        change_type: session.change_type,
+        ..Default::default()
+    };
+
+    match build_funded_transaction(&session.rpc_client, &[], &outputs, &fund_options) {
+        Ok(funded) => {
+            out!(session, "Virtual size: {} vB", funded.tx.vsize());
+            out!(session, "Estimated fee: {}", funded.fee);
+            out!(session, "Fee rate: {:.2} sat/vB", funded.fee_rate());
+            out!(session, "Not broadcast");
+            session.out.json(&json!({
+                "vsize": funded.tx.vsize(),
+                "fee": funded.fee.to_btc(),
+                "fee_rate": funded.fee_rate(),
+            }));
+        }
+        Err(e) => err!(session, "Failed to build transaction to {}. Error {}", address, e)
+    }
+}
+
+// sendmany and sweep only take feerate=, id= and --estimate-size are sendtoaddress-only
+pub fn require_fee_rate_only(command: &str, options: &SendOptions) -> Result<(), String> {
+    if options.id.is_some() {
+        return Err(format!("{} does not take id=, only sendtoaddress does", command));
+    }
+    if options.estimate_size {
+        return Err(format!("{} does not take --estimate-size, only sendtoaddress does", command));
+    }
+    Ok(())
+}
+
+pub fn is_send_option(token: &str) -> bool {
+    token.starts_with("--") || token.starts_with("feerate=") || token.starts_with("id=")
+}
+
+// "<address|alias>=<amount>" tokens for sendmany
+pub fn parse_send_outputs<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Vec<(&'a str, Amount)>, String> {
+    tokens
+        .map(|token| match token.split_once('=') {
+            Some((address, amount)) => parse_amount(amount)
+                .map(|amount| (address, amount))
+                .map_err(|e| format!("Error parsing amount for {}: {}", address, e)),
+            None => Err(format!("Expected <address>=<amount>, got '{}'", token)),
+        })
+        .collect()
+}
+
+pub fn sum_amounts(amounts: impl IntoIterator<Item = Amount>) -> Result<Amount, MinerError> {
+    amounts.into_iter()
+        .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+        .filter(|total| *total <= Amount::MAX_MONEY)
+        .ok_or(MinerError::TotalOverflow)
+}
+
+pub fn spendable_balance(session: &Session) -> Result<Amount, MinerError> {
+    Ok(session.rpc_client.get_balances()?.mine.trusted)
+}
+
+pub fn send_many(session: &Session, outputs: &[(&str, Amount)], options: &SendOptions) {
+    let mut amounts = serde_json::Map::new();
+    for (address_string, amount) in outputs {
+        let address = match session.resolve_address(address_string) {
+            Ok(address) => address,
+            Err(e) => {
+                err!(session, "{}", e);
+                return;
+            }
+        };
+        if let Err(e) = check_dust(*amount, &address) {
+            err!(session, "{}: {}", address, e);
+            return;
+        }
+        if amounts.insert(address.to_string(), amount.to_btc().into()).is_some() {
+            err!(session, "Address {} appears more than once", address);
+            return;
+        }
+    }
+
+    // Checked up front so an overspend gets a precise message instead of the node's generic one
+    let checked_total = sum_amounts(outputs.iter().map(|(_, amount)| *amount))
+        .and_then(|total| Ok((total, spendable_balance(session)?)));
+    let total = match checked_total {
+        Ok((total, balance)) if total > balance => {
+            err!(session, "{}", MinerError::InsufficientBalance { total, balance });
+            return;
+        }
+        Ok((total, _)) => total,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+
+    // dummy, amounts, minconf, comment, subtractfeefrom, replaceable, conf_target, estimate_mode, fee_rate
+    let mut params = vec!["".into(), amounts.into()];
+    params.extend([serde_json::Value::Null, serde_json::Value::Null, serde_json::Value::Null, serde_json::Value::Null]);
+    match options.fee_rate {
+        Some(fee_rate) => params.extend([serde_json::Value::Null, serde_json::Value::Null, fee_rate.into()]),
+        None => params.extend(estimation_params(session)),
+    }
+
+    let result = match session.change_type {
+        Some(change_type) => call_send_with_change_type(session, params[1].clone(), false, options.fee_rate, change_type),
+        None => session.rpc_client.call::<Txid>("sendmany", &params),
+    };
+    match result {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            out!(session, "Sent {} to {} output(s)", total, outputs.len());
+            let mut result = json!({ "txid": tx_id, "total": total.to_btc(), "outputs": outputs.len() });
+            if session.show_fee {
+                report_fee(session, &tx_id, &mut result);
+            }
+            session.out.json(&result);
+            confirm_send(session);
+        }
+        Err(e) => err!(session, "Failed to send to {} output(s). Error {:?}", outputs.len(), e)
+    }
+}
+
+// Sends the whole trusted balance, with the fee taken out of the amount sent
+pub fn sweep(session: &Session, address_string: &str, options: &SendOptions) {
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+
+    let balance = match spendable_balance(session) {
+        Ok(balance) => balance,
+        Err(e) => {
+            err!(session, "Failed to get balance. Error {}", e);
+            return;
+        }
+    };
+    if balance == Amount::ZERO {
+        out!(session, "Nothing to sweep, the spendable balance is zero");
+        return;
+    }
+
+    match call_send_to_address(session, &address, balance, true, options.fee_rate) {
+        Ok(tx_id) => {
+            session.record_transaction_sent(&tx_id);
+            out!(session, "TxID: {}", tx_id);
+            let mut result = json!({ "txid": tx_id, "address": address, "swept": balance.to_btc() });
+            if let Some(fee) = report_fee(session, &tx_id, &mut result) {
+                match balance.checked_sub(fee) {
+                    Some(received) => {
+                        out!(session, "Swept {} to {}, recipient receives {}", balance, address, received);
+                        result["received"] = received.to_btc().into();
+                    }
+                    None => err!(session, "Fee {} exceeds the swept balance {}", fee, balance),
+                }
+            }
+            session.out.json(&result);
+            confirm_send(session);
+        }
+        Err(e) => err!(session, "Failed to sweep to {}. Error {:?}", address, e)
+    }
+}
+
+pub fn request_flood(session: &mut Session, count: u64, amount: Amount, skip_confirmation: bool) {
+    if count == 0 {
+        out!(session, "Nothing to send");
+        return;
+    }
+    if skip_confirmation {
+        flood_mempool(session, count, amount);
+    } else {
+        out!(session, "About to send {} transaction(s) of {} each. Type 'yes' to confirm", count, amount);
+        session.pending_action = Some(PendingAction::Flood { count, amount });
+    }
+}
+
+// Sends back to the wallet's own fresh addresses, so only the fees are actually spent
+pub fn flood_mempool(session: &Session, count: u64, amount: Amount) {
+    let started = Instant::now();
+    let mut sent = 0u64;
+    // Failure message -> occurrences, so a repeated rejection is reported once
+    let mut failures: BTreeMap<String, u64> = BTreeMap::new();
+
+    for _ in 0..count {
+        let result = session.rpc_client.get_new_address(None, None)
+            .map(|address| address.assume_checked())
+            .and_then(|address| call_send_to_address(session, &address, amount, false, None));
+        match result {
+            Ok(tx_id) => {
+                session.record_transaction_sent(&tx_id);
+                sent += 1;
+            }
+            Err(e) => *failures.entry(rpc_error_message(&e).map_or(e.to_string(), str::to_string)).or_default() += 1,
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let rate = if elapsed > 0.0 { sent as f64 / elapsed } else { 0.0 };
+    out!(session, "Sent {} of {} transaction(s) in {:.2}s ({:.1} tx/s)", sent, count, elapsed, rate);
+    for (message, occurrences) in &failures {
+        out!(session, "  {} failure(s): {}", occurrences, message);
+    }
+    session.out.json(&json!({
+        "sent": sent,
+        "failed": count - sent,
+        "elapsed_secs": elapsed,
+        "tx_per_sec": rate,
+        "failures": failures,
+    }));
+    if sent > 0 {
+        confirm_send(session);
+    }
+}
+
+pub const DEFAULT_ESTIMATE_TARGET: u16 = 6;
+
+pub fn show_fee_estimate(session: &Session, conf_target: u16) {
+    match session.rpc_client.estimate_smart_fee(conf_target, session.estimate_mode) {
+        Ok(estimate) => {
+            let mode = session.estimate_mode.map_or("node default".to_string(), |mode| format!("{:?}", mode).to_lowercase());
+            match estimate.fee_rate {
+                // Reported per kvB, so sat/kvB divided by 1000 is sat/vB
+                Some(fee_rate) => out!(
+                    session,
+                    "Fee rate for confirmation within {} block(s): {}/kvB ({:.2} sat/vB, {} mode)",
+                    estimate.blocks, fee_rate, fee_rate.to_sat() as f64 / 1000.0, mode
+                ),
+                None => out!(session, "No fee estimate available ({})", estimate.errors.unwrap_or_default().join(", ")),
+            }
+            session.out.json(&json!({
+                "conf_target": conf_target,
+                "blocks": estimate.blocks,
+                "fee_rate": estimate.fee_rate.map(|fee_rate| fee_rate.to_btc()),
+                "estimate_mode": session.estimate_mode,
+            }));
+        }
+        Err(e) => err!(session, "Failed to estimate fee. Error {:?}", e)
+    }
+}
+
+// Answers collected so far by the guided send, the first missing one is what's being asked
+#[derive(Default)]
+pub struct SendWizard {
+    address: Option<Address>,
+    amount: Option<Amount>,
+    // Some(None) is an explicit "use the node's estimate"
+    fee_rate: Option<Option<f64>>,
+}
+
+pub fn start_send_wizard(session: &mut Session) {
+    out!(session, "Guided send, enter a blank line at any prompt to cancel");
+    out!(session, "Destination address or alias:");
+    session.pending_action = Some(PendingAction::SendWizard(SendWizard::default()));
+}
+
+// Runs as a pending action, one line per prompt, so the mine timer keeps ticking between answers
+pub fn advance_send_wizard(session: &mut Session, mut wizard: SendWizard, line: &str) {
+    let answer = line.trim();
+    if answer.is_empty() {
+        out!(session, "Send cancelled");
+        return;
+    }
+
+    match (&wizard.address, wizard.amount, wizard.fee_rate) {
+        (None, _, _) => match session.resolve_address(answer) {
+            Ok(address) => {
+                wizard.address = Some(address);
+                out!(session, "Amount, e.g. 0.5 or 0.5 BTC or 50000 sat:");
+            }
+            Err(e) => err!(session, "{}, try again:", e),
+        },
+        (Some(address), None, _) => match parse_amount_with_unit(answer).and_then(|amount| check_dust(amount, address).map(|_| amount)) {
+            Ok(amount) => {
+                wizard.amount = Some(amount);
+                out!(session, "Fee rate in sat/vB, or 'auto' for the node's estimate:");
+            }
+            Err(e) => err!(session, "Invalid amount: {}, try again:", e),
+        },
+        (Some(address), Some(amount), None) => {
+            let fee_rate = match answer {
+                "auto" => Ok(None),
+                _ => parse_fee_rate(answer).map(Some),
+            };
+            match fee_rate {
+                Ok(fee_rate) => {
+                    wizard.fee_rate = Some(fee_rate);
+                    let fee = fee_rate.map_or("node estimate".to_string(), |fee_rate| format!("{} sat/vB", fee_rate));
+                    out!(session, "Send {} to {} with fee {}? Type 'yes' to confirm", amount, address, fee);
+                }
+                Err(e) => err!(session, "{} (or 'auto'), try again:", e),
+            }
+        }
+        (Some(address), Some(amount), Some(fee_rate)) => {
+            if matches!(answer, "yes" | "y") {
+                let options = SendOptions { fee_rate, ..Default::default() };
+                send_to_address(session, &address.to_string(), amount, &options);
+            } else {
+                out!(session, "Send cancelled");
+            }
+            return;
+        }
+    }
+    session.pending_action = Some(PendingAction::SendWizard(wizard));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bitcoin::{Network, ScriptBuf};
+
+    use super::*;
+    use crate::commands::handle_input_line;
+    use crate::testing::{test_session, ScriptedNode};
+
+    fn regtest_address(script: &[u8]) -> String {
+        Address::p2wsh(&ScriptBuf::from_bytes(script.to_vec()), Network::Regtest).to_string()
+    }
+
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    // A fresh log path per test so parallel tests never share one
+    fn idempotency_log(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("btc-miner-{}-{}.log", std::process::id(), test));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn trusted_balance(btc: f64) -> serde_json::Value {
+        json!({ "mine": { "trusted": btc, "untrusted_pending": 0.0, "immature": 0.0 } })
+    }
+
+    #[test]
+    fn sendmany_and_sweep_reject_sendtoaddress_only_options() {
+        let node = ScriptedNode::default();
+        let (mut session, out) = test_session(&node);
+        let address = regtest_address(&[]);
+
+        for line in [
+            format!("sendmany {}=1 id=payout-1", address),
+            format!("sendmany {}=1 --estimate-size", address),
+            format!("sweep {} id=payout-1", address),
+            format!("sweep {} --estimate-size", address),
+        ] {
+            assert!(!handle_input_line(&mut session, line.clone()), "{} was accepted", line);
+        }
+
+        assert_eq!(out.errors.borrow().iter().filter(|e| e.contains("only sendtoaddress does")).count(), 4);
+        assert!(out.error_text().contains("sendmany does not take id="));
+        assert!(out.error_text().contains("sweep does not take --estimate-size"));
+        assert!(node.calls().is_empty());
+    }
+
+    #[test]
+    fn sum_amounts_rejects_totals_above_max_money() {
+        assert_eq!(sum_amounts([Amount::ONE_BTC, Amount::ONE_BTC]).unwrap(), Amount::from_btc(2.0).unwrap());
+        assert!(matches!(sum_amounts([Amount::MAX_MONEY, Amount::ONE_SAT]), Err(MinerError::TotalOverflow)));
+        assert!(matches!(sum_amounts([Amount::MAX, Amount::MAX]), Err(MinerError::TotalOverflow)));
+    }
+
+    #[test]
+    fn sendmany_refuses_a_total_above_the_balance_without_sending() {
+        let node = ScriptedNode::default();
+        node.respond("getbalances", trusted_balance(1.5));
+        let (mut session, out) = test_session(&node);
+
+        handle_input_line(&mut session, format!("sendmany {}=1 {}=1", regtest_address(&[]), regtest_address(&[0x51])));
+
+        assert_eq!(out.error_text(), "total 2 BTC exceeds balance 1.50000000 BTC");
+        assert_eq!(node.calls(), ["getbalances"]);
+    }
+
+    #[test]
+    fn sendmany_reports_an_overflowing_total_before_asking_for_the_balance() {
+        let node = ScriptedNode::default();
+        let (mut session, out) = test_session(&node);
+
+        handle_input_line(&mut session, format!("sendmany {}=20000000 {}=20000000", regtest_address(&[]), regtest_address(&[0x51])));
+
+        assert!(out.error_text().contains("total of the amounts exceeds the maximum"), "{}", out.error_text());
+        assert!(node.calls().is_empty());
+    }
+
+    #[test]
+    fn recorded_send_id_is_not_broadcast_again() {
+        let node = ScriptedNode::default();
+        node.respond("sendtoaddress", json!(TXID));
+        let (mut session, out) = test_session(&node);
+        let log = idempotency_log("recorded-send");
+        session.idempotency_log = Some(log.clone());
+        let line = format!("sendtoaddress {} 0.5 id=payout-1", regtest_address(&[]));
+
+        handle_input_line(&mut session, line.clone());
+        handle_input_line(&mut session, line);
+
+        assert_eq!(node.count("sendtoaddress"), 1);
+        assert!(out.text().ends_with(&format!("Already sent (id=payout-1), TxID: {}", TXID)), "{}", out.text());
+        assert_eq!(out.json.borrow().last().unwrap()["already_sent"], true);
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn id_recorded_by_an_earlier_run_skips_the_broadcast() {
+        let node = ScriptedNode::default();
+        let (mut session, out) = test_session(&node);
+        let address = regtest_address(&[]);
+        let log = idempotency_log("earlier-run");
+        let key = send_key(&session.resolve_address(&address).unwrap(), Amount::from_btc(0.5).unwrap(), "payout-1");
+        fs::write(&log, format!("{} {}\n", key, TXID)).unwrap();
+        session.idempotency_log = Some(log.clone());
+
+        handle_input_line(&mut session, format!("sendtoaddress {} 0.5 id=payout-1", address));
+
+        assert!(node.calls().is_empty());
+        assert_eq!(out.text(), format!("Already sent (id=payout-1), TxID: {}", TXID));
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn a_different_id_or_amount_is_a_new_send() {
+        let node = ScriptedNode::default();
+        node.respond("sendtoaddress", json!(TXID));
+        let (mut session, _) = test_session(&node);
+        let address = regtest_address(&[]);
+        let log = idempotency_log("different-send");
+        session.idempotency_log = Some(log.clone());
+
+        handle_input_line(&mut session, format!("sendtoaddress {} 0.5 id=payout-1", address));
+        handle_input_line(&mut session, format!("sendtoaddress {} 0.5 id=payout-2", address));
+        handle_input_line(&mut session, format!("sendtoaddress {} 0.6 id=payout-1", address));
+
+        assert_eq!(node.count("sendtoaddress"), 3);
+        assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 3);
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn fee_rates_must_be_finite_positive_and_under_the_cap() {
+        assert_eq!(parse_fee_rate("2.5"), Ok(2.5));
+        assert_eq!(parse_fee_rate("10000"), Ok(MAX_FEE_RATE_SAT_PER_VB));
+        assert_eq!(parse_fee_rate("10000.5"), Err("Fee rate 10000.5 sat/vB is above the maximum of 10000 sat/vB".to_string()));
+        for value in ["0", "-1", "inf", "NaN", "", "fast"] {
+            assert_eq!(parse_fee_rate(value), Err(format!("Invalid fee rate '{}', expected a positive sat/vB value", value)));
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use bitcoin::{Address, Amount, Network, Txid};
+use bitcoincore_rpc::{Auth, Client};
+use bitcoincore_rpc::json::{AddressType, EstimateMode};
+use rand::rngs::StdRng;
+
+use crate::activity::ActivityLog;
+use crate::events::{Event, EventStream};
+use crate::mining::InterruptTrap;
+use crate::output::Output;
+use crate::rpc::{batch_calls, connect, wallet_url, MinerClient};
+use crate::rawtx::CapturedTx;
+use crate::send::SendWizard;
+use crate::snapshot::Snapshot;
+use crate::wallet::StableWait;
+
+// How often run_blocking notes that a slow call is still going
+const SLOW_CALL_NOTICE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Destructive actions wait here until the user answers "yes" on the next line
+pub enum PendingAction {
+    AbandonAll(Vec<Txid>),
+    Flood { count: u64, amount: Amount },
+    // Not a yes/no question: each line answers the wizard's current prompt
+    SendWizard(SendWizard),
+    LoadSnapshot(String),
+}
+
+// State shared by all commands: the RPC connection plus anything the user set up during the session
+pub struct Session {
+    pub rpc_client: MinerClient,
+    pub rpc_url: String,
+    pub rpc_auth: Auth,
+    pub rpc_timeout: Duration,
+    pub out: Rc<dyn Output>,
+    pub activity_log: Option<Rc<ActivityLog>>,
+    pub events: Option<Rc<EventStream>>,
+    pub network: Network,
+    pub aliases: BTreeMap<String, Address>,
+    pub alias_file: Option<PathBuf>,
+    pub idempotency_log: Option<PathBuf>,
+    pub pending_action: Option<PendingAction>,
+    pub confirm_target: Option<u32>,
+    // Passed to fee estimation for sends without an explicit fee rate; None leaves the node's default
+    pub estimate_mode: Option<EstimateMode>,
+    // Address type for change outputs; None leaves the node's -changetype default
+    pub change_type: Option<AddressType>,
+    // Look up and print the fee paid after each send
+    pub show_fee: bool,
+    pub extra_nodes: Vec<String>,
+    // Watched address -> amount received when last polled
+    pub watches: BTreeMap<String, (Address, Amount)>,
+    // note command: block height -> annotation
+    pub notes: BTreeMap<u64, String>,
+    // snapshot save: name -> recorded chain tip
+    pub snapshots: BTreeMap<String, Snapshot>,
+    pub snapshot_file: Option<PathBuf>,
+    // replaytx capture: txid -> the transaction as first seen
+    pub captured_txs: BTreeMap<Txid, CapturedTx>,
+    // mempoolwatch: the mempool as of the last tick, with fees
+    pub mempool_watch: Option<HashMap<Txid, Amount>>,
+    // waitstable: the balance being watched until it settles
+    pub stable_wait: Option<StableWait>,
+    // Set by the quit command, checked by the main loop before reading the next line
+    pub quit_requested: bool,
+    pub auto_mine: bool,
+    // Mine whatever is in the mempool after each successful send instead of on the timer
+    pub auto_mine_on_send: bool,
+    // Mine timer period and +/- jitter, adjustable with automine interval
+    pub mine_interval_secs: u64,
+    pub mine_interval_jitter: u64,
+    // Set when the interval changes, so the main loop reschedules the pending tick
+    pub mine_schedule_changed: bool,
+    // When the pending mine timer tick fires, for eta
+    pub next_mine_at: Cell<Option<Instant>>,
+    // --mine-to-address, cleared if the node starts rejecting it mid-session
+    pub mine_to_address: RefCell<Option<Address>>,
+    // Returned by receive instead of a fresh address
+    pub receive_address: Option<Address>,
+    // Mine a block after every successful send (regtest only)
+    pub confirm_sends: bool,
+    // --allow-key-export: permits dumpwallet
+    pub allow_key_export: bool,
+    // --allow-chain-reset: permits resetchain
+    pub allow_chain_reset: bool,
+    // Blocks this tool has mined since startup
+    pub blocks_mined: Cell<u64>,
+    // --no-startup-checks: the startup info has not been shown yet
+    pub startup_checks_pending: bool,
+    // Set by the first simulate, which takes Ctrl-C over for the rest of the session
+    pub interrupt_trap: Option<Arc<InterruptTrap>>,
+    // Seeded by --seed, shared by the mine timer jitter and simulate
+    pub rng: RefCell<StdRng>,
+}
+
+impl Session {
+    // Another wallet on the primary node, with the same recovery as the session's own client
+    pub fn wallet_client(&self, wallet_name: &str) -> Result<MinerClient, bitcoincore_rpc::Error> {
+        self.rpc_client.wallet(wallet_name)
+    }
+
+    pub fn node_client(&self, url: &str) -> Result<Client, bitcoincore_rpc::Error> {
+        connect(url, &self.rpc_auth, self.rpc_timeout)
+    }
+
+    // Runs a slow call (gettxoutsetinfo, an import that rescans) on tokio's blocking pool over its own
+    // connection, noting every few seconds that it is still going. That connection has none of MinerClient's recovery
+    pub fn run_blocking<T: Send + 'static>(
+        &self,
+        what: &str,
+        call: impl FnOnce(&Client) -> Result<T, bitcoincore_rpc::Error> + Send + 'static,
+    ) -> Result<T, bitcoincore_rpc::Error> {
+        let client = self.node_client(&wallet_url(&self.rpc_url, self.rpc_client.wallet_name()))?;
+        let (sender, receiver) = mpsc::channel();
+        tokio::task::spawn_blocking(move || {
+            let _ = sender.send(call(&client));
+        });
+
+        let started = Instant::now();
+        loop {
+            match receiver.recv_timeout(SLOW_CALL_NOTICE_INTERVAL) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Timeout) => out!(self, "Still {} ({}s elapsed)", what, started.elapsed().as_secs()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!("{} stopped without a result", what)))
+                }
+            }
+        }
+    }
+
+    // The primary node first, followed by every --node endpoint
+    pub fn node_urls(&self) -> Vec<String> {
+        let mut urls = vec![self.rpc_url.clone()];
+        urls.extend(self.extra_nodes.iter().cloned());
+        urls
+    }
+
+    // Runs the same query against every configured node concurrently, results in node_urls() order
+    pub fn query_nodes<T: Send>(
+        &self,
+        query: impl Fn(&Client) -> Result<T, bitcoincore_rpc::Error> + Sync,
+    ) -> Vec<Result<T, bitcoincore_rpc::Error>> {
+        let urls = self.node_urls();
+        let (auth, timeout) = (&self.rpc_auth, self.rpc_timeout);
+        let query = &query;
+
+        // Each node gets its own blocking client, so query them all at once
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = urls.iter()
+                .map(|url| scope.spawn(move || query(&connect(url, auth, timeout)?)))
+                .collect();
+
+            handles.into_iter().map(|handle| handle.join().expect("node query panicked")).collect()
+        })
+    }
+
+    // The same reads sent to every configured node as one batch each, results in node_urls() order
+    pub fn batch_nodes(
+        &self,
+        calls: &[(&str, Vec<serde_json::Value>)],
+    ) -> Vec<Result<Vec<Result<serde_json::Value, bitcoincore_rpc::Error>>, bitcoincore_rpc::Error>> {
+        self.node_urls().iter()
+            .map(|url| batch_calls(&self.node_client(url)?, calls))
+            .collect()
+    }
+
+    pub fn is_regtest(&self) -> bool {
+        self.network == Network::Regtest
+    }
+
+    // Guard for actions that only make sense (or are only safe) on a throwaway chain
+    pub fn require_regtest(&self, action: &str) -> bool {
+        if !self.is_regtest() {
+            err!(self, "{} is only available on regtest, the node is on {}", action, self.network);
+            return false;
+        }
+        true
+    }
+
+    pub fn record_blocks_mined(&self, count: usize) {
+        self.blocks_mined.set(self.blocks_mined.get() + count as u64);
+        self.emit(&Event::BlocksMined { count: count as u64, total_mined: self.blocks_mined.get() });
+    }
+
+    // Called by every command that broadcasts a transaction
+    pub fn record_transaction_sent(&self, txid: &Txid) {
+        self.emit(&Event::TransactionSent { txid });
+    }
+
+    pub fn emit(&self, event: &Event) {
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+    }
+
+    // Accepts either a registered alias or an address valid for the node's network
+    pub fn resolve_address(&self, address_or_alias: &str) -> Result<Address, String> {
+        if let Some(address) = self.aliases.get(address_or_alias) {
+            return Ok(address.clone());
+        }
+
+        parse_address(address_or_alias, self.network)
+    }
+
+    pub fn add_alias(&mut self, name: &str, address_string: &str) {
+        let address = match parse_address(address_string, self.network) {
+            Ok(address) => address,
+            Err(e) => {
+                err!(self, "{}", e);
+                return;
+            }
+        };
+
+        out!(self, "{} -> {}", name, address);
+        self.aliases.insert(name.to_string(), address);
+        self.save_aliases();
+    }
+
+    pub fn list_aliases(&self) {
+        if self.aliases.is_empty() {
+            out!(self, "No aliases registered");
+        }
+        for (name, address) in &self.aliases {
+            out!(self, "{:<16} {}", name, address);
+        }
+    }
+
+    pub fn load_aliases(&mut self) {
+        let Some(path) = &self.alias_file else { return };
+        let Ok(contents) = fs::read_to_string(path) else { return };
+
+        for line in contents.lines() {
+            let mut parts = line.split_whitespace();
+            if let (Some(name), Some(address_string)) = (parts.next(), parts.next()) {
+                match parse_address(address_string, self.network) {
+                    Ok(address) => {
+                        self.aliases.insert(name.to_string(), address);
+                    }
+                    Err(e) => err!(self, "Skipping alias {}: {}", name, e),
+                }
+            }
+        }
+    }
+
+    fn save_aliases(&self) {
+        let Some(path) = &self.alias_file else { return };
+        let contents: String = self.aliases.iter()
+            .map(|(name, address)| format!("{} {}\n", name, address))
+            .collect();
+
+        if let Err(e) = fs::write(path, contents) {
+            err!(self, "Failed to write alias file {}: {}", path.display(), e);
+        }
+    }
+}
+
+pub fn parse_address(address_string: &str, network: Network) -> Result<Address, String> {
+    Address::from_str(address_string)
+        .map_err(|e| format!("Error parsing address {:?}", e))?
+        .require_network(network)
+        .map_err(|e| format!("Address {} is not valid for {}: {}", address_string, network, e))
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use std::str::FromStr;
+
+use bitcoin::BlockHash;
+use bitcoincore_rpc::RpcApi;
+use serde_json::json;
+
+use crate::session::{PendingAction, Session};
+
+// Invalidate/reconsider rounds a load makes before giving up, each one switches the node to another branch
+const MAX_RESTORE_STEPS: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub tip: BlockHash,
+    pub height: u64,
+    // Where snapshot save wrote a wallet backup, if it was asked to
+    pub backup: Option<String>,
+}
+
+// --snapshot-file: one "<name> <tip hash> <height> [backup path]" line per snapshot
+pub fn load_snapshots(session: &mut Session) {
+    let Some(path) = &session.snapshot_file else { return };
+    let Ok(contents) = fs::read_to_string(path) else { return };
+
+    for line in contents.lines() {
+        let mut parts = line.splitn(4, ' ');
+        let (Some(name), Some(tip), Some(height)) = (parts.next(), parts.next(), parts.next()) else { continue };
+        match (BlockHash::from_str(tip), height.parse()) {
+            (Ok(tip), Ok(height)) => {
+                let backup = parts.next().map(str::to_string);
+                session.snapshots.insert(name.to_string(), Snapshot { tip, height, backup });
+            }
+            _ => err!(session, "Skipping snapshot {}: malformed line in {}", name, path.display()),
+        }
+    }
+}
+
+fn persist_snapshots(session: &Session) {
+    let Some(path) = &session.snapshot_file else { return };
+    let contents: String = session.snapshots.iter()
+        .map(|(name, snapshot)| match &snapshot.backup {
+            Some(backup) => format!("{} {} {} {}\n", name, snapshot.tip, snapshot.height, backup),
+            None => format!("{} {} {}\n", name, snapshot.tip, snapshot.height),
+        })
+        .collect();
+
+    if let Err(e) = fs::write(path, contents) {
+        err!(session, "Failed to write snapshot file {}: {}", path.display(), e);
+    }
+}
+
+pub fn save_snapshot(session: &mut Session, name: &str, backup: Option<&str>) {
+    let rpc_client = &session.rpc_client;
+    let (tip, height) = match rpc_client.get_best_block_hash().and_then(|hash| Ok((hash, rpc_client.get_block_count()?))) {
+        Ok(tip) => tip,
+        Err(e) => {
+            err!(session, "Failed to get the chain tip. Error {:?}", e);
+            return;
+        }
+    };
+    if let Some(path) = backup {
+        if let Err(e) = rpc_client.backup_wallet(Some(path)) {
+            err!(session, "Failed to back up the wallet to {}. Error {:?}", path, e);
+            return;
+        }
+        out!(session, "Wallet backed up to {}", path);
+    }
+
+    let snapshot = Snapshot { tip, height, backup: backup.map(str::to_string) };
+    if session.snapshots.insert(name.to_string(), snapshot).is_some() {
+        out!(session, "Replaced snapshot {}", name);
+    }
+    persist_snapshots(session);
+    out!(session, "Saved snapshot {}: {} at height {}", name, tip, height);
+    session.out.json(&json!({ "name": name, "tip": tip, "height": height, "backup": backup }));
+}
+
+pub fn list_snapshots(session: &Session) {
+    if session.snapshots.is_empty() {
+        out!(session, "No snapshots saved, create one with snapshot save <name>");
+    }
+    for (name, snapshot) in &session.snapshots {
+        match &snapshot.backup {
+            Some(backup) => out!(session, "{}: {} at height {} (wallet backup {})", name, snapshot.tip, snapshot.height, backup),
+            None => out!(session, "{}: {} at height {}", name, snapshot.tip, snapshot.height),
+        }
+    }
+
+    let snapshots: Vec<serde_json::Value> = session.snapshots.iter()
+        .map(|(name, snapshot)| json!({ "name": name, "tip": snapshot.tip, "height": snapshot.height, "backup": snapshot.backup }))
+        .collect();
+    session.out.json(&json!({ "snapshots": snapshots }));
+}
+
+// Loading rewrites the active chain, so it waits for a "yes" like the other destructive commands
+pub fn request_snapshot_load(session: &mut Session, name: &str) {
+    if !session.require_regtest("snapshot load") {
+        return;
+    }
+    let Some(snapshot) = session.snapshots.get(name) else {
+        err!(session, "Unknown snapshot {}, see snapshot list", name);
+        return;
+    };
+
+    out!(
+        session,
+        "About to move the chain back to snapshot {} ({} at height {}), invalidating any blocks built on top. Type 'yes' to confirm",
+        name, snapshot.tip, snapshot.height
+    );
+    session.pending_action = Some(PendingAction::LoadSnapshot(name.to_string()));
+}
+
+// Height of the newest block that is both an ancestor of tip and on the active chain
+fn fork_height(session: &Session, tip: &BlockHash) -> Result<u64, bitcoincore_rpc::Error> {
+    let rpc_client = &session.rpc_client;
+    let mut header = rpc_client.get_block_header_info(tip)?;
+    loop {
+        let height = header.height as u64;
+        if rpc_client.get_block_hash(height).is_ok_and(|hash| hash == header.hash) {
+            return Ok(height);
+        }
+        let Some(previous) = header.previous_block_hash else { return Ok(0) };
+        header = rpc_client.get_block_header_info(&previous)?;
+    }
+}
+
+// Makes the snapshot tip valid again, then invalidates whatever the node has activated past or
+// beside it until it is the active tip
+fn restore_tip(session: &Session, snapshot: &Snapshot) -> Result<u64, bitcoincore_rpc::Error> {
+    let rpc_client = &session.rpc_client;
+    rpc_client.reconsider_block(&snapshot.tip)?;
+
+    let mut invalidated = 0;
+    for _ in 0..MAX_RESTORE_STEPS {
+        if rpc_client.get_best_block_hash()? == snapshot.tip {
+            return Ok(invalidated);
+        }
+        // Past the snapshot on the same branch, or on another branch since their fork point
+        let keep_height = fork_height(session, &snapshot.tip)?;
+        let first_extra = rpc_client.get_block_hash(keep_height + 1)?;
+        invalidated += rpc_client.get_block_count()?.saturating_sub(keep_height);
+        rpc_client.invalidate_block(&first_extra)?;
+    }
+
+    Err(bitcoincore_rpc::Error::ReturnedError(format!(
+        "chain tip is still not {} after {} invalidations", snapshot.tip, MAX_RESTORE_STEPS
+    )))
+}
+
+pub fn load_snapshot(session: &Session, name: &str) {
+    let Some(snapshot) = session.snapshots.get(name) else {
+        err!(session, "Unknown snapshot {}, see snapshot list", name);
+        return;
+    };
+
+    match restore_tip(session, snapshot) {
+        Ok(invalidated) => {
+            out!(session, "Loaded snapshot {}: tip {} at height {} ({} block(s) invalidated)", name, snapshot.tip, snapshot.height, invalidated);
+            if let Some(backup) = &snapshot.backup {
+                out!(session, "The wallet is unchanged, restore {} with restorewallet to roll it back too", backup);
+            }
+            session.out.json(&json!({ "name": name, "tip": snapshot.tip, "height": snapshot.height, "invalidated": invalidated }));
+        }
+        Err(e) => err!(session, "Failed to load snapshot {}. Error {:?}", name, e),
+    }
+}
@@ -0,0 +1,192 @@
+// Test doubles shared by the unit tests: a scripted node, an output that records what it was given,
+// and a regtest session wired to both
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+use bitcoin::Network;
+
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::{Auth, Error};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::output::Output;
+use crate::rpc::{MinerClient, NodeTransport};
+use crate::session::Session;
+
+pub fn rpc_error(code: i32, message: &str) -> Error {
+    Error::JsonRpc(jsonrpc::error::Error::Rpc(jsonrpc::error::RpcError {
+        code,
+        message: message.to_string(),
+        data: None,
+    }))
+}
+
+// A scripted result or an (RPC error code, message) to fail with
+type ScriptedResponse = Result<serde_json::Value, (i32, String)>;
+
+// Answers each method from its queue of scripted responses, in order; the last one repeats once the
+// queue is down to it. Clones share the queues, the call log and the round trip count. Calls made through
+// another wallet's endpoint are scripted and logged as "<wallet>/<method>"
+#[derive(Clone, Default)]
+pub struct ScriptedNode {
+    wallet_prefix: String,
+    responses: Rc<RefCell<HashMap<String, VecDeque<ScriptedResponse>>>>,
+    calls: Rc<RefCell<Vec<String>>>,
+    accepts_batches: Rc<Cell<bool>>,
+    round_trips: Rc<Cell<usize>>,
+}
+
+impl ScriptedNode {
+    pub fn respond(&self, cmd: &str, value: serde_json::Value) -> &Self {
+        self.push(cmd, Ok(value))
+    }
+
+    // Queued alongside respond(), so "fail once then succeed" is fail(..) followed by respond(..)
+    pub fn fail(&self, cmd: &str, code: i32, message: &str) -> &Self {
+        self.push(cmd, Err((code, message.to_string())))
+    }
+
+    fn push(&self, cmd: &str, response: ScriptedResponse) -> &Self {
+        self.responses.borrow_mut().entry(cmd.to_string()).or_default().push_back(response);
+        self
+    }
+
+    // Off by default, like a node that rejects batches
+    pub fn accept_batches(&self) -> &Self {
+        self.accepts_batches.set(true);
+        self
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    pub fn count(&self, cmd: &str) -> usize {
+        self.calls.borrow().iter().filter(|call| *call == cmd).count()
+    }
+
+    // Requests sent, a whole batch counting as one
+    pub fn round_trips(&self) -> usize {
+        self.round_trips.get()
+    }
+
+    fn answer(&self, cmd: &str) -> Result<serde_json::Value, Error> {
+        let cmd = &format!("{}{}", self.wallet_prefix, cmd);
+        self.calls.borrow_mut().push(cmd.to_string());
+        let mut responses = self.responses.borrow_mut();
+        let response = responses.get_mut(cmd)
+            .and_then(|queue| if queue.len() > 1 { queue.pop_front() } else { queue.front().cloned() });
+        match response {
+            Some(Ok(value)) => Ok(value),
+            Some(Err((code, message))) => Err(rpc_error(code, &message)),
+            None => Err(rpc_error(-32601, &format!("Method not found: {} is not scripted", cmd))),
+        }
+    }
+}
+
+impl NodeTransport for ScriptedNode {
+    fn call_value(&self, cmd: &str, _args: &[serde_json::Value]) -> Result<serde_json::Value, Error> {
+        self.round_trips.set(self.round_trips.get() + 1);
+        self.answer(cmd)
+    }
+
+    fn call_batch(&self, calls: &[(&str, Vec<serde_json::Value>)]) -> Result<Vec<Option<Result<serde_json::Value, Error>>>, Error> {
+        self.round_trips.set(self.round_trips.get() + 1);
+        if !self.accepts_batches.get() {
+            return Err(rpc_error(-32600, "batching is not scripted"));
+        }
+        Ok(calls.iter().map(|(cmd, _)| Some(self.answer(cmd))).collect())
+    }
+
+    fn wallet(&self, wallet_name: &str) -> Result<Box<dyn NodeTransport>, Error> {
+        Ok(Box::new(ScriptedNode { wallet_prefix: format!("{}/", wallet_name), ..self.clone() }))
+    }
+}
+
+#[derive(Default)]
+pub struct RecordingOutput {
+    pub human: RefCell<Vec<String>>,
+    pub json: RefCell<Vec<serde_json::Value>>,
+    pub errors: RefCell<Vec<String>>,
+}
+
+impl RecordingOutput {
+    pub fn text(&self) -> String {
+        self.human.borrow().join("\n")
+    }
+
+    pub fn error_text(&self) -> String {
+        self.errors.borrow().join("\n")
+    }
+}
+
+impl Output for RecordingOutput {
+    fn human(&self, message: &str) {
+        self.human.borrow_mut().push(message.to_string());
+    }
+
+    fn json(&self, value: &serde_json::Value) {
+        self.json.borrow_mut().push(value.clone());
+    }
+
+    fn error(&self, message: &str) {
+        self.errors.borrow_mut().push(message.to_string());
+    }
+
+    fn progress(&self, _label: &str, _done: u64, _total: u64) {}
+}
+
+pub fn test_client(node: &ScriptedNode, auto_reload_wallet: bool, out: Rc<RecordingOutput>) -> MinerClient {
+    MinerClient::new(node.clone(), "test".to_string(), auto_reload_wallet, out)
+}
+
+// A regtest session talking to the scripted node, with everything else at its startup defaults
+pub fn test_session(node: &ScriptedNode) -> (Session, Rc<RecordingOutput>) {
+    let out = Rc::new(RecordingOutput::default());
+    let session = Session {
+        rpc_client: test_client(node, false, out.clone()),
+        rpc_url: "http://127.0.0.1:1".to_string(),
+        rpc_auth: Auth::None,
+        rpc_timeout: Duration::from_secs(1),
+        out: out.clone(),
+        activity_log: None,
+        events: None,
+        network: Network::Regtest,
+        aliases: BTreeMap::new(),
+        alias_file: None,
+        idempotency_log: None,
+        pending_action: None,
+        confirm_target: None,
+        estimate_mode: None,
+        change_type: None,
+        show_fee: false,
+        extra_nodes: Vec::new(),
+        watches: BTreeMap::new(),
+        notes: BTreeMap::new(),
+        snapshots: BTreeMap::new(),
+        snapshot_file: None,
+        captured_txs: BTreeMap::new(),
+        mempool_watch: None,
+        stable_wait: None,
+        quit_requested: false,
+        auto_mine: false,
+        auto_mine_on_send: false,
+        mine_interval_secs: 15,
+        mine_interval_jitter: 0,
+        mine_schedule_changed: false,
+        next_mine_at: Cell::new(None),
+        mine_to_address: RefCell::new(None),
+        receive_address: None,
+        confirm_sends: false,
+        allow_key_export: false,
+        allow_chain_reset: false,
+        blocks_mined: Cell::new(0),
+        startup_checks_pending: false,
+        interrupt_trap: None,
+        rng: RefCell::new(StdRng::seed_from_u64(0)),
+    };
+    (session, out)
+}
@@ -0,0 +1,1552 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Amount, OutPoint, PubkeyHash, PublicKey, SignedAmount, Txid};
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::json::{
+    GetTransactionResultDetailCategory, ImportDescriptors, ListTransactionResult, ListUnspentQueryOptions, Timestamp,
+};
+use serde_json::json;
+
+use crate::amount::{check_dust, format_btc_value, parse_amount};
+use crate::error::MinerError;
+use crate::mempool::RPC_INVALID_ADDRESS_OR_KEY;
+use crate::mining::mining_address;
+use crate::rpc::{rpc_error_code, rpc_error_message, MinerClient, RPC_WALLET_NOT_FOUND};
+use crate::send::{call_send_to_address, spendable_balance};
+use crate::session::{PendingAction, Session};
+
+// Fee rates above this (in BTC/kvB) are almost certainly a typo
+const HIGH_FEE_RATE_BTC_PER_KB: f64 = 0.01;
+const LIST_TRANSACTIONS_PAGE_SIZE: usize = 500;
+pub const DEFAULT_DESCRIPTOR_ADDRESS_COUNT: u32 = 1;
+// Keys derived up front for a ranged --seed-descriptor
+const SEED_DESCRIPTOR_RANGE: (usize, usize) = (0, 999);
+// Consensus limit on keys in a P2WSH multisig
+pub const MAX_MULTISIG_KEYS: usize = 20;
+pub const DEFAULT_KEYPOOL_SIZE: u64 = 100;
+// Returned for key operations on an encrypted wallet that is locked
+const RPC_WALLET_UNLOCK_NEEDED: i32 = -13;
+
+// Loads the wallet, creating a blank one first if it doesn't exist and create_if_missing is set
+pub fn open_wallet(session: &Session, wallet_name: &str, create_if_missing: bool) {
+    match session.rpc_client.load_wallet(wallet_name) {
+        Ok(v) => out!(session, "Loaded wallet {}", v.name),
+        Err(e) if create_if_missing && rpc_error_code(&e) == Some(RPC_WALLET_NOT_FOUND) => {
+            match session.rpc_client.create_wallet(wallet_name, None, Some(true), None, None) {
+                Ok(v) => out!(session, "Created blank wallet {}", v.name),
+                Err(e) => err!(session, "Failed to create wallet {:?}", e),
+            }
+        }
+        Err(e) => {
+            err!(session, "Failed to load wallet {:?}", e);
+        }
+    }
+}
+
+// Imports a known descriptor (rescanning from genesis) so every run starts with the same keys
+pub fn seed_wallet_descriptor(session: &Session, descriptor: &str) {
+    let info = match session.rpc_client.get_descriptor_info(descriptor) {
+        Ok(info) => info,
+        Err(e) => {
+            err!(session, "Invalid seed descriptor. Error {}", e);
+            return;
+        }
+    };
+
+    if !info.has_private_keys {
+        out!(session, "Warning: seed descriptor has no private keys, the wallet will only be able to watch it");
+    }
+
+    // getdescriptorinfo normalises the descriptor and drops private keys, so keep the original and only add the checksum
+    let descriptor = match (descriptor.contains('#'), &info.checksum) {
+        (false, Some(checksum)) => format!("{}#{}", descriptor, checksum),
+        _ => descriptor.to_string(),
+    };
+
+    let request = ImportDescriptors {
+        descriptor,
+        timestamp: Timestamp::Time(0),
+        active: Some(info.is_range),
+        range: info.is_range.then_some(SEED_DESCRIPTOR_RANGE),
+        internal: Some(false),
+        ..Default::default()
+    };
+
+    out!(session, "Importing seed descriptor and rescanning, this may take a while");
+    match session.rpc_client.import_descriptors(request) {
+        Ok(results) => {
+            for result in results {
+                for warning in &result.warnings {
+                    out!(session, "Import warning: {}", warning);
+                }
+                match (result.success, result.error) {
+                    (true, _) => out!(session, "Imported seed descriptor, rescan complete"),
+                    (false, Some(e)) => err!(session, "Failed to import seed descriptor: {}", e.message),
+                    (false, None) => err!(session, "Failed to import seed descriptor"),
+                }
+            }
+        }
+        Err(e) => err!(session, "Failed to import seed descriptor. Error {:?}", e)
+    }
+}
+
+pub fn check_balance(session: &Session) {
+    match session.rpc_client.get_balance(None, None) {
+        Ok(balance) => {
+            out!(session, "Current balance: {}", balance);
+            session.out.json(&json!({ "balance": balance.to_btc() }));
+        }
+        Err(e) => err!(session, "Failed to get balance. Error {:?}", e)
+    }
+}
+
+// bitcoind rejects addresses and labels the wallet has never seen, which for our purposes just means nothing was received
+pub fn is_not_in_wallet_error(e: &bitcoincore_rpc::Error) -> bool {
+    e.to_string().contains("not found in wallet")
+}
+
+pub fn show_received_by_address(session: &Session, address_string: &str, minconf: Option<u32>) {
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+
+    match session.rpc_client.get_received_by_address(&address, minconf) {
+        Ok(amount) => out!(session, "Received by {}: {}", address, amount),
+        Err(e) if is_not_in_wallet_error(&e) => out!(session, "Received by {}: {} (not a wallet address)", address, Amount::ZERO),
+        Err(e) => err!(session, "Failed to get received amount for {}. Error {:?}", address, e)
+    }
+}
+
+pub fn show_received_by_label(session: &Session, label: &str, minconf: Option<u32>) {
+    let mut params = vec![label.into()];
+    if let Some(minconf) = minconf {
+        params.push(minconf.into());
+    }
+
+    match session.rpc_client.call::<f64>("getreceivedbylabel", &params) {
+        Ok(btc) => match Amount::from_btc(btc) {
+            Ok(amount) => out!(session, "Received by label '{}': {}", label, amount),
+            Err(e) => err!(session, "Node returned invalid amount {}. Error {:?}", btc, e),
+        },
+        Err(e) if is_not_in_wallet_error(&e) => out!(session, "Received by label '{}': {} (unknown label)", label, Amount::ZERO),
+        Err(e) => err!(session, "Failed to get received amount for label '{}'. Error {:?}", label, e)
+    }
+}
+
+pub fn show_labels(session: &Session) {
+    let rpc_client = &session.rpc_client;
+    let labels = match rpc_client.call::<Vec<String>>("listlabels", &[]) {
+        Ok(labels) => labels,
+        Err(e) => {
+            err!(session, "Failed to list labels. Error {:?}", e);
+            return;
+        }
+    };
+
+    if labels.is_empty() {
+        out!(session, "Wallet has no labels");
+        return;
+    }
+
+    let mut total = Amount::ZERO;
+    let mut listing = Vec::new();
+    for label in &labels {
+        let display_name = if label.is_empty() { "(no label)" } else { label.as_str() };
+        let received = rpc_client.call::<f64>("getreceivedbylabel", &[label.as_str().into()])
+            .map_err(|e| e.to_string())
+            .and_then(|btc| Amount::from_btc(btc).map_err(|e| e.to_string()));
+        let addresses: Vec<String> = rpc_client.call::<serde_json::Map<String, serde_json::Value>>("getaddressesbylabel", &[label.as_str().into()])
+            .map(|addresses| addresses.keys().cloned().collect())
+            .unwrap_or_default();
+
+        match &received {
+            Ok(received) => {
+                out!(session, "{}: {}", display_name, received);
+                total += *received;
+            }
+            Err(e) => out!(session, "{}: received amount unavailable ({})", display_name, e),
+        }
+        for address in &addresses {
+            out!(session, "    {}", address);
+        }
+        listing.push(json!({
+            "label": label,
+            "received": received.as_ref().ok().map(|amount| amount.to_btc()),
+            "addresses": addresses,
+        }));
+    }
+
+    out!(session, "Total received across {} label(s): {}", labels.len(), total);
+    session.out.json(&json!({ "labels": listing, "total_received": total.to_btc() }));
+}
+
+pub fn list_all_transactions(rpc_client: &MinerClient) -> Result<Vec<ListTransactionResult>, bitcoincore_rpc::Error> {
+    let mut transactions = Vec::new();
+    loop {
+        let page = rpc_client.list_transactions(None, Some(LIST_TRANSACTIONS_PAGE_SIZE), Some(transactions.len()), None)?;
+        let page_len = page.len();
+        transactions.extend(page);
+        if page_len < LIST_TRANSACTIONS_PAGE_SIZE {
+            return Ok(transactions);
+        }
+    }
+}
+
+// Conflicted transactions are reported by the wallet with negative confirmations
+fn find_conflicted_transactions(rpc_client: &MinerClient) -> Result<Vec<ListTransactionResult>, bitcoincore_rpc::Error> {
+    let mut conflicted: Vec<ListTransactionResult> = Vec::new();
+    for tx in list_all_transactions(rpc_client)? {
+        if tx.info.confirmations < 0 && !conflicted.iter().any(|c| c.info.txid == tx.info.txid) {
+            conflicted.push(tx);
+        }
+    }
+
+    Ok(conflicted)
+}
+
+pub fn show_conflicts(session: &Session) {
+    match find_conflicted_transactions(&session.rpc_client) {
+        Ok(conflicted) if conflicted.is_empty() => out!(session, "No conflicted transactions"),
+        Ok(conflicted) => {
+            for tx in &conflicted {
+                out!(session, "{} confirmations: {} amount: {}", tx.info.txid, tx.info.confirmations, tx.detail.amount);
+            }
+            out!(session, "{} conflicted transaction(s)", conflicted.len());
+        }
+        Err(e) => err!(session, "Failed to list transactions. Error {:?}", e)
+    }
+}
+
+pub fn request_abandon_all(session: &mut Session, skip_confirmation: bool) {
+    let conflicted = match find_conflicted_transactions(&session.rpc_client) {
+        Ok(conflicted) => conflicted,
+        Err(e) => {
+            err!(session, "Failed to list transactions. Error {:?}", e);
+            return;
+        }
+    };
+
+    if conflicted.is_empty() {
+        out!(session, "No conflicted transactions");
+        return;
+    }
+
+    let txids = conflicted.iter().map(|tx| tx.info.txid).collect();
+    if skip_confirmation {
+        abandon_transactions(session, txids);
+    } else {
+        out!(session, "About to abandon {} conflicted transaction(s). Type 'yes' to confirm", conflicted.len());
+        session.pending_action = Some(PendingAction::AbandonAll(txids));
+    }
+}
+
+pub fn abandon_transactions(session: &Session, txids: Vec<Txid>) {
+    let mut abandoned = 0;
+    for txid in &txids {
+        match session.rpc_client.call::<()>("abandontransaction", &[txid.to_string().into()]) {
+            Ok(()) => abandoned += 1,
+            Err(e) => err!(session, "Could not abandon {}. Error {}", txid, e),
+        }
+    }
+    out!(session, "Abandoned {} of {} transaction(s)", abandoned, txids.len());
+}
+
+// Why reorgcheck flagged a wallet transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReorgIssue {
+    // Negative confirmations: a transaction on the active chain spends the same inputs
+    Conflicted,
+    // Coinbase of a block that is no longer on the active chain
+    OrphanedCoinbase,
+    // The wallet still places it in a block the active chain doesn't contain
+    OffChainBlock,
+    // Knocked out of its block and not back in the mempool either
+    Unconfirmed,
+    Abandoned,
+}
+
+impl ReorgIssue {
+    fn describe(self) -> &'static str {
+        match self {
+            ReorgIssue::Conflicted => "conflicted",
+            ReorgIssue::OrphanedCoinbase => "orphaned coinbase",
+            ReorgIssue::OffChainBlock => "block not on active chain",
+            ReorgIssue::Unconfirmed => "unconfirmed, not in mempool",
+            ReorgIssue::Abandoned => "abandoned",
+        }
+    }
+}
+
+pub fn check_reorg_consistency(session: &Session) {
+    let rpc_client = &session.rpc_client;
+    let (transactions, mempool) = match list_all_transactions(rpc_client).and_then(|txs| Ok((txs, rpc_client.get_raw_mempool()?))) {
+        Ok(result) => result,
+        Err(e) => {
+            err!(session, "Failed to list transactions. Error {:?}", e);
+            return;
+        }
+    };
+    let mempool: HashSet<Txid> = mempool.into_iter().collect();
+
+    let mut seen = HashSet::new();
+    let mut issues = Vec::new();
+    for tx in &transactions {
+        // Sends to several outputs show up once per output
+        if !seen.insert(tx.info.txid) {
+            continue;
+        }
+        let on_active_chain = match (tx.info.blockhash, tx.info.blockheight) {
+            (Some(hash), Some(height)) => rpc_client.get_block_hash(height as u64).is_ok_and(|active| active == hash),
+            _ => true,
+        };
+        let issue = if tx.detail.abandoned == Some(true) {
+            Some(ReorgIssue::Abandoned)
+        } else if tx.info.confirmations < 0 {
+            Some(ReorgIssue::Conflicted)
+        } else if tx.detail.category == GetTransactionResultDetailCategory::Orphan {
+            Some(ReorgIssue::OrphanedCoinbase)
+        } else if !on_active_chain {
+            Some(ReorgIssue::OffChainBlock)
+        } else if tx.info.confirmations == 0 && !mempool.contains(&tx.info.txid) {
+            Some(ReorgIssue::Unconfirmed)
+        } else {
+            None
+        };
+        if let Some(issue) = issue {
+            issues.push((tx, issue));
+        }
+    }
+
+    if issues.is_empty() {
+        out!(session, "Wallet is consistent with the active chain ({} transaction(s) checked)", seen.len());
+        session.out.json(&json!({ "checked": seen.len(), "issues": [] }));
+        return;
+    }
+
+    for (tx, issue) in &issues {
+        out!(session, "{}  {:<28}  confirmations: {}  amount: {}", tx.info.txid, issue.describe(), tx.info.confirmations, tx.detail.amount);
+    }
+    let count = |kind: ReorgIssue| issues.iter().filter(|(_, issue)| *issue == kind).count();
+    out!(
+        session,
+        "{} of {} transaction(s) inconsistent with the active chain: {} conflicted, {} orphaned coinbase(s), \
+         {} in off-chain blocks, {} dropped, {} abandoned",
+        issues.len(), seen.len(), count(ReorgIssue::Conflicted), count(ReorgIssue::OrphanedCoinbase),
+        count(ReorgIssue::OffChainBlock), count(ReorgIssue::Unconfirmed), count(ReorgIssue::Abandoned)
+    );
+    if count(ReorgIssue::Conflicted) > 0 {
+        out!(session, "Run abandonall to abandon the conflicted transactions and release their inputs");
+    }
+    if count(ReorgIssue::Unconfirmed) > 0 {
+        out!(session, "Dropped transactions return once the block they were in is reconsidered (snapshot load does this)");
+    }
+
+    let json_issues: Vec<serde_json::Value> = issues.iter()
+        .map(|(tx, issue)| json!({
+            "txid": tx.info.txid,
+            "issue": issue.describe(),
+            "confirmations": tx.info.confirmations,
+            "amount": tx.detail.amount.to_btc(),
+            "blockhash": tx.info.blockhash,
+        }))
+        .collect();
+    session.out.json(&json!({ "checked": seen.len(), "issues": json_issues }));
+}
+
+// Finds the active external (receiving) descriptor and the index of its next unused address
+fn active_receive_descriptor(rpc_client: &MinerClient) -> Result<(String, u32), String> {
+    let result = rpc_client.call::<serde_json::Value>("listdescriptors", &[])
+        .map_err(|e| {
+            // "listdescriptors is not available for non-descriptor wallets"
+            if e.to_string().contains("non-descriptor wallets") {
+                "Wallet is a legacy (non-descriptor) wallet and has no descriptors".to_string()
+            } else {
+                format!("Failed to list descriptors. Error {:?}", e)
+            }
+        })?;
+
+    let descriptors = result["descriptors"].as_array().cloned().unwrap_or_default();
+    let receiving: Vec<&serde_json::Value> = descriptors.iter()
+        .filter(|d| d["active"].as_bool() == Some(true) && d["internal"].as_bool() != Some(true))
+        .collect();
+
+    // Prefer the segwit v0 descriptor since that's the node's default address type
+    let descriptor = receiving.iter()
+        .find(|d| d["desc"].as_str().is_some_and(|desc| desc.starts_with("wpkh(")))
+        .or(receiving.first())
+        .ok_or("Wallet has no active receiving descriptor")?;
+
+    let desc = descriptor["desc"].as_str().ok_or("Descriptor is missing its string form")?;
+    let next_index = descriptor["next_index"].as_u64()
+        .or(descriptor["next"].as_u64())
+        .unwrap_or(0) as u32;
+
+    Ok((desc.to_string(), next_index))
+}
+
+pub fn show_descriptor_addresses(session: &Session, count: u32) {
+    let (descriptor, next_index) = match active_receive_descriptor(&session.rpc_client) {
+        Ok(found) => found,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+
+    // count is at least 1, checked by the command
+    let range = [next_index, next_index + count - 1];
+    match session.rpc_client.derive_addresses(&descriptor, Some(range)) {
+        Ok(addresses) => {
+            let mut derived = Vec::new();
+            for (index, address) in (next_index..).zip(addresses) {
+                let address = address.assume_checked();
+                out!(session, "{:>6} {}", index, address);
+                derived.push(json!({ "index": index, "address": address }));
+            }
+            session.out.json(&json!({ "descriptor": descriptor, "addresses": derived }));
+        }
+        Err(e) => err!(session, "Failed to derive addresses from {}. Error {:?}", descriptor, e)
+    }
+}
+
+pub fn show_total_balance(session: &Session) {
+    let wallets = match session.rpc_client.list_wallets() {
+        Ok(wallets) => wallets,
+        Err(e) => {
+            err!(session, "Failed to list wallets. Error {:?}", e);
+            return;
+        }
+    };
+
+    let mut total = Amount::ZERO;
+    let mut breakdown = serde_json::Map::new();
+    for wallet_name in wallets {
+        let balances = session.wallet_client(&wallet_name).and_then(|client| client.get_balances());
+        match balances {
+            Ok(balances) => {
+                out!(session, "{:<20} {}", wallet_name, balances.mine.trusted);
+                breakdown.insert(wallet_name, balances.mine.trusted.to_btc().into());
+                total += balances.mine.trusted;
+            }
+            Err(e) => {
+                out!(session, "{:<20} unavailable ({})", wallet_name, e);
+                breakdown.insert(wallet_name, serde_json::Value::Null);
+            }
+        }
+    }
+    out!(session, "{:<20} {}", "Total", total);
+    session.out.json(&json!({ "wallets": breakdown, "total": total.to_btc() }));
+}
+
+pub fn show_wallet_info(session: &Session) {
+    // Read the raw results: the typed one lacks the descriptors flag and breaks when newer nodes drop balance fields.
+    // Batched, so the balances cost no extra round trip
+    let results = session.rpc_client.batch(&[("getwalletinfo", vec![]), ("getbalances", vec![])]);
+    let info = match &results[0] {
+        Ok(info) => info,
+        Err(e) => {
+            err!(session, "Failed to get wallet info. Error {:?}", e);
+            return;
+        }
+    };
+    // getbalances (v0.19+) is where newer nodes report balances, getwalletinfo's fields are the fallback
+    let mine = results[1].as_ref().ok().map(|balances| &balances["mine"]);
+    let balance = mine.map_or(&info["balance"], |mine| &mine["trusted"]);
+    let unconfirmed_balance = mine.map_or(&info["unconfirmed_balance"], |mine| &mine["untrusted_pending"]);
+
+    out!(session, "Wallet: {}", info["walletname"].as_str().unwrap_or_default());
+    out!(session, "Balance: {}", format_btc_value(balance));
+    out!(session, "Unconfirmed balance: {}", format_btc_value(unconfirmed_balance));
+    out!(session, "Keypool size: {}", info["keypoolsize"]);
+    out!(session, "Descriptor wallet: {}", info["descriptors"].as_bool().map_or("unknown", |d| if d { "yes" } else { "no" }));
+    out!(session, "Pay tx fee: {}/kvB", format_btc_value(&info["paytxfee"]));
+
+    // "scanning" is false when idle, otherwise an object with duration and progress (0..1)
+    match info["scanning"]["progress"].as_f64() {
+        Some(progress) => out!(
+            session,
+            "Rescanning: {:.1}% ({}s elapsed)",
+            progress * 100.0,
+            info["scanning"]["duration"].as_u64().unwrap_or(0)
+        ),
+        None => out!(session, "Rescanning: no"),
+    }
+
+    // Only present for encrypted wallets, 0 means locked
+    match info["unlocked_until"].as_u64() {
+        Some(0) => out!(session, "Encrypted: locked"),
+        Some(until) => out!(session, "Encrypted: unlocked until {}", until),
+        None => out!(session, "Encrypted: no"),
+    }
+
+    session.out.json(&json!({
+        "wallet_name": info["walletname"],
+        "balance": balance,
+        "unconfirmed_balance": unconfirmed_balance,
+        "keypool_size": info["keypoolsize"],
+        "descriptors": info["descriptors"],
+        "scanning": info["scanning"],
+        "unlocked_until": info["unlocked_until"],
+        "pay_tx_fee": info["paytxfee"],
+    }));
+}
+
+pub fn set_tx_fee(session: &Session, fee_rate: Amount) {
+    if fee_rate.to_btc() > HIGH_FEE_RATE_BTC_PER_KB {
+        out!(session, "Warning: fee rate {}/kvB is unusually high", fee_rate);
+    }
+
+    match session.rpc_client.call::<bool>("settxfee", &[fee_rate.to_btc().into()]) {
+        Ok(true) => out!(session, "Set wallet fee rate to {}/kvB", fee_rate),
+        Ok(false) => out!(session, "Node refused to set fee rate {}/kvB", fee_rate),
+        Err(e) => err!(session, "Failed to set fee rate. Error {:?}", e)
+    }
+}
+
+// dumpwallet refuses to overwrite, and the file is written by the node, so check up front what we can
+fn check_dump_destination(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return Err(format!("{} already exists, the node will not overwrite it", path.display()));
+    }
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match fs::metadata(parent) {
+        Ok(metadata) if !metadata.is_dir() => Err(format!("{} is not a directory", parent.display())),
+        Ok(metadata) if metadata.permissions().readonly() => Err(format!("{} is not writable", parent.display())),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot write to {}: {}", parent.display(), e)),
+    }
+}
+
+// Key lines in a dump are "<key> <timestamp> <label=...|reserve=1|change=1|...> # addr=...", everything else is a comment
+fn count_dumped_keys(filename: &str) -> Option<usize> {
+    let contents = fs::read_to_string(filename).ok()?;
+    Some(contents.lines().filter(|line| !line.is_empty() && !line.starts_with('#')).count())
+}
+
+pub fn dump_wallet(session: &Session, path: &str) {
+    if !session.allow_key_export {
+        err!(session, "dumpwallet writes every private key to disk, start with --allow-key-export to enable it");
+        return;
+    }
+    if !session.require_regtest("dumpwallet") {
+        return;
+    }
+    if let Err(e) = check_dump_destination(Path::new(path)) {
+        err!(session, "{}", e);
+        return;
+    }
+
+    match session.rpc_client.call::<serde_json::Value>("dumpwallet", &[path.into()]) {
+        Ok(result) => {
+            let filename = result["filename"].as_str().unwrap_or(path);
+            // The node may be on another host, in which case the file isn't visible here
+            let key_count = count_dumped_keys(filename);
+            match key_count {
+                Some(count) => out!(session, "Dumped {} key(s) to {}", count, filename),
+                None => out!(session, "Dumped wallet to {} (file not readable here, key count unavailable)", filename),
+            }
+            session.out.json(&json!({ "filename": filename, "keys": key_count }));
+        }
+        // RPC_WALLET_ERROR, e.g. descriptor wallets, which only support listdescriptors
+        Err(e) if rpc_error_code(&e) == Some(-4) => {
+            err!(session, "Failed to dump wallet: {}", rpc_error_message(&e).unwrap_or_default())
+        }
+        Err(e) => err!(session, "Failed to dump wallet. Error {:?}", e)
+    }
+}
+
+// --receive-address if configured (checked against the wallet on each use), otherwise a fresh address
+pub fn show_receive_address(session: &Session) {
+    let address = match &session.receive_address {
+        Some(address) => {
+            match session.rpc_client.get_address_info(address) {
+                Ok(info) if info.is_mine == Some(true) => {}
+                Ok(_) => out!(session, "Warning: {} is not owned by this wallet, funds sent to it won't show up here", address),
+                Err(e) => err!(session, "Warning: could not check ownership of {}. Error {}", address, e),
+            }
+            address.clone()
+        }
+        None => match session.rpc_client.get_new_address(None, None) {
+            Ok(address) => address.assume_checked(),
+            Err(e) => {
+                err!(session, "Failed to get new address. Error {:?}", e);
+                return;
+            }
+        },
+    };
+
+    out!(session, "{}", address);
+    session.out.json(&json!({ "address": address, "fixed": session.receive_address.is_some() }));
+}
+
+// Creates a second wallet and funds it from the active one, the usual setup for a two-party test
+pub fn spawn_wallet(session: &Session, name: &str, amount: Amount) {
+    let rpc_client = &session.rpc_client;
+    // Validated before createwallet so a bad amount doesn't leave an empty wallet behind. The new wallet's
+    // address type isn't known until it exists, so check against the highest dust threshold (P2PKH)
+    let strictest = Address::p2pkh(PubkeyHash::all_zeros(), session.network);
+    if let Err(e) = check_dust(amount, &strictest) {
+        err!(session, "{}", e);
+        return;
+    }
+    match spendable_balance(session) {
+        Ok(balance) if amount > balance => {
+            err!(session, "{}", MinerError::InsufficientBalance { total: amount, balance });
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            err!(session, "Failed to get balance. Error {}", e);
+            return;
+        }
+    }
+
+    let existing = rpc_client.list_wallet_dir()
+        .map(|on_disk| on_disk.iter().any(|wallet| wallet == name))
+        .and_then(|on_disk| Ok(on_disk || rpc_client.list_wallets()?.iter().any(|wallet| wallet == name)));
+    match existing {
+        Ok(true) => {
+            err!(session, "Wallet {} already exists, pick another name or use it with --wallet-name", name);
+            return;
+        }
+        Ok(false) => {}
+        Err(e) => {
+            err!(session, "Failed to list wallets. Error {:?}", e);
+            return;
+        }
+    }
+
+    if let Err(e) = rpc_client.create_wallet(name, None, None, None, None) {
+        err!(session, "Failed to create wallet {}. Error {:?}", name, e);
+        return;
+    }
+    out!(session, "Created wallet {}", name);
+
+    let address = match session.wallet_client(name).and_then(|client| client.get_new_address(None, None)) {
+        Ok(address) => address.assume_checked(),
+        Err(e) => {
+            err!(session, "Failed to get an address in {}. Error {:?}", name, e);
+            return;
+        }
+    };
+
+    let txid = match call_send_to_address(session, &address, amount, false, None) {
+        Ok(txid) => txid,
+        Err(e) => {
+            err!(session, "Created {} but failed to fund it. Error {:?}", name, e);
+            return;
+        }
+    };
+    session.record_transaction_sent(&txid);
+    out!(session, "Funded {} with {} at {} (TxID: {})", name, amount, address, txid);
+
+    // Off regtest the funding transaction confirms whenever the network mines it
+    let block = if session.is_regtest() {
+        match mining_address(session).and_then(|mine_to| rpc_client.generate_to_address(1, &mine_to)) {
+            Ok(hashes) => {
+                session.record_blocks_mined(hashes.len());
+                out!(session, "Confirmed in block {}", hashes[0]);
+                Some(hashes[0])
+            }
+            Err(e) => {
+                err!(session, "Failed to mine confirming block. Error {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    session.out.json(&json!({ "wallet": name, "address": address, "amount": amount.to_btc(), "txid": txid, "block": block }));
+}
+
+// How the session wallet takes watch-only imports
+enum WatchOnlyImport {
+    // importaddress
+    Legacy,
+    // importdescriptors, which only accepts keyless descriptors into a wallet with private keys disabled
+    Descriptors,
+}
+
+// Checked before importing so a wallet that can't hold the import fails here rather than after a long rescan
+fn watch_only_import(session: &Session) -> Result<WatchOnlyImport, String> {
+    let info = session.rpc_client.call::<serde_json::Value>("getwalletinfo", &[])
+        .map_err(|e| format!("Failed to get wallet info. Error {:?}", e))?;
+    // Nodes before v0.21 don't report "descriptors" and only have legacy wallets
+    if !info["descriptors"].as_bool().unwrap_or(false) {
+        return Ok(WatchOnlyImport::Legacy);
+    }
+    if info["private_keys_enabled"].as_bool().unwrap_or(true) {
+        return Err(format!(
+            "Wallet {} is a descriptor wallet with private keys, which can't hold watch-only imports. Create a watch-only \
+             wallet with bitcoin-cli createwallet <name> true and rerun with --wallet-name <name>",
+            session.rpc_client.wallet_name()
+        ));
+    }
+    Ok(WatchOnlyImport::Descriptors)
+}
+
+// Watch-only import of a wsh(sortedmulti(...)) descriptor, rescanning from genesis so past payments are found
+pub fn import_multisig(session: &Session, required: usize, pubkeys: &[PublicKey]) {
+    let rpc_client = &session.rpc_client;
+    match watch_only_import(session) {
+        Ok(WatchOnlyImport::Descriptors) => {}
+        Ok(WatchOnlyImport::Legacy) => {
+            err!(
+                session,
+                "Wallet {} is a legacy wallet, importmultisig needs a descriptor wallet with private keys disabled. \
+                 Create one with bitcoin-cli createwallet <name> true and rerun with --wallet-name <name>",
+                rpc_client.wallet_name()
+            );
+            return;
+        }
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    }
+    let keys: Vec<String> = pubkeys.iter().map(|key| key.to_string()).collect();
+    let descriptor = format!("wsh(sortedmulti({},{}))", required, keys.join(","));
+    let descriptor = match rpc_client.get_descriptor_info(&descriptor) {
+        Ok(info) => info.descriptor,
+        Err(e) => {
+            err!(session, "Invalid multisig descriptor {}. Error {}", descriptor, e);
+            return;
+        }
+    };
+
+    let address = match rpc_client.derive_addresses(&descriptor, None) {
+        Ok(addresses) if !addresses.is_empty() => addresses[0].clone().assume_checked(),
+        Ok(_) => {
+            out!(session, "Descriptor {} derives no address", descriptor);
+            return;
+        }
+        Err(e) => {
+            err!(session, "Failed to derive the multisig address. Error {:?}", e);
+            return;
+        }
+    };
+
+    let request = ImportDescriptors {
+        descriptor: descriptor.clone(),
+        timestamp: Timestamp::Time(0),
+        active: Some(false),
+        internal: Some(false),
+        label: Some(format!("multisig {}-of-{}", required, pubkeys.len())),
+        ..Default::default()
+    };
+    out!(session, "Importing {}-of-{} multisig {} and rescanning, this may take a while", required, pubkeys.len(), address);
+    match session.run_blocking("rescanning", move |client| client.import_descriptors(request)) {
+        Ok(results) => {
+            for result in &results {
+                for warning in &result.warnings {
+                    out!(session, "Import warning: {}", warning);
+                }
+                if !result.success {
+                    match &result.error {
+                        Some(e) => err!(session, "Failed to import multisig descriptor: {}", e.message),
+                        None => err!(session, "Failed to import multisig descriptor"),
+                    }
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            err!(session, "Failed to import multisig descriptor. Error {:?}", e);
+            return;
+        }
+    }
+
+    let balance = rpc_client.list_unspent(Some(0), None, Some(&[&address]), Some(true), None)
+        .map(|utxos| utxos.iter().map(|utxo| utxo.amount).sum::<Amount>());
+    match balance {
+        Ok(balance) => {
+            out!(session, "Imported {}, rescan complete", descriptor);
+            out!(session, "Multisig address: {}", address);
+            out!(session, "Balance: {} (track it with watch {})", balance, address);
+            session.out.json(&json!({ "descriptor": descriptor, "address": address, "balance": balance.to_btc() }));
+        }
+        Err(e) => err!(session, "Imported {} but failed to get its balance. Error {:?}", descriptor, e)
+    }
+}
+
+fn keypool_size(session: &Session) -> Option<u64> {
+    session.rpc_client.call::<serde_json::Value>("getwalletinfo", &[]).ok()?["keypoolsize"].as_u64()
+}
+
+pub fn refill_keypool(session: &Session, size: u64) {
+    let before = keypool_size(session);
+    match session.rpc_client.key_pool_refill(Some(size as usize)) {
+        Ok(()) => {
+            let after = keypool_size(session);
+            let show = |size: Option<u64>| size.map_or("unknown".to_string(), |size| size.to_string());
+            out!(session, "Keypool refilled: {} -> {} key(s)", show(before), show(after));
+            session.out.json(&json!({ "before": before, "after": after, "target": size }));
+        }
+        Err(e) if rpc_error_code(&e) == Some(RPC_WALLET_UNLOCK_NEEDED) => {
+            err!(session, "Wallet is locked, unlock it with walletpassphrase first");
+        }
+        Err(e) => err!(session, "Failed to refill keypool. Error {:?}", e)
+    }
+}
+
+fn locked_outpoints(session: &Session) -> Result<Vec<OutPoint>, bitcoincore_rpc::Error> {
+    let locked: Vec<serde_json::Value> = session.rpc_client.call("listlockunspent", &[])?;
+    Ok(locked.iter()
+        .filter_map(|outpoint| {
+            let txid = outpoint["txid"].as_str()?.parse().ok()?;
+            Some(OutPoint { txid, vout: outpoint["vout"].as_u64()? as u32 })
+        })
+        .collect())
+}
+
+// listlockunspent entries are bare outpoints, so look each amount up (including the mempool, for unconfirmed change)
+fn locked_utxo_total(session: &Session) -> Result<(usize, Amount), bitcoincore_rpc::Error> {
+    let locked = locked_outpoints(session)?;
+    let mut total = Amount::ZERO;
+    for outpoint in &locked {
+        if let Some(tx_out) = session.rpc_client.get_tx_out(&outpoint.txid, outpoint.vout, Some(true))? {
+            total += tx_out.value;
+        }
+    }
+    Ok((locked.len(), total))
+}
+
+// Trusted balance against the spendable UTXOs it should be made of; locked coins count toward the balance but aren't listed
+pub fn audit_balance(session: &Session) {
+    let rpc_client = &session.rpc_client;
+    let figures = rpc_client.get_balances().and_then(|balances| {
+        let utxos = rpc_client.list_unspent(Some(0), None, None, Some(false), None)?;
+        Ok((balances.mine, utxos, locked_utxo_total(session)?))
+    });
+    let (balances, utxos, (locked_count, locked_total)) = match figures {
+        Ok(figures) => figures,
+        Err(e) => {
+            err!(session, "Failed to read balances and UTXOs. Error {:?}", e);
+            return;
+        }
+    };
+
+    let utxo_total: Amount = utxos.iter().filter(|utxo| utxo.spendable).map(|utxo| utxo.amount).sum();
+    let expected = utxo_total + locked_total;
+    let difference = balances.trusted.to_signed().ok().zip(expected.to_signed().ok()).map(|(trusted, expected)| trusted - expected);
+
+    out!(session, "getbalances trusted:   {}", balances.trusted);
+    out!(session, "listunspent total:     {} ({} UTXO(s))", utxo_total, utxos.len());
+    out!(session, "locked UTXOs:          {} ({} UTXO(s))", locked_total, locked_count);
+    out!(session, "(pending {}, immature {} are not part of either figure)", balances.untrusted_pending, balances.immature);
+    let pass = balances.trusted == expected;
+    match difference {
+        _ if pass => out!(session, "PASS: balances agree"),
+        Some(difference) => out!(session, "FAIL: balances differ by {}", difference),
+        None => out!(session, "FAIL: balances differ"),
+    }
+
+    session.out.json(&json!({
+        "pass": pass,
+        "trusted": balances.trusted.to_btc(),
+        "utxo_total": utxo_total.to_btc(),
+        "utxo_count": utxos.len(),
+        "locked_total": locked_total.to_btc(),
+        "locked_count": locked_count,
+        "difference": difference.map(|difference| difference.to_btc()),
+    }));
+}
+
+// Locked outputs are skipped by the wallet's coin selection until unlocked or the node restarts
+pub fn set_utxo_locked(session: &Session, outpoint: OutPoint, lock: bool) {
+    let rpc_client = &session.rpc_client;
+    let checked = if lock {
+        rpc_client.get_tx_out(&outpoint.txid, outpoint.vout, Some(true)).map(|tx_out| tx_out.is_some())
+    } else {
+        locked_outpoints(session).map(|locked| locked.contains(&outpoint))
+    };
+    match checked {
+        Ok(true) => {}
+        Ok(false) if lock => {
+            err!(session, "{} is not an unspent output", outpoint);
+            return;
+        }
+        Ok(false) => {
+            err!(session, "{} is not locked", outpoint);
+            return;
+        }
+        Err(e) => {
+            err!(session, "Failed to look up {}. Error {:?}", outpoint, e);
+            return;
+        }
+    }
+
+    let (verb, result) = match lock {
+        true => ("lock", rpc_client.lock_unspent(&[outpoint])),
+        false => ("unlock", rpc_client.unlock_unspent(&[outpoint])),
+    };
+    match result {
+        Ok(true) => {
+            let count = locked_outpoints(session).map(|locked| locked.len()).ok();
+            let count_text = count.map_or("unknown".to_string(), |count| count.to_string());
+            out!(session, "{} {}, {} output(s) now locked", if lock { "Locked" } else { "Unlocked" }, outpoint, count_text);
+            session.out.json(&json!({ "outpoint": outpoint.to_string(), "locked": lock, "locked_count": count }));
+        }
+        // The node refuses outputs the wallet doesn't own, or that are already locked
+        Ok(false) => out!(session, "Node refused to {} {}", verb, outpoint),
+        Err(e) => err!(session, "Failed to {} {}. Error {:?}", verb, outpoint, e)
+    }
+}
+
+pub fn list_locked_utxos(session: &Session) {
+    let locked = match locked_outpoints(session) {
+        Ok(locked) => locked,
+        Err(e) => {
+            err!(session, "Failed to list locked outputs. Error {:?}", e);
+            return;
+        }
+    };
+
+    if locked.is_empty() {
+        out!(session, "No locked outputs");
+    }
+    for outpoint in &locked {
+        match session.rpc_client.get_tx_out(&outpoint.txid, outpoint.vout, Some(true)) {
+            Ok(Some(tx_out)) => out!(session, "{}  {}", outpoint, tx_out.value),
+            _ => out!(session, "{}  (spent or unknown)", outpoint),
+        }
+    }
+    let outpoints: Vec<String> = locked.iter().map(|outpoint| outpoint.to_string()).collect();
+    session.out.json(&json!({ "locked": outpoints }));
+}
+
+// Progress as 0..1 and seconds elapsed, None when the wallet isn't rescanning
+fn rescan_progress(session: &Session) -> Result<Option<(f64, u64)>, bitcoincore_rpc::Error> {
+    // "scanning" is false when idle, as in walletinfo
+    let info = session.rpc_client.call::<serde_json::Value>("getwalletinfo", &[])?;
+    Ok(info["scanning"]["progress"].as_f64().map(|progress| (progress, info["scanning"]["duration"].as_u64().unwrap_or(0))))
+}
+
+pub fn show_rescan_status(session: &Session) {
+    match rescan_progress(session) {
+        Ok(Some((progress, duration))) => {
+            out!(session, "Rescan in progress: {:.1}% ({}s elapsed)", progress * 100.0, duration);
+            session.out.json(&json!({ "scanning": true, "progress": progress, "duration": duration }));
+        }
+        Ok(None) => {
+            out!(session, "No rescan in progress");
+            session.out.json(&json!({ "scanning": false }));
+        }
+        Err(e) => err!(session, "Failed to get rescan status. Error {:?}", e)
+    }
+}
+
+pub fn abort_rescan(session: &Session) {
+    let progress = match rescan_progress(session) {
+        Ok(Some((progress, _))) => progress,
+        Ok(None) => {
+            out!(session, "No rescan in progress");
+            session.out.json(&json!({ "aborted": false, "scanning": false }));
+            return;
+        }
+        Err(e) => {
+            err!(session, "Failed to get rescan status. Error {:?}", e);
+            return;
+        }
+    };
+
+    // false means the rescan finished, or was never running, before the abort reached it
+    match session.rpc_client.call::<bool>("abortrescan", &[]) {
+        Ok(true) => {
+            out!(session, "Rescan aborted at {:.1}%", progress * 100.0);
+            session.out.json(&json!({ "aborted": true, "progress": progress }));
+        }
+        Ok(false) => {
+            out!(session, "Rescan was not aborted, it may have just finished");
+            session.out.json(&json!({ "aborted": false, "scanning": false }));
+        }
+        Err(e) => err!(session, "Failed to abort rescan. Error {:?}", e)
+    }
+}
+
+// listunspent filter tokens, each forwarded to the node rather than applied here
+#[derive(Default)]
+pub struct UnspentFilters {
+    pub minconf: Option<usize>,
+    pub maxconf: Option<usize>,
+    pub min_amount: Option<Amount>,
+    pub address: Option<Address>,
+}
+
+impl UnspentFilters {
+    fn describe(&self) -> String {
+        let mut applied = Vec::new();
+        if let Some(minconf) = self.minconf {
+            applied.push(format!("minconf={}", minconf));
+        }
+        if let Some(maxconf) = self.maxconf {
+            applied.push(format!("maxconf={}", maxconf));
+        }
+        if let Some(min_amount) = self.min_amount {
+            applied.push(format!("minamount={}", min_amount));
+        }
+        if let Some(address) = &self.address {
+            applied.push(format!("address={}", address));
+        }
+        if applied.is_empty() { "none".to_string() } else { applied.join(" ") }
+    }
+}
+
+// A bare number is minconf, as before the filters existed
+pub fn parse_unspent_filters(session: &Session, tokens: &[&str]) -> Result<UnspentFilters, String> {
+    let mut filters = UnspentFilters::default();
+    for token in tokens {
+        match token.split_once('=') {
+            None => match token.parse() {
+                Ok(minconf) if filters.minconf.is_none() => filters.minconf = Some(minconf),
+                _ => return Err(format!("Unknown listunspent filter '{}'", token)),
+            },
+            Some(("maxconf", value)) => match value.parse() {
+                Ok(maxconf) => filters.maxconf = Some(maxconf),
+                Err(_) => return Err(format!("Invalid maxconf '{}', expected a number of confirmations", value)),
+            },
+            Some(("minamount", value)) => match parse_amount(value) {
+                Ok(min_amount) => filters.min_amount = Some(min_amount),
+                Err(e) => return Err(format!("Invalid minamount '{}': {}", value, e)),
+            },
+            // Resolved against the session's network, so a mainnet address on regtest is caught here
+            Some(("address", value)) => filters.address = Some(session.resolve_address(value)?),
+            _ => return Err(format!("Unknown listunspent filter '{}'", token)),
+        }
+    }
+
+    if let (Some(minconf), Some(maxconf)) = (filters.minconf, filters.maxconf) {
+        if maxconf < minconf {
+            return Err(format!("maxconf {} is below minconf {}", maxconf, minconf));
+        }
+    }
+    Ok(filters)
+}
+
+pub fn list_unspent(session: &Session, filters: &UnspentFilters) {
+    let addresses = filters.address.as_ref().map(|address| vec![address]);
+    let query_options = filters.min_amount.map(|min_amount| ListUnspentQueryOptions {
+        minimum_amount: Some(min_amount),
+        ..Default::default()
+    });
+    let utxos = match session.rpc_client.list_unspent(filters.minconf, filters.maxconf, addresses.as_deref(), None, query_options) {
+        Ok(utxos) => utxos,
+        Err(e) => {
+            err!(session, "Failed to list unspent outputs. Error {:?}", e);
+            return;
+        }
+    };
+
+    let total = utxos.iter().map(|utxo| utxo.amount).sum::<Amount>();
+    out!(session, "Filters: {}", filters.describe());
+    out!(session, "{} UTXO(s), total {}", utxos.len(), total);
+    for utxo in &utxos {
+        let address = utxo.address.as_ref().map_or("no address".to_string(), |address| address.clone().assume_checked().to_string());
+        out!(session, "{}:{}  {}  {} conf  {}", utxo.txid, utxo.vout, utxo.amount, utxo.confirmations, address);
+    }
+
+    let entries: Vec<serde_json::Value> = utxos.iter()
+        .map(|utxo| json!({
+            "txid": utxo.txid,
+            "vout": utxo.vout,
+            "amount": utxo.amount.to_btc(),
+            "confirmations": utxo.confirmations,
+            "address": utxo.address,
+            "spendable": utxo.spendable,
+        }))
+        .collect();
+    session.out.json(&json!({ "filters": filters.describe(), "total": total.to_btc(), "utxos": entries }));
+}
+
+// Wallet labels belong to addresses, so a transaction is labeled through every address it touched
+pub fn label_transaction(session: &Session, txid: &Txid, label: &str) {
+    let rpc_client = &session.rpc_client;
+    let wallet_tx = match rpc_client.get_transaction(txid, Some(true)) {
+        Ok(wallet_tx) => wallet_tx,
+        Err(e) if rpc_error_code(&e) == Some(RPC_INVALID_ADDRESS_OR_KEY) => {
+            err!(session, "Transaction {} is not in the wallet, only wallet transactions can be labeled", txid);
+            return;
+        }
+        Err(e) => {
+            err!(session, "Failed to get transaction {}. Error {:?}", txid, e);
+            return;
+        }
+    };
+
+    // A send lists its destination, a receive our own address; setlabel takes both
+    let mut addresses: Vec<Address> = wallet_tx.details.iter()
+        .filter_map(|detail| detail.address.clone())
+        .map(|address| address.assume_checked())
+        .collect();
+    addresses.sort_by_key(|address| address.to_string());
+    addresses.dedup();
+    if addresses.is_empty() {
+        err!(session, "Transaction {} has no addresses to label, its outputs are raw scripts", txid);
+        return;
+    }
+
+    let mut labeled = Vec::new();
+    for address in &addresses {
+        match rpc_client.call::<serde_json::Value>("setlabel", &[json!(address), json!(label)]) {
+            Ok(_) => labeled.push(address),
+            Err(e) => err!(session, "Failed to label {}. Error {}", address, rpc_error_message(&e).unwrap_or_default()),
+        }
+    }
+    if labeled.is_empty() {
+        return;
+    }
+    out!(session, "Labeled {} address(es) of {} as '{}':", labeled.len(), txid, label);
+    for address in &labeled {
+        out!(session, "  {}", address);
+    }
+    session.out.json(&json!({ "txid": txid, "label": label, "addresses": labeled }));
+}
+
+// Wallet's view of the history: what came in, went out and was mined, whoever initiated it
+pub fn show_wallet_summary(session: &Session) {
+    let transactions = match list_all_transactions(&session.rpc_client) {
+        Ok(transactions) => transactions,
+        Err(e) => {
+            err!(session, "Failed to list transactions. Error {:?}", e);
+            return;
+        }
+    };
+
+    let mut received = SignedAmount::ZERO;
+    let mut sent = SignedAmount::ZERO;
+    let mut generated = SignedAmount::ZERO;
+    let mut fees = SignedAmount::ZERO;
+    let mut fee_txids = HashSet::new();
+    let mut skipped = 0;
+    for tx in &transactions {
+        // Conflicted, abandoned and orphaned entries never made it into the balance
+        let orphaned = tx.detail.category == GetTransactionResultDetailCategory::Orphan;
+        if tx.info.confirmations < 0 || tx.detail.abandoned == Some(true) || orphaned {
+            skipped += 1;
+            continue;
+        }
+        match tx.detail.category {
+            GetTransactionResultDetailCategory::Receive => received += tx.detail.amount,
+            GetTransactionResultDetailCategory::Send => sent -= tx.detail.amount,
+            GetTransactionResultDetailCategory::Generate | GetTransactionResultDetailCategory::Immature => {
+                generated += tx.detail.amount
+            }
+            GetTransactionResultDetailCategory::Orphan => {}
+        }
+        // Every send entry of a transaction repeats its fee, so count it once per txid
+        if let Some(fee) = tx.detail.fee {
+            if fee_txids.insert(tx.info.txid) {
+                fees -= fee;
+            }
+        }
+    }
+
+    let net = received + generated - sent - fees;
+    out!(session, "Wallet transactions: {} entries", transactions.len());
+    out!(session, "Received:  {}", received);
+    out!(session, "Sent:      {}", sent);
+    out!(session, "Fees paid: {}", fees);
+    out!(session, "Generated: {}", generated);
+    out!(session, "Net change: {}", net);
+    if skipped > 0 {
+        out!(session, "Skipped {} conflicted, abandoned or orphaned entries", skipped);
+    }
+    session.out.json(&json!({
+        "entries": transactions.len(),
+        "received": received.to_btc(),
+        "sent": sent.to_btc(),
+        "fees": fees.to_btc(),
+        "generated": generated.to_btc(),
+        "net": net.to_btc(),
+        "skipped": skipped,
+    }));
+}
+
+pub const DEFAULT_STABLE_QUIET_SECS: u64 = 10;
+pub const DEFAULT_STABLE_TIMEOUT_SECS: u64 = 300;
+// How often the main loop checks a running waitstable
+pub const STABLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// A running waitstable: the balance as of the last tick and when it last changed
+pub struct StableWait {
+    started: Instant,
+    quiet_period: Duration,
+    timeout_secs: u64,
+    balance: Amount,
+    changed_at: Instant,
+    changes: u32,
+}
+
+// Unconfirmed incoming counts, so the wait ends when payments stop arriving rather than when they confirm
+fn pending_inclusive_balance(session: &Session) -> Result<Amount, bitcoincore_rpc::Error> {
+    let balances = session.rpc_client.get_balances()?;
+    Ok(balances.mine.trusted + balances.mine.untrusted_pending + balances.mine.immature)
+}
+
+// Checked by the main loop every STABLE_POLL_INTERVAL, so other commands keep working while it waits
+pub fn start_stable_wait(session: &mut Session, quiet_secs: u64, timeout_secs: u64) {
+    if session.stable_wait.is_some() {
+        out!(session, "Already waiting for the balance to settle, stop with waitstable stop");
+        return;
+    }
+    let balance = match pending_inclusive_balance(session) {
+        Ok(balance) => balance,
+        Err(e) => {
+            err!(session, "Failed to get balances. Error {:?}", e);
+            return;
+        }
+    };
+    out!(session, "Waiting up to {}s for the balance to stay unchanged for {}s", timeout_secs, quiet_secs);
+    let started = Instant::now();
+    session.stable_wait = Some(StableWait {
+        started,
+        quiet_period: Duration::from_secs(quiet_secs),
+        timeout_secs,
+        balance,
+        changed_at: started,
+        changes: 0,
+    });
+}
+
+pub fn stop_stable_wait(session: &mut Session) {
+    match session.stable_wait.take() {
+        Some(wait) => out!(session, "Stopped waiting, balance {} ({} change(s))", wait.balance, wait.changes),
+        None => out!(session, "Not waiting for the balance to settle"),
+    }
+}
+
+pub fn poll_stable_wait(session: &mut Session) {
+    let Some(mut wait) = session.stable_wait.take() else { return };
+    match pending_inclusive_balance(session) {
+        Ok(current) if current != wait.balance => {
+            out!(session, "Balance changed: {} -> {}", wait.balance, current);
+            wait.balance = current;
+            wait.changed_at = Instant::now();
+            wait.changes += 1;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            err!(session, "Failed to get balances. Error {:?}", e);
+            return;
+        }
+    }
+
+    let (balance, changes) = (wait.balance.to_btc(), wait.changes);
+    if wait.changed_at.elapsed() >= wait.quiet_period {
+        let waited = wait.started.elapsed().as_secs_f64();
+        out!(session, "Balance stable at {} after {:.1}s ({} change(s))", wait.balance, waited, changes);
+        session.out.json(&json!({ "stable": true, "balance": balance, "changes": changes, "waited_secs": waited }));
+    } else if wait.started.elapsed() >= Duration::from_secs(wait.timeout_secs) {
+        out!(session, "Timed out after {}s, balance still changing: {} ({} change(s))", wait.timeout_secs, wait.balance, changes);
+        session.out.json(&json!({ "stable": false, "balance": balance, "changes": changes, "waited_secs": wait.timeout_secs }));
+    } else {
+        session.stable_wait = Some(wait);
+    }
+}
+
+// Imports the address without keys and rescans, so the wallet tracks the address's coins but can't spend them.
+// Returns the address when it is (now) in the wallet
+pub fn import_watch_only(session: &Session, address_string: &str, label: Option<&str>) -> Option<Address> {
+    let rpc_client = &session.rpc_client;
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return None;
+        }
+    };
+
+    match rpc_client.get_address_info(&address) {
+        Ok(info) if info.is_mine == Some(true) => {
+            out!(session, "{} belongs to this wallet already, no need to watch it", address);
+            return Some(address);
+        }
+        Ok(info) if info.is_watchonly == Some(true) => {
+            out!(session, "{} is already watched by this wallet", address);
+            return Some(address);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            err!(session, "Failed to get address info for {}. Error {:?}", address, e);
+            return None;
+        }
+    }
+
+    let descriptor = match watch_only_import(session) {
+        Ok(WatchOnlyImport::Legacy) => {
+            let (watched, label) = (address.clone(), label.map(str::to_string));
+            out!(session, "Importing {} as watch-only and rescanning, this may take a while", address);
+            let imported = session.run_blocking("rescanning", move |client| {
+                client.import_address(&watched, label.as_deref(), Some(true))
+            });
+            if let Err(e) = imported {
+                err!(session, "Failed to import {}. Error {:?}", address, e);
+                return None;
+            }
+            None
+        }
+        Ok(WatchOnlyImport::Descriptors) => Some(import_address_descriptor(session, &address, label)?),
+        Err(e) => {
+            err!(session, "{}", e);
+            return None;
+        }
+    };
+
+    let balance = rpc_client.list_unspent(Some(0), None, Some(&[&address]), Some(true), None)
+        .map(|utxos| utxos.iter().map(|utxo| utxo.amount).sum::<Amount>());
+    match balance {
+        Ok(balance) => {
+            out!(session, "Watching {}, rescan complete, balance {}", address, balance);
+            session.out.json(&json!({
+                "address": address,
+                "descriptor": descriptor,
+                "label": label,
+                "balance": balance.to_btc(),
+            }));
+        }
+        Err(e) => err!(session, "Imported {} but failed to get its balance. Error {:?}", address, e)
+    }
+    Some(address)
+}
+
+// importdescriptors of addr(<address>), returning the checksummed descriptor once it's imported
+fn import_address_descriptor(session: &Session, address: &Address, label: Option<&str>) -> Option<String> {
+    let descriptor = match session.rpc_client.get_descriptor_info(&format!("addr({})", address)) {
+        Ok(info) => info.descriptor,
+        Err(e) => {
+            err!(session, "Failed to build a descriptor for {}. Error {:?}", address, e);
+            return None;
+        }
+    };
+    let request = ImportDescriptors {
+        descriptor: descriptor.clone(),
+        timestamp: Timestamp::Time(0),
+        active: Some(false),
+        internal: Some(false),
+        label: label.map(str::to_string),
+        ..Default::default()
+    };
+    out!(session, "Importing {} as watch-only and rescanning, this may take a while", address);
+    match session.run_blocking("rescanning", move |client| client.import_descriptors(request)) {
+        Ok(results) => {
+            for result in &results {
+                for warning in &result.warnings {
+                    out!(session, "Import warning: {}", warning);
+                }
+                if !result.success {
+                    match &result.error {
+                        Some(e) => err!(session, "Failed to import {}: {}", address, e.message),
+                        None => err!(session, "Failed to import {}", address),
+                    }
+                    return None;
+                }
+            }
+            Some(descriptor)
+        }
+        Err(e) => {
+            err!(session, "Failed to import {}. Error {:?}", address, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_session, ScriptedNode};
+
+    fn wallet_info() -> serde_json::Value {
+        json!({ "walletname": "test", "balance": 1.0, "unconfirmed_balance": 0.0, "keypoolsize": 1000, "descriptors": true, "scanning": false })
+    }
+
+    #[test]
+    fn walletinfo_batches_the_balances_with_the_wallet_info() {
+        let node = ScriptedNode::default();
+        node.accept_batches()
+            .respond("getwalletinfo", wallet_info())
+            .respond("getbalances", json!({ "mine": { "trusted": 2.5, "untrusted_pending": 0.25, "immature": 0.0 } }));
+        let (session, out) = test_session(&node);
+
+        show_wallet_info(&session);
+
+        assert_eq!(node.round_trips(), 1);
+        assert!(out.text().contains("Balance: 2.50000000 BTC\nUnconfirmed balance: 0.25000000 BTC"), "{}", out.text());
+        assert_eq!(out.json.borrow()[0]["balance"], 2.5);
+    }
+
+    #[test]
+    fn walletinfo_falls_back_to_its_own_balances_without_getbalances() {
+        let node = ScriptedNode::default();
+        node.accept_batches()
+            .respond("getwalletinfo", wallet_info())
+            .fail("getbalances", -32601, "Method not found");
+        let (session, out) = test_session(&node);
+
+        show_wallet_info(&session);
+
+        assert!(out.text().contains("Balance: 1 BTC\nUnconfirmed balance: 0 BTC"), "{}", out.text());
+        assert!(out.errors.borrow().is_empty());
+    }
+
+    #[test]
+    fn spawnwallet_checks_the_amount_before_creating_the_wallet() {
+        let node = ScriptedNode::default();
+        node.respond("getbalances", json!({ "mine": { "trusted": 1.0, "untrusted_pending": 0.0, "immature": 0.0 } }));
+        let (session, out) = test_session(&node);
+
+        spawn_wallet(&session, "alice", Amount::from_sat(545));
+        spawn_wallet(&session, "alice", Amount::from_btc(2.0).unwrap());
+
+        assert!(out.error_text().contains("below the dust threshold of 0.00000546 BTC"), "{}", out.error_text());
+        assert!(out.error_text().contains("total 2 BTC exceeds balance 1 BTC"), "{}", out.error_text());
+        assert_eq!(node.calls(), ["getbalances"]);
+    }
+
+    #[test]
+    fn importmultisig_refuses_a_wallet_with_private_keys_before_rescanning() {
+        let node = ScriptedNode::default();
+        node.respond("getwalletinfo", json!({ "walletname": "test", "descriptors": true, "private_keys_enabled": true }));
+        let (session, out) = test_session(&node);
+        let key: PublicKey = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".parse().unwrap();
+
+        import_multisig(&session, 1, &[key]);
+
+        assert!(out.error_text().contains("Wallet test is a descriptor wallet with private keys"), "{}", out.error_text());
+        assert_eq!(node.calls(), ["getwalletinfo"]);
+    }
+
+    #[test]
+    fn importmultisig_refuses_a_legacy_wallet() {
+        let node = ScriptedNode::default();
+        node.respond("getwalletinfo", json!({ "walletname": "test", "private_keys_enabled": true }));
+        let (session, out) = test_session(&node);
+        let key: PublicKey = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".parse().unwrap();
+
+        import_multisig(&session, 1, &[key]);
+
+        assert!(out.error_text().contains("Wallet test is a legacy wallet"), "{}", out.error_text());
+        assert_eq!(node.calls(), ["getwalletinfo"]);
+    }
+
+    fn unknown_address_info() -> serde_json::Value {
+        json!({
+            "address": "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            "scriptPubKey": "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+            "ismine": false,
+            "iswatchonly": false,
+            "labels": [],
+        })
+    }
+
+    #[test]
+    fn watchaddress_refuses_a_descriptor_wallet_with_private_keys_before_rescanning() {
+        let node = ScriptedNode::default();
+        node.respond("getaddressinfo", unknown_address_info())
+            .respond("getwalletinfo", json!({ "walletname": "test", "descriptors": true, "private_keys_enabled": true }));
+        let (session, out) = test_session(&node);
+
+        assert!(import_watch_only(&session, "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080", None).is_none());
+        assert!(out.error_text().contains("Wallet test is a descriptor wallet with private keys"), "{}", out.error_text());
+        assert_eq!(node.calls(), ["getaddressinfo", "getwalletinfo"]);
+    }
+
+    fn balances(trusted: f64) -> serde_json::Value {
+        json!({ "mine": { "trusted": trusted, "untrusted_pending": 0.0, "immature": 0.0 } })
+    }
+
+    #[test]
+    fn waitstable_keeps_waiting_between_ticks_until_the_quiet_period_passes() {
+        let node = ScriptedNode::default();
+        node.respond("getbalances", balances(1.0));
+        let (mut session, out) = test_session(&node);
+
+        start_stable_wait(&mut session, 10, 300);
+        poll_stable_wait(&mut session);
+
+        assert!(session.stable_wait.is_some());
+        assert!(out.json.borrow().is_empty());
+        stop_stable_wait(&mut session);
+        assert!(session.stable_wait.is_none());
+    }
+
+    #[test]
+    fn waitstable_reports_changes_and_finishes_on_a_tick() {
+        let node = ScriptedNode::default();
+        node.respond("getbalances", balances(1.0)).respond("getbalances", balances(1.5));
+        let (mut session, out) = test_session(&node);
+
+        start_stable_wait(&mut session, 0, 300);
+        poll_stable_wait(&mut session);
+
+        assert!(session.stable_wait.is_none());
+        assert!(out.text().contains("Balance changed: 1 BTC -> 1.50000000 BTC"), "{}", out.text());
+        let result = &out.json.borrow()[0];
+        assert_eq!((result["stable"].as_bool(), result["changes"].as_u64()), (Some(true), Some(1)));
+    }
+
+    #[test]
+    fn getdescriptoraddress_lists_addresses_from_the_next_unused_index() {
+        let node = ScriptedNode::default();
+        node.respond("listdescriptors", json!({ "descriptors": [
+            { "desc": "wpkh(tpub/0/*)#abcdefgh", "active": true, "internal": false, "next_index": 3 },
+        ] }))
+            .respond("deriveaddresses", json!(["bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"]));
+        let (session, out) = test_session(&node);
+
+        show_descriptor_addresses(&session, 1);
+
+        assert_eq!(out.json.borrow()[0], json!({
+            "descriptor": "wpkh(tpub/0/*)#abcdefgh",
+            "addresses": [{ "index": 3, "address": "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080" }],
+        }));
+    }
+
+    #[test]
+    fn getdescriptoraddress_reports_a_legacy_wallet_as_an_error() {
+        let node = ScriptedNode::default();
+        node.fail("listdescriptors", -8, "listdescriptors is not available for non-descriptor wallets");
+        let (session, out) = test_session(&node);
+
+        show_descriptor_addresses(&session, 1);
+
+        assert!(out.error_text().contains("legacy (non-descriptor) wallet"), "{}", out.error_text());
+        assert!(out.human.borrow().is_empty());
+    }
+
+    #[test]
+    fn spawnwallet_keeps_using_the_session_wallet_after_creating_another() {
+        let node = ScriptedNode::default();
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let txid = "11".repeat(32);
+        let block = "22".repeat(32);
+        node.respond("getbalances", json!({ "mine": { "trusted": 1.0, "untrusted_pending": 0.0, "immature": 0.0 } }))
+            .respond("listwalletdir", json!({ "wallets": [{ "name": "test" }] }))
+            .respond("listwallets", json!(["test"]))
+            .respond("createwallet", json!({ "name": "alice", "warning": "" }))
+            .respond("alice/getnewaddress", json!(address))
+            .respond("sendtoaddress", json!(txid))
+            .respond("getnewaddress", json!(address))
+            .respond("generatetoaddress", json!([block]));
+        let (session, out) = test_session(&node);
+
+        spawn_wallet(&session, "alice", Amount::from_btc(0.5).unwrap());
+
+        assert!(out.errors.borrow().is_empty(), "{}", out.error_text());
+        assert_eq!(node.calls(), [
+            "getbalances", "listwalletdir", "listwallets", "createwallet",
+            "alice/getnewaddress", "sendtoaddress", "getnewaddress", "generatetoaddress",
+        ]);
+        assert_eq!(out.json.borrow()[0], json!({
+            "wallet": "alice", "address": address, "amount": 0.5, "txid": txid, "block": block,
+        }));
+    }
+}
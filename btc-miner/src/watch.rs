@@ -0,0 +1,66 @@
+use bitcoin::{Address, Amount};
+use bitcoincore_rpc::RpcApi;
+
+use crate::rpc::MinerClient;
+use crate::session::Session;
+use crate::wallet::is_not_in_wallet_error;
+
+fn received_by_watched_address(rpc_client: &MinerClient, address: &Address) -> Result<Amount, bitcoincore_rpc::Error> {
+    match rpc_client.get_received_by_address(address, Some(0)) {
+        Err(e) if is_not_in_wallet_error(&e) => Ok(Amount::ZERO),
+        result => result,
+    }
+}
+
+pub fn watch_address(session: &mut Session, address_string: &str) {
+    let address = match session.resolve_address(address_string) {
+        Ok(address) => address,
+        Err(e) => {
+            err!(session, "{}", e);
+            return;
+        }
+    };
+
+    match received_by_watched_address(&session.rpc_client, &address) {
+        Ok(received) => {
+            out!(session, "Watching {} (received so far: {})", address, received);
+            session.watches.insert(address.to_string(), (address, received));
+        }
+        Err(e) => err!(session, "Failed to get received amount for {}. Error {:?}", address, e)
+    }
+}
+
+pub fn unwatch_address(session: &mut Session, address_string: &str) {
+    let key = match session.resolve_address(address_string) {
+        Ok(address) => address.to_string(),
+        Err(_) => address_string.to_string(),
+    };
+
+    match session.watches.remove(&key) {
+        Some(_) => out!(session, "Stopped watching {}", key),
+        None => out!(session, "{} is not being watched", key),
+    }
+}
+
+pub fn list_watches(session: &Session) {
+    if session.watches.is_empty() {
+        out!(session, "No addresses being watched");
+    }
+    for (address, (_, received)) in &session.watches {
+        out!(session, "{} {}", address, received);
+    }
+}
+
+// Called on every timer tick, reports any watched address whose received total moved
+pub fn poll_watches(session: &mut Session) {
+    for (key, (address, last_received)) in session.watches.iter_mut() {
+        match received_by_watched_address(&session.rpc_client, address) {
+            Ok(received) if received != *last_received => {
+                out!(session, "Watched address {} received total changed: {} -> {}", key, last_received, received);
+                *last_received = received;
+            }
+            Ok(_) => {}
+            Err(e) => err!(session, "Failed to poll watched address {}. Error {}", key, e),
+        }
+    }
+}